@@ -1,10 +1,19 @@
-use std::{ffi::OsStr, fs::File, io, mem, path::PathBuf, thread, time::Duration};
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{self, BufWriter, Write},
+    mem,
+    path::PathBuf,
+    thread,
+    time::Duration,
+};
 
 use clap::Parser;
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use pgn_reader::{BufferedReader, Color, Outcome, RawHeader, SanPlus, Skip, Visitor};
 use serde::Serialize;
 use serde_with::{formats::SpaceSeparator, serde_as, DisplayFromStr, StringWithSeparator};
+use shakmaty::san::Suffix;
 
 #[derive(Debug, Serialize, Copy, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -36,18 +45,50 @@ impl Speed {
         }
     }
 
-    fn from_bytes(bytes: &[u8]) -> Result<Speed, ()> {
+    fn from_clock(clock: Option<Clock>) -> Speed {
+        match clock {
+            Some(clock) => {
+                Speed::from_seconds_and_increment(clock.initial.into(), clock.increment.into())
+            }
+            None => Speed::Correspondence,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Copy, Clone)]
+struct Clock {
+    initial: u32,
+    increment: u32,
+}
+
+impl Clock {
+    fn from_time_control(bytes: &[u8]) -> Result<Option<Clock>, ()> {
         if bytes == b"-" {
-            return Ok(Speed::Correspondence);
+            return Ok(None);
         }
 
         let mut parts = bytes.splitn(2, |ch| *ch == b'+');
-        let seconds = btoi::btou(parts.next().ok_or(())?).map_err(|_| ())?;
+        let initial = btoi::btou(parts.next().ok_or(())?).map_err(|_| ())?;
         let increment = btoi::btou(parts.next().ok_or(())?).map_err(|_| ())?;
-        Ok(Speed::from_seconds_and_increment(seconds, increment))
+        Ok(Some(Clock { initial, increment }))
     }
 }
 
+/// Game status as named by Lichess. The PGN exports do not distinguish all
+/// of them, so stalemates are reported as draws, and decisive games in
+/// variants without mate are left unknown.
+#[derive(Debug, Serialize, Copy, Clone)]
+#[serde(rename_all = "camelCase")]
+enum Termination {
+    Mate,
+    Resign,
+    Timeout,
+    Draw,
+    #[serde(rename = "outoftime")]
+    OutOfTime,
+    Cheat,
+}
+
 struct Batch {
     filename: PathBuf,
     games: Vec<Game>,
@@ -69,6 +110,7 @@ struct Importer<'a> {
     progress: &'a ProgressBar,
 
     current: Game,
+    termination: Vec<u8>,
     skip: bool,
     batch: Vec<Game>,
 }
@@ -87,6 +129,10 @@ struct Game {
     winner: Option<Color>,
     #[serde_as(as = "StringWithSeparator<SpaceSeparator, SanPlus>")]
     moves: Vec<SanPlus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clock: Option<Clock>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    termination: Option<Termination>,
 }
 
 #[derive(Default, Serialize, Debug)]
@@ -107,12 +153,42 @@ impl Importer<'_> {
             filename,
             batch_size,
             current: Game::default(),
+            termination: Vec::new(),
             skip: false,
             batch: Vec::with_capacity(batch_size),
             progress,
         }
     }
 
+    fn termination(&self) -> Option<Termination> {
+        Some(match &self.termination[..] {
+            b"Time forfeit" => Termination::OutOfTime,
+            b"Rules infraction" => Termination::Cheat,
+            b"Normal" => {
+                if self
+                    .current
+                    .moves
+                    .last()
+                    .map_or(false, |san| san.suffix == Some(Suffix::Checkmate))
+                {
+                    Termination::Mate
+                } else if self.current.winner.is_none() {
+                    Termination::Draw
+                } else if matches!(
+                    self.current.variant.as_deref(),
+                    None | Some("Standard" | "Chess960" | "From Position")
+                ) {
+                    Termination::Resign
+                } else {
+                    return None;
+                }
+            }
+            // Includes "Abandoned", which does not tell whether the game was
+            // left or aborted.
+            _ => return None,
+        })
+    }
+
     pub fn send(&mut self) {
         let batch = Batch {
             filename: self.filename.clone(),
@@ -129,6 +205,7 @@ impl Visitor for Importer<'_> {
     fn begin_game(&mut self) {
         self.skip = false;
         self.current = Game::default();
+        self.termination.clear();
     }
 
     fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
@@ -145,7 +222,12 @@ impl Visitor for Importer<'_> {
                 self.current.black.rating = Some(btoi::btoi(value.as_bytes()).expect("BlackElo"));
             }
         } else if key == b"TimeControl" {
-            self.current.speed = Some(Speed::from_bytes(value.as_bytes()).expect("TimeControl"));
+            let clock = Clock::from_time_control(value.as_bytes()).expect("TimeControl");
+            self.current.clock = clock;
+            self.current.speed = Some(Speed::from_clock(clock));
+        } else if key == b"Termination" {
+            self.termination.clear();
+            self.termination.extend_from_slice(value.as_bytes());
         } else if key == b"Variant" {
             self.current.variant = Some(value.decode_utf8().expect("Variant").into_owned());
         } else if key == b"Date" || key == b"UTCDate" {
@@ -196,6 +278,7 @@ impl Visitor for Importer<'_> {
 
     fn end_game(&mut self) {
         if !self.skip {
+            self.current.termination = self.termination();
             self.batch.push(mem::take(&mut self.current));
         }
 
@@ -211,6 +294,11 @@ struct Args {
     endpoint: String,
     #[arg(long, default_value = "200")]
     batch_size: usize,
+    /// Write games as newline delimited JSON to the given file, for the
+    /// bulk-load command of the explorer, instead of sending them to the
+    /// endpoint.
+    #[arg(long)]
+    ndjson: Option<PathBuf>,
     pgns: Vec<PathBuf>,
 }
 
@@ -219,6 +307,12 @@ fn main() -> Result<(), io::Error> {
 
     let (tx, rx) = crossbeam::channel::bounded::<Batch>(50);
 
+    let mut ndjson = args
+        .ndjson
+        .as_ref()
+        .map(|path| File::create(path).map(BufWriter::new))
+        .transpose()?;
+
     let bg = thread::spawn(move || {
         let client = reqwest::blocking::Client::builder()
             .timeout(Duration::from_secs(60))
@@ -226,6 +320,14 @@ fn main() -> Result<(), io::Error> {
             .expect("client");
 
         while let Ok(batch) = rx.recv() {
+            if let Some(ref mut writer) = ndjson {
+                for game in &batch.games {
+                    serde_json::to_writer(&mut *writer, game).expect("serialize game");
+                    writer.write_all(b"\n").expect("write ndjson");
+                }
+                continue;
+            }
+
             let res = client
                 .put(format!("{}/import/lichess", args.endpoint))
                 .json(&batch.games)
@@ -242,6 +344,10 @@ fn main() -> Result<(), io::Error> {
                 );
             }
         }
+
+        if let Some(mut writer) = ndjson {
+            writer.flush().expect("flush ndjson");
+        }
     });
 
     for arg in args.pgns {
@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+#[derive(Clone)]
+pub struct AdminBearer(Arc<str>);
+
+impl From<String> for AdminBearer {
+    fn from(bearer: String) -> AdminBearer {
+        AdminBearer(bearer.into())
+    }
+}
+
+impl AdminBearer {
+    fn matches(&self, candidate: &str) -> bool {
+        // Compare in constant time (for a given length), so that the token
+        // can not be guessed byte by byte.
+        let expected = self.0.as_bytes();
+        let candidate = candidate.as_bytes();
+        expected.len() == candidate.len()
+            && expected
+                .iter()
+                .zip(candidate)
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+pub async fn require_admin_bearer<B>(
+    State(bearer): State<AdminBearer>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map_or(false, |candidate| bearer.matches(candidate));
+
+    if authorized {
+        next.run(req).await
+    } else {
+        (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"))],
+            "bearer token required",
+        )
+            .into_response()
+    }
+}
@@ -12,12 +12,38 @@ pub enum Error {
     IllegalUciError(#[from] IllegalUciError),
     #[error("bad request: {0}")]
     SanError(#[from] SanError),
-    #[error("duplicate game {id}")]
-    DuplicateGame { id: GameId },
+    #[error("duplicate game {id} (already imported as {existing})")]
+    DuplicateGame { id: GameId, existing: GameId },
     #[error("rejected import of {id} due to average rating {rating}")]
     RejectedRating { id: GameId, rating: u16 },
+    #[error("rejected import of {id} due to player rating {rating}")]
+    RejectedPlayerRating { id: GameId, rating: u16 },
     #[error("rejected import of {id} due to date {date}")]
     RejectedDate { id: GameId, date: LaxDate },
+    #[error("rejected import of {id} due to event {event:?}")]
+    RejectedEvent { id: GameId, event: String },
+    #[error("rejected import of {id} due to site {site:?}")]
+    RejectedSite { id: GameId, site: String },
+    #[error("bad request: at most {max} events")]
+    TooManyEvents { max: usize },
+    #[error("bad request: page must be between 1 and {max}")]
+    InvalidPage { max: usize },
+    #[error("bad request: search terms need a word with at least {min} characters")]
+    SearchTermTooShort { min: usize },
+    #[error(
+        "bad request: ratings, minRating and maxRating can not be combined with moverRatings or opponentRatings"
+    )]
+    ConflictingRatingFilters,
+    #[error("bad request: breakdown can not be combined with moverRatings or opponentRatings")]
+    ConflictingBreakdown,
+    #[error("bad request: minRating must not be greater than maxRating")]
+    InvalidRatingRange,
+    #[error("bad request: since and until are not supported by lite databases")]
+    UnsupportedDateFilter,
+    #[error("player {player} has not been indexed, and this replica can not index")]
+    PlayerNotIndexed { player: String },
+    #[error("masters imports are paused until the database migration is complete")]
+    MigrationPending,
 }
 
 impl From<PositionError<VariantPosition>> for Error {
@@ -28,6 +54,15 @@ impl From<PositionError<VariantPosition>> for Error {
 
 impl axum::response::IntoResponse for Error {
     fn into_response(self) -> Response {
-        (StatusCode::BAD_REQUEST, self.to_string()).into_response()
+        (
+            match self {
+                Error::PlayerNotIndexed { .. } | Error::MigrationPending => {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }
+                _ => StatusCode::BAD_REQUEST,
+            },
+            self.to_string(),
+        )
+            .into_response()
     }
 }
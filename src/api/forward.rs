@@ -0,0 +1,46 @@
+use axum::{
+    body::{self, StreamBody},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+/// Forwards requests from a read-only replica to the primary explorer.
+#[derive(Clone)]
+pub struct Primary {
+    client: reqwest::Client,
+    base_url: String,
+}
+
+impl Primary {
+    pub fn new(base_url: String) -> Primary {
+        Primary {
+            client: reqwest::Client::builder().build().expect("reqwest client"),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+        }
+    }
+
+    pub async fn forward(&self, path: &str, query: Option<String>) -> Response {
+        let url = match query {
+            Some(query) => format!("{}{}?{}", self.base_url, path, query),
+            None => format!("{}{}", self.base_url, path),
+        };
+
+        let res = match self.client.get(&url).send().await {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("forwarding to primary failed: {}", err);
+                return (StatusCode::BAD_GATEWAY, "primary unavailable").into_response();
+            }
+        };
+
+        let mut builder = Response::builder()
+            .status(res.status())
+            .header("X-Accel-Buffering", "no");
+        if let Some(content_type) = res.headers().get(header::CONTENT_TYPE) {
+            builder = builder.header(header::CONTENT_TYPE, content_type.clone());
+        }
+        builder
+            .body(body::boxed(StreamBody::new(res.bytes_stream())))
+            .unwrap()
+    }
+}
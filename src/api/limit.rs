@@ -0,0 +1,300 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use clap::Parser;
+use tokio::{
+    sync::Semaphore,
+    task::{self, JoinError},
+};
+
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+const SWEPT_CLIENTS: usize = MAX_TRACKED_CLIENTS * 3 / 4;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Parser)]
+pub struct LimitOpt {
+    /// Sustained number of requests per second allowed for each client.
+    /// IPv6 clients are identified by their /64 prefix. Rate limiting is
+    /// disabled by default.
+    #[arg(long)]
+    rate_limit: Option<f64>,
+    /// Number of requests a client may burst above the sustained rate.
+    #[arg(long, default_value = "20")]
+    rate_limit_burst: u32,
+    /// Identify clients by the last address in X-Forwarded-For, as appended
+    /// by a trusted reverse proxy.
+    #[arg(long)]
+    trust_forwarded_for: bool,
+    /// Maximum number of concurrent blocking database reads. Further reads
+    /// are queued.
+    #[arg(long, default_value = "64")]
+    max_concurrent_reads: usize,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+struct Buckets {
+    clients: HashMap<IpAddr, Bucket>,
+    /// Tokens for clients without a bucket. Less than the burst while active
+    /// clients had to be forgotten, so that they do not return with more
+    /// tokens than they had.
+    new_tokens: f64,
+    next_sweep: Instant,
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<Buckets>>,
+    per_second: f64,
+    burst: f64,
+    trust_forwarded_for: bool,
+}
+
+impl RateLimiter {
+    pub fn new(opt: &LimitOpt) -> Option<RateLimiter> {
+        opt.rate_limit
+            .filter(|per_second| *per_second > 0.0)
+            .map(|per_second| RateLimiter {
+                buckets: Arc::new(Mutex::new(Buckets {
+                    clients: HashMap::new(),
+                    new_tokens: f64::from(opt.rate_limit_burst.max(1)),
+                    next_sweep: Instant::now() + SWEEP_INTERVAL,
+                })),
+                per_second,
+                burst: f64::from(opt.rate_limit_burst.max(1)),
+                trust_forwarded_for: opt.trust_forwarded_for,
+            })
+    }
+
+    fn client<B>(&self, req: &Request<B>, addr: SocketAddr) -> IpAddr {
+        self.trust_forwarded_for
+            .then(|| {
+                req.headers()
+                    .get("X-Forwarded-For")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.rsplit(',').next())
+                    .and_then(|last| last.trim().parse().ok())
+            })
+            .flatten()
+            .map_or_else(|| client_key(addr.ip()), client_key)
+    }
+
+    fn tokens(&self, bucket: &Bucket, now: Instant) -> f64 {
+        f64::min(
+            self.burst,
+            bucket.tokens + now.duration_since(bucket.updated_at).as_secs_f64() * self.per_second,
+        )
+    }
+
+    fn sweep(&self, buckets: &mut Buckets, now: Instant) {
+        // Forget clients that would have refilled their bucket anyway.
+        let refill = Duration::from_secs_f64(self.burst / self.per_second);
+        buckets
+            .clients
+            .retain(|_, bucket| now.duration_since(bucket.updated_at) < refill);
+
+        // Still too many active clients. Forget those with the most tokens
+        // left, and give clients without a bucket no more than that, so
+        // that throttled clients cannot reset their bucket by waiting to be
+        // forgotten.
+        if buckets.clients.len() > SWEPT_CLIENTS {
+            let excess = buckets.clients.len() - SWEPT_CLIENTS;
+            let mut tokens: Vec<f64> = buckets
+                .clients
+                .values()
+                .map(|bucket| self.tokens(bucket, now))
+                .collect();
+            let (_, cutoff, _) = tokens.select_nth_unstable_by(excess - 1, |a, b| b.total_cmp(a));
+            let cutoff = *cutoff;
+            buckets
+                .clients
+                .retain(|_, bucket| self.tokens(bucket, now) < cutoff);
+            buckets.new_tokens = cutoff;
+        } else {
+            buckets.new_tokens = self.burst;
+        }
+
+        buckets.next_sweep = now + SWEEP_INTERVAL;
+    }
+
+    fn acquire(&self, client: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut guard = self.buckets.lock().expect("lock rate limiter");
+
+        // Sweeps leave room for at least MAX_TRACKED_CLIENTS - SWEPT_CLIENTS
+        // new clients, so that their cost is amortized.
+        if guard.clients.len() >= MAX_TRACKED_CLIENTS || guard.next_sweep <= now {
+            self.sweep(&mut guard, now);
+        }
+
+        let new_tokens = guard.new_tokens;
+        let bucket = guard.clients.entry(client).or_insert(Bucket {
+            tokens: new_tokens,
+            updated_at: now,
+        });
+        bucket.tokens = self.tokens(bucket, now);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+}
+
+/// Identifies IPv6 clients by their /64 prefix, which is commonly assigned
+/// to a single host or subscriber, and IPv4-mapped clients by their IPv4
+/// address.
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & (u128::MAX << 64))),
+        },
+    }
+}
+
+pub async fn rate_limit<B>(
+    State(limiter): State<RateLimiter>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let client = limiter.client(&req, addr);
+    match limiter.acquire(client) {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(
+                header::RETRY_AFTER,
+                retry_after.as_secs_f64().ceil().max(1.0).to_string(),
+            )],
+            "too many requests",
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Clone)]
+pub struct ReadLimiter {
+    semaphore: Arc<Semaphore>,
+}
+
+impl ReadLimiter {
+    pub fn new(opt: &LimitOpt) -> ReadLimiter {
+        ReadLimiter {
+            semaphore: Arc::new(Semaphore::new(opt.max_concurrent_reads.max(1))),
+        }
+    }
+
+    pub async fn spawn_blocking<F, R>(&self, f: F) -> Result<R, JoinError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let permit = Arc::clone(&self.semaphore)
+            .acquire_owned()
+            .await
+            .expect("read semaphore never closed");
+
+        task::spawn_blocking(move || {
+            let _permit = permit;
+            f()
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn limiter(burst: f64) -> RateLimiter {
+        RateLimiter {
+            buckets: Arc::new(Mutex::new(Buckets {
+                clients: HashMap::new(),
+                new_tokens: burst,
+                next_sweep: Instant::now() + SWEEP_INTERVAL,
+            })),
+            per_second: 0.001,
+            burst,
+            trust_forwarded_for: false,
+        }
+    }
+
+    #[test]
+    fn test_client_key() {
+        assert_eq!(
+            client_key("2001:db8:1:2:3:4:5:6".parse().unwrap()),
+            "2001:db8:1:2::".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            client_key("::ffff:192.0.2.1".parse().unwrap()),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))
+        );
+        assert_eq!(
+            client_key("192.0.2.1".parse().unwrap()),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter_evicts_active_clients() {
+        let limiter = limiter(1000.0);
+
+        for i in 0..MAX_TRACKED_CLIENTS {
+            let client = IpAddr::V6(Ipv6Addr::from((i as u128 + 1) << 64));
+            assert!(limiter.acquire(client).is_ok());
+        }
+
+        // No bucket has refilled, yet the map does not keep growing.
+        assert!(limiter.acquire(IpAddr::V6(Ipv6Addr::LOCALHOST)).is_ok());
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.clients.len() <= SWEPT_CLIENTS + 1);
+        assert!(buckets
+            .clients
+            .contains_key(&IpAddr::V6(Ipv6Addr::LOCALHOST)));
+    }
+
+    #[test]
+    fn test_rate_limiter_keeps_throttled_clients() {
+        let limiter = limiter(2.0);
+
+        let throttled = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert!(limiter.acquire(throttled).is_ok());
+        assert!(limiter.acquire(throttled).is_ok());
+        assert!(limiter.acquire(throttled).is_err());
+
+        for i in 0..MAX_TRACKED_CLIENTS {
+            let client = IpAddr::V6(Ipv6Addr::from((i as u128 + 1) << 64));
+            assert!(limiter.acquire(client).is_ok());
+        }
+
+        // Clients with tokens left were forgotten first, and do not come
+        // back with more tokens than they had.
+        assert!(limiter.acquire(throttled).is_err());
+        let client = IpAddr::V6(Ipv6Addr::from(1 << 64));
+        assert!(limiter.acquire(client).is_ok());
+        assert!(limiter.acquire(client).is_err());
+    }
+}
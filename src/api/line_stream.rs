@@ -0,0 +1,95 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::{self, HttpBody},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    BoxError,
+};
+use bytes::Bytes;
+use futures_util::{ready, stream::Stream};
+use pin_project_lite::pin_project;
+use sync_wrapper::SyncWrapper;
+use tokio::{
+    time,
+    time::{Interval, MissedTickBehavior},
+};
+
+/// Streams encoded items, each followed by `terminator`. Sends keep-alive
+/// newlines while waiting for the next item, so formats must allow blank
+/// lines between items.
+pub struct LineStream<S> {
+    pub stream: S,
+    pub content_type: &'static str,
+    pub terminator: &'static [u8],
+}
+
+impl<S, E> IntoResponse for LineStream<S>
+where
+    S: Stream<Item = Result<Vec<u8>, E>> + Send + 'static,
+    E: Into<BoxError> + 'static,
+{
+    fn into_response(self) -> Response {
+        let mut keep_alive = time::interval(Duration::from_secs(8));
+        keep_alive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Response::builder()
+            .header("X-Accel-Buffering", "no")
+            .header(axum::http::header::CONTENT_TYPE, self.content_type)
+            .body(body::boxed(LineBody {
+                stream: SyncWrapper::new(self.stream),
+                terminator: self.terminator,
+                keep_alive,
+            }))
+            .unwrap()
+    }
+}
+
+pin_project! {
+    pub struct LineBody<S> {
+        #[pin]
+        stream: SyncWrapper<S>,
+        terminator: &'static [u8],
+        keep_alive: Interval,
+    }
+}
+
+impl<S, E> HttpBody for LineBody<S>
+where
+    S: Stream<Item = Result<Vec<u8>, E>>,
+{
+    type Data = Bytes;
+    type Error = E;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.project();
+
+        match this.stream.get_pin_mut().poll_next(cx) {
+            Poll::Pending => {
+                ready!(this.keep_alive.poll_tick(cx));
+                Poll::Ready(Some(Ok(Bytes::from("\n"))))
+            }
+            Poll::Ready(Some(Ok(mut line))) => {
+                this.keep_alive.reset();
+                line.extend_from_slice(this.terminator);
+                Poll::Ready(Some(Ok(Bytes::from(line))))
+            }
+            Poll::Ready(Some(Err(err))) => Poll::Ready(Some(Err(err))),
+            Poll::Ready(None) => Poll::Ready(None),
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Poll::Ready(Ok(None))
+    }
+}
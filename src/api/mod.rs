@@ -1,15 +1,27 @@
+mod auth;
 mod error;
+mod forward;
+mod limit;
+mod line_stream;
 mod nd_json;
+mod pgn_stream;
 mod query;
 mod response;
 
+pub use auth::{require_admin_bearer, AdminBearer};
 pub use error::Error;
+pub use forward::Primary;
+pub use limit::{rate_limit, LimitOpt, RateLimiter, ReadLimiter};
 pub use nd_json::NdJson;
+pub use pgn_stream::PgnStream;
 pub use query::{
-    LichessHistoryQuery, LichessQuery, LichessQueryFilter, Limits, MastersQuery, PlayPosition,
+    Breakdown, Breakdowns, GameResult, InvalidBreakdown, LichessHistoryQuery, LichessMatchupFilter,
+    LichessQuery, LichessQueryFilter, Limits, MastersEventQuery, MastersEventsQuery,
+    MastersPlayerQuery, MastersQuery, MastersSearchQuery, PgnLimit, Play, PlayPosition,
     PlayerQuery, PlayerQueryFilter,
 };
 pub use response::{
     ExplorerGame, ExplorerGameWithUci, ExplorerHistoryResponse, ExplorerHistorySegment,
-    ExplorerMove, ExplorerResponse,
+    ExplorerMove, ExplorerResponse, MastersEventRow, MastersEventsResponse, MastersSearchResponse,
+    MastersSearchRow, RatingRange,
 };
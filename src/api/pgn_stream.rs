@@ -0,0 +1,24 @@
+use std::convert::Infallible;
+
+use axum::response::{IntoResponse, Response};
+use futures_util::stream::{Stream, StreamExt as _};
+
+use crate::api::line_stream::LineStream;
+
+/// Streams games, each already formatted as PGN, separated by blank lines.
+/// Keep-alive newlines are harmless between games.
+pub struct PgnStream<S>(pub S);
+
+impl<S> IntoResponse for PgnStream<S>
+where
+    S: Stream<Item = String> + Send + 'static,
+{
+    fn into_response(self) -> Response {
+        LineStream {
+            stream: self.0.map(|pgn| Ok::<_, Infallible>(pgn.into_bytes())),
+            content_type: "application/x-chess-pgn",
+            terminator: b"\n",
+        }
+        .into_response()
+    }
+}
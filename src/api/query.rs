@@ -1,4 +1,4 @@
-use std::cmp::min;
+use std::{cmp::min, str::FromStr};
 
 use serde::Deserialize;
 use serde_with::{
@@ -10,10 +10,14 @@ use shakmaty::{
     variant::{Variant, VariantPosition},
     CastlingMode, Color, PositionError,
 };
+use thiserror::Error;
 
 use crate::{
-    api::Error,
-    model::{Mode, Month, RatingGroup, Speed, UserName, Year},
+    api::{Error, RatingRange},
+    model::{
+        matches_tokens, tokenize, MastersEvent, MastersEventId, MastersGame, Mode, Month,
+        RatingGroup, SearchField, Speed, UserName, Year,
+    },
     opening::{Opening, Openings},
 };
 
@@ -32,6 +36,293 @@ pub struct MastersQuery {
     pub limits: Limits,
 }
 
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct MastersPlayerQuery {
+    #[serde(flatten)]
+    pub play: Play,
+    /// Name as in the PGN headers. Case, punctuation and order of words are
+    /// ignored.
+    pub player: String,
+    #[serde_as(as = "DisplayFromStr")]
+    pub color: Color,
+    #[serde_as(as = "TryFromInto<u16>")]
+    #[serde(default = "Year::min_value")]
+    pub since: Year,
+    #[serde_as(as = "TryFromInto<u16>")]
+    #[serde(default = "Year::max_value")]
+    pub until: Year,
+    #[serde(flatten)]
+    pub limits: Limits,
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MastersSearchQuery {
+    /// Partial name of either player.
+    #[serde(default)]
+    pub player: Option<String>,
+    /// Partial name of the event.
+    #[serde(default)]
+    pub event: Option<String>,
+    /// Partial name of the site.
+    #[serde(default)]
+    pub site: Option<String>,
+    #[serde_as(as = "TryFromInto<u16>")]
+    #[serde(default = "Year::min_value")]
+    pub since: Year,
+    #[serde_as(as = "TryFromInto<u16>")]
+    #[serde(default = "Year::max_value")]
+    pub until: Year,
+    #[serde(default)]
+    pub result: Option<GameResult>,
+    /// Minimum rating of both players.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub min_rating: Option<u16>,
+    /// Maximum rating of both players.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub max_rating: Option<u16>,
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "MastersSearchQuery::default_page")]
+    pub page: usize,
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "MastersSearchQuery::default_max_per_page")]
+    pub max_per_page: usize,
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MastersEventsQuery {
+    /// Partial name of the event or site.
+    #[serde(default)]
+    pub q: Option<String>,
+    #[serde_as(as = "TryFromInto<u16>")]
+    #[serde(default = "Year::min_value")]
+    pub since: Year,
+    #[serde_as(as = "TryFromInto<u16>")]
+    #[serde(default = "Year::max_value")]
+    pub until: Year,
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "MastersSearchQuery::default_page")]
+    pub page: usize,
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "MastersSearchQuery::default_max_per_page")]
+    pub max_per_page: usize,
+}
+
+impl MastersEventsQuery {
+    /// Events with games in the range of years, and with all tokens of the
+    /// query in the event or site.
+    pub fn matches(&self, event: &MastersEvent) -> bool {
+        if event.last.year() < self.since || self.until < event.first.year() {
+            return false;
+        }
+        self.q.as_deref().map_or(true, |q| {
+            matches_tokens(&tokenize(q), &format!("{} {}", event.event, event.site))
+        })
+    }
+
+    pub fn max_per_page(&self) -> usize {
+        self.max_per_page.clamp(1, MastersSearchQuery::MAX_PER_PAGE)
+    }
+
+    pub fn last_page(&self) -> usize {
+        MastersSearchQuery::MAX_RESULTS / self.max_per_page()
+    }
+
+    pub fn check_page(&self) -> Result<(), Error> {
+        if self.page < 1 || self.page > self.last_page() {
+            return Err(Error::InvalidPage {
+                max: self.last_page(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Selects the requested page of the matching `events`, which are
+    /// already sorted. Also returns whether there are more.
+    pub fn select(
+        &self,
+        events: &[(MastersEventId, MastersEvent)],
+    ) -> (Vec<(MastersEventId, MastersEvent)>, bool) {
+        let skip = self.page.saturating_sub(1) * self.max_per_page();
+        let mut matches = events.iter().filter(|(_, event)| self.matches(event));
+        let page = matches
+            .by_ref()
+            .skip(skip)
+            .take(self.max_per_page())
+            .cloned()
+            .collect();
+        (page, matches.next().is_some())
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct MastersEventQuery {
+    #[serde(flatten)]
+    pub play: Play,
+    /// Ids from the event listing.
+    #[serde_as(as = "StringWithSeparator<CommaSeparator, MastersEventId>")]
+    pub events: Vec<MastersEventId>,
+    #[serde_as(as = "TryFromInto<u16>")]
+    #[serde(default = "Year::min_value")]
+    pub since: Year,
+    #[serde_as(as = "TryFromInto<u16>")]
+    #[serde(default = "Year::max_value")]
+    pub until: Year,
+    #[serde(flatten)]
+    pub limits: Limits,
+}
+
+impl MastersEventQuery {
+    pub const MAX_EVENTS: usize = 64;
+}
+
+/// Limit for PGN exports, next to the query of the exported games.
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct PgnLimit {
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    max: Option<usize>,
+}
+
+impl PgnLimit {
+    pub const MAX_GAMES: usize = 5_000;
+
+    pub fn max(&self) -> usize {
+        self.max
+            .map_or(PgnLimit::MAX_GAMES, |max| min(max, PgnLimit::MAX_GAMES))
+    }
+}
+
+#[derive(Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum GameResult {
+    White,
+    Black,
+    Draw,
+}
+
+impl MastersSearchQuery {
+    pub const MAX_PER_PAGE: usize = 100;
+
+    /// Results beyond are not reachable through pagination.
+    pub const MAX_RESULTS: usize = 10_000;
+
+    /// Searches give up after visiting this many index keys or games, so
+    /// that unselective queries do not scan the entire database.
+    pub const MAX_SCANNED: usize = 50_000;
+
+    /// Searches without terms read each visited game, and give up earlier.
+    /// This still allows paging through all reachable results when only
+    /// filtering by year.
+    pub const MAX_SCANNED_WITHOUT_TERMS: usize = MastersSearchQuery::MAX_RESULTS;
+
+    /// Minimum length of the most selective token, which is matched as a
+    /// prefix in the search index.
+    pub const MIN_TOKEN_CHARS: usize = 3;
+
+    fn default_page() -> usize {
+        1
+    }
+
+    fn default_max_per_page() -> usize {
+        20
+    }
+
+    pub fn max_per_page(&self) -> usize {
+        self.max_per_page.clamp(1, MastersSearchQuery::MAX_PER_PAGE)
+    }
+
+    pub fn last_page(&self) -> usize {
+        MastersSearchQuery::MAX_RESULTS / self.max_per_page()
+    }
+
+    pub fn check_page(&self) -> Result<(), Error> {
+        if self.page < 1 || self.page > self.last_page() {
+            return Err(Error::InvalidPage {
+                max: self.last_page(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Search terms must include a token that can be looked up efficiently.
+    pub fn check_terms(&self) -> Result<(), Error> {
+        match self.most_selective_token() {
+            Some((_, token)) if token.chars().count() < MastersSearchQuery::MIN_TOKEN_CHARS => {
+                Err(Error::SearchTermTooShort {
+                    min: MastersSearchQuery::MIN_TOKEN_CHARS,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Terms of the query, with tokens as in `tokenize()`.
+    pub fn terms(&self) -> Vec<(SearchField, Vec<String>)> {
+        [
+            (SearchField::Player, &self.player),
+            (SearchField::Event, &self.event),
+            (SearchField::Site, &self.site),
+        ]
+        .into_iter()
+        .filter_map(|(field, text)| text.as_deref().map(|text| (field, tokenize(text))))
+        .filter(|(_, tokens)| !tokens.is_empty())
+        .collect()
+    }
+
+    /// The longest token, probably matching the least games in the search
+    /// index.
+    pub fn most_selective_token(&self) -> Option<(SearchField, String)> {
+        self.terms()
+            .into_iter()
+            .flat_map(|(field, tokens)| tokens.into_iter().map(move |token| (field, token)))
+            .max_by_key(|(_, token)| token.chars().count())
+    }
+
+    pub fn matches(&self, game: &MastersGame) -> bool {
+        let year = game.date.year();
+        if year < self.since || self.until < year {
+            return false;
+        }
+
+        if let Some(result) = self.result {
+            let expected = match result {
+                GameResult::White => Some(Color::White),
+                GameResult::Black => Some(Color::Black),
+                GameResult::Draw => None,
+            };
+            if game.winner != expected {
+                return false;
+            }
+        }
+
+        for player in [&game.players.white, &game.players.black] {
+            if self.min_rating.map_or(false, |min| player.rating < min)
+                || self.max_rating.map_or(false, |max| max < player.rating)
+            {
+                return false;
+            }
+        }
+
+        self.terms().iter().all(|(field, tokens)| match field {
+            SearchField::Player => {
+                matches_tokens(tokens, &game.players.white.name)
+                    || matches_tokens(tokens, &game.players.black.name)
+            }
+            SearchField::Event => matches_tokens(tokens, &game.event),
+            SearchField::Site => matches_tokens(tokens, &game.site),
+        })
+    }
+}
+
 #[derive(Deserialize, Clone, Debug, Hash, Eq, PartialEq)]
 pub struct LichessQuery {
     #[serde(flatten)]
@@ -40,6 +331,10 @@ pub struct LichessQuery {
     pub limits: Limits,
     #[serde(flatten)]
     pub filter: LichessQueryFilter,
+    #[serde(flatten)]
+    pub matchup: LichessMatchupFilter,
+    #[serde(flatten)]
+    pub breakdowns: Breakdowns,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -52,6 +347,7 @@ pub struct LichessHistoryQuery {
 
 #[serde_as]
 #[derive(Deserialize, Clone, Debug, Hash, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
 pub struct LichessQueryFilter {
     #[serde_as(as = "Option<StringWithSeparator<CommaSeparator, Speed>>")]
     #[serde(default)]
@@ -59,6 +355,15 @@ pub struct LichessQueryFilter {
     #[serde_as(as = "Option<StringWithSeparator<CommaSeparator, RatingGroup>>")]
     #[serde(default)]
     pub ratings: Option<Vec<RatingGroup>>,
+    /// Lowest average rating, inclusive. Widened to the rating groups it
+    /// cuts through, see `rating_range()`.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub min_rating: Option<u16>,
+    /// Highest average rating, inclusive.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub max_rating: Option<u16>,
     #[serde_as(as = "Option<DisplayFromStr>")]
     #[serde(default)]
     pub since: Option<Month>,
@@ -75,9 +380,47 @@ impl LichessQueryFilter {
     }
 
     pub fn contains_rating_group(&self, rating_group: RatingGroup) -> bool {
+        let (low, high) = rating_group_bounds(rating_group);
         self.ratings.as_ref().map_or(true, |ratings| {
             ratings.contains(&min(rating_group, RatingGroup::Group2500))
-        })
+        }) && self.min_rating.map_or(true, |min_rating| {
+            high.map_or(true, |high| min_rating <= high)
+        }) && self.max_rating.map_or(true, |max_rating| low <= max_rating)
+    }
+
+    /// Whether games are selected by the average rating of both players.
+    pub fn has_rating_filter(&self) -> bool {
+        self.ratings.is_some() || self.min_rating.is_some() || self.max_rating.is_some()
+    }
+
+    /// Range of average ratings actually covered by `min_rating` and
+    /// `max_rating`, after widening them to the boundaries of the rating
+    /// groups. `None` if neither is given.
+    pub fn rating_range(&self) -> Result<Option<RatingRange>, Error> {
+        if let (Some(min_rating), Some(max_rating)) = (self.min_rating, self.max_rating) {
+            if min_rating > max_rating {
+                return Err(Error::InvalidRatingRange);
+            }
+        }
+        if self.min_rating.is_none() && self.max_rating.is_none() {
+            return Ok(None);
+        }
+        let min = self.min_rating.map_or(0, |min_rating| {
+            rating_group_bounds(RatingGroup::select_single(min_rating)).0
+        });
+        let max = self
+            .max_rating
+            .and_then(|max_rating| rating_group_bounds(RatingGroup::select_single(max_rating)).1);
+        Ok(Some(RatingRange {
+            min,
+            max,
+            approximate: self
+                .min_rating
+                .map_or(false, |min_rating| min_rating != min)
+                || self
+                    .max_rating
+                    .map_or(false, |max_rating| Some(max_rating) != max),
+        }))
     }
 
     pub fn top_group(&self) -> Option<RatingGroup> {
@@ -92,6 +435,59 @@ impl LichessQueryFilter {
     }
 }
 
+/// Lowest and highest average rating (inclusive) of the games in a rating
+/// group, see `RatingGroup::select_avg()`. Averages from 2800 upwards all
+/// fall into `Group3200`, leaving `Group2800` empty.
+fn rating_group_bounds(rating_group: RatingGroup) -> (u16, Option<u16>) {
+    match rating_group {
+        RatingGroup::Group2800 | RatingGroup::Group3200 => {
+            (RatingGroup::Group2800.min_rating(), None)
+        }
+        RatingGroup::Group2500 => (
+            RatingGroup::Group2500.min_rating(),
+            Some(RatingGroup::Group2800.min_rating() - 1),
+        ),
+        _ => (
+            rating_group.min_rating(),
+            RatingGroup::ALL
+                .into_iter()
+                .find(|next| rating_group < *next)
+                .map(|next| next.min_rating() - 1),
+        ),
+    }
+}
+
+/// Filters on the rating groups of the player to move and of their
+/// opponent, rather than on the group of their average rating.
+#[serde_as]
+#[derive(Deserialize, Clone, Debug, Default, Hash, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LichessMatchupFilter {
+    #[serde_as(as = "Option<StringWithSeparator<CommaSeparator, RatingGroup>>")]
+    #[serde(default)]
+    pub mover_ratings: Option<Vec<RatingGroup>>,
+    #[serde_as(as = "Option<StringWithSeparator<CommaSeparator, RatingGroup>>")]
+    #[serde(default)]
+    pub opponent_ratings: Option<Vec<RatingGroup>>,
+}
+
+impl LichessMatchupFilter {
+    pub fn any(&self) -> bool {
+        self.mover_ratings.is_some() || self.opponent_ratings.is_some()
+    }
+
+    pub fn contains(&self, mover: RatingGroup, opponent: RatingGroup) -> bool {
+        fn contains_group(ratings: &Option<Vec<RatingGroup>>, group: RatingGroup) -> bool {
+            ratings.as_ref().map_or(true, |ratings| {
+                ratings.contains(&min(group, RatingGroup::Group2500))
+            })
+        }
+
+        contains_group(&self.mover_ratings, mover)
+            && contains_group(&self.opponent_ratings, opponent)
+    }
+}
+
 #[serde_as]
 #[derive(Deserialize, Debug)]
 pub struct PlayerQuery {
@@ -105,6 +501,8 @@ pub struct PlayerQuery {
     pub filter: PlayerQueryFilter,
     #[serde(flatten)]
     pub limits: Limits,
+    #[serde(flatten)]
+    pub breakdowns: Breakdowns,
 }
 
 #[serde_as]
@@ -179,3 +577,220 @@ impl Limits {
         self.top_games > 0 || self.recent_games > 0
     }
 }
+
+/// Optional sub-statistics of each move.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Breakdown {
+    /// By how the games ended. See `TerminationGroup`.
+    Termination,
+    /// By time control.
+    Speed,
+    /// By average rating group. See `RatingGroup`.
+    Rating,
+}
+
+impl FromStr for Breakdown {
+    type Err = InvalidBreakdown;
+
+    fn from_str(s: &str) -> Result<Breakdown, InvalidBreakdown> {
+        Ok(match s {
+            "termination" => Breakdown::Termination,
+            "speed" => Breakdown::Speed,
+            "rating" => Breakdown::Rating,
+            _ => return Err(InvalidBreakdown),
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("invalid breakdown")]
+pub struct InvalidBreakdown;
+
+#[serde_as]
+#[derive(Deserialize, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Breakdowns {
+    #[serde_as(as = "Option<StringWithSeparator<CommaSeparator, Breakdown>>")]
+    #[serde(default)]
+    pub breakdown: Option<Vec<Breakdown>>,
+}
+
+impl Breakdowns {
+    pub fn any(&self) -> bool {
+        self.breakdown
+            .as_ref()
+            .map_or(false, |breakdown| !breakdown.is_empty())
+    }
+
+    pub fn contains(&self, breakdown: Breakdown) -> bool {
+        self.breakdown
+            .as_ref()
+            .map_or(false, |breakdowns| breakdowns.contains(&breakdown))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::LaxDate;
+
+    fn filter(min_rating: Option<u16>, max_rating: Option<u16>) -> LichessQueryFilter {
+        LichessQueryFilter {
+            speeds: None,
+            ratings: None,
+            min_rating,
+            max_rating,
+            since: None,
+            until: None,
+        }
+    }
+
+    #[test]
+    fn test_rating_range() {
+        // Aligned with rating groups.
+        let exact = filter(Some(1600), Some(1999));
+        assert_eq!(
+            exact.rating_range().unwrap(),
+            Some(RatingRange {
+                min: 1600,
+                max: Some(1999),
+                approximate: false,
+            })
+        );
+        assert!(!exact.contains_rating_group(RatingGroup::Group1400));
+        assert!(exact.contains_rating_group(RatingGroup::Group1600));
+        assert!(exact.contains_rating_group(RatingGroup::Group1800));
+        assert!(!exact.contains_rating_group(RatingGroup::Group2000));
+
+        // Cutting through rating groups.
+        let approximate = filter(Some(1750), Some(2600));
+        assert_eq!(
+            approximate.rating_range().unwrap(),
+            Some(RatingRange {
+                min: 1600,
+                max: Some(2799),
+                approximate: true,
+            })
+        );
+        assert!(!approximate.contains_rating_group(RatingGroup::Group1400));
+        assert!(approximate.contains_rating_group(RatingGroup::Group1600));
+        assert!(approximate.contains_rating_group(RatingGroup::Group2500));
+        assert!(!approximate.contains_rating_group(RatingGroup::Group3200));
+
+        // Top groups.
+        let top = filter(Some(2800), None);
+        assert_eq!(
+            top.rating_range().unwrap(),
+            Some(RatingRange {
+                min: 2800,
+                max: None,
+                approximate: false,
+            })
+        );
+        assert!(!top.contains_rating_group(RatingGroup::Group2500));
+        assert!(top.contains_rating_group(RatingGroup::Group3200));
+
+        let below_top = filter(Some(2500), Some(2799));
+        assert_eq!(
+            below_top.rating_range().unwrap(),
+            Some(RatingRange {
+                min: 2500,
+                max: Some(2799),
+                approximate: false,
+            })
+        );
+        assert!(below_top.contains_rating_group(RatingGroup::Group2500));
+        assert!(!below_top.contains_rating_group(RatingGroup::Group3200));
+
+        let above_top = filter(Some(3200), None);
+        assert_eq!(
+            above_top.rating_range().unwrap(),
+            Some(RatingRange {
+                min: 2800,
+                max: None,
+                approximate: true,
+            })
+        );
+        assert!(!above_top.contains_rating_group(RatingGroup::Group2500));
+        assert!(above_top.contains_rating_group(RatingGroup::Group3200));
+
+        assert_eq!(filter(None, None).rating_range().unwrap(), None);
+        assert!(filter(Some(2000), Some(1999)).rating_range().is_err());
+    }
+
+    #[test]
+    fn test_masters_search_limits() {
+        let search = |player: &str, page: usize, max_per_page: usize| MastersSearchQuery {
+            player: Some(player.to_owned()),
+            event: None,
+            site: None,
+            since: Year::min_value(),
+            until: Year::max_value(),
+            result: None,
+            min_rating: None,
+            max_rating: None,
+            page,
+            max_per_page,
+        };
+
+        assert!(search("carlsen", 1, 20).check_page().is_ok());
+        assert!(search("carlsen", 0, 20).check_page().is_err());
+        assert!(search("carlsen", 100, 100).check_page().is_ok());
+        assert!(search("carlsen", 101, 100).check_page().is_err());
+        assert!(search("carlsen", 10_000, 0).check_page().is_ok());
+
+        assert!(search("wei yi", 1, 20).check_terms().is_ok());
+        assert!(search("yi", 1, 20).check_terms().is_err());
+        assert!(search("", 1, 20).check_terms().is_ok());
+    }
+
+    #[test]
+    fn test_masters_events_pages() {
+        let query = |page: usize| MastersEventsQuery {
+            q: Some("candidates".to_owned()),
+            since: Year::min_value(),
+            until: Year::max_value(),
+            page,
+            max_per_page: 2,
+        };
+        let events: Vec<_> = [
+            "Candidates 2022",
+            "Olympiad 2022",
+            "Candidates 2020",
+            "Candidates 2018",
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let date: LaxDate = "2022.06.17".parse().unwrap();
+            (
+                format!("{:016x}", i).parse::<MastersEventId>().unwrap(),
+                MastersEvent {
+                    event: name.to_owned(),
+                    site: "Madrid ESP".to_owned(),
+                    games: 1,
+                    first: date,
+                    last: date,
+                },
+            )
+        })
+        .collect();
+
+        assert!(query(0).check_page().is_err());
+        assert!(query(5000).check_page().is_ok());
+        assert!(query(5001).check_page().is_err());
+
+        let (first, more) = query(1).select(&events);
+        assert_eq!(
+            first
+                .iter()
+                .map(|(_, event)| event.event.as_str())
+                .collect::<Vec<_>>(),
+            ["Candidates 2022", "Candidates 2020"]
+        );
+        assert!(more);
+
+        let (second, more) = query(2).select(&events);
+        assert_eq!(second.len(), 1);
+        assert!(!more);
+    }
+}
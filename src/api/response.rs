@@ -3,7 +3,10 @@ use serde_with::{serde_as, DisplayFromStr, TryFromInto};
 use shakmaty::{san::SanPlus, uci::Uci, ByColor, Color};
 
 use crate::{
-    model::{GameId, GamePlayer, LichessGame, MastersGame, Mode, Month, Speed, Stats, Year},
+    model::{
+        Clock, GameId, GamePlayer, LaxDate, LichessGame, MastersEvent, MastersEventId, MastersGame,
+        Mode, Month, MoveBreakdown, Speed, Stats, Termination, Year,
+    },
     opening::Opening,
     util::ByColorDef,
 };
@@ -20,6 +23,18 @@ pub struct ExplorerResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub top_games: Option<Vec<ExplorerGameWithUci>>,
     pub opening: Option<&'static Opening>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rating_range: Option<RatingRange>,
+}
+
+/// Range of average ratings (inclusive) covered by the `minRating` and
+/// `maxRating` filters. Approximate if they cut through a rating group, in
+/// which case the range is widened to the boundaries of the group.
+#[derive(Serialize, Clone, Debug, Eq, PartialEq)]
+pub struct RatingRange {
+    pub min: u16,
+    pub max: Option<u16>,
+    pub approximate: bool,
 }
 
 #[serde_as]
@@ -39,6 +54,8 @@ pub struct ExplorerMove {
     #[serde(flatten)]
     pub stats: Stats,
     pub game: Option<ExplorerGame>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub breakdown: Option<MoveBreakdown>,
 }
 
 #[serde_as]
@@ -67,6 +84,12 @@ pub struct ExplorerGame {
     pub year: Year,
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub month: Option<Month>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clock: Option<Clock>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub termination: Option<Termination>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plies: Option<u32>,
 }
 
 impl ExplorerGame {
@@ -79,6 +102,9 @@ impl ExplorerGame {
             players: info.players,
             year: info.month.year(),
             month: Some(info.month),
+            clock: info.clock,
+            termination: info.termination,
+            plies: info.plies,
         }
     }
 
@@ -91,14 +117,86 @@ impl ExplorerGame {
             players: info.players,
             year: info.date.year(),
             month: info.date.month(),
+            clock: None,
+            termination: None,
+            plies: u32::try_from(info.moves.len()).ok(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MastersSearchResponse {
+    pub page: usize,
+    pub next_page: Option<usize>,
+    /// The search gave up before finding all matching games.
+    pub truncated: bool,
+    pub games: Vec<MastersSearchRow>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MastersSearchRow {
+    #[serde(flatten)]
+    pub row: ExplorerGame,
+    pub event: String,
+    pub site: String,
+    /// Path of the PGN export.
+    pub pgn: String,
+}
+
+impl MastersSearchRow {
+    pub fn new(id: GameId, game: MastersGame) -> MastersSearchRow {
+        MastersSearchRow {
+            event: game.event.clone(),
+            site: game.site.clone(),
+            pgn: format!("/masters/pgn/{}", id),
+            row: ExplorerGame::from_masters(id, game),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MastersEventsResponse {
+    pub page: usize,
+    pub next_page: Option<usize>,
+    pub events: Vec<MastersEventRow>,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug)]
+pub struct MastersEventRow {
+    #[serde_as(as = "DisplayFromStr")]
+    pub id: MastersEventId,
+    pub event: String,
+    pub site: String,
+    pub games: u64,
+    #[serde_as(as = "DisplayFromStr")]
+    pub first: LaxDate,
+    #[serde_as(as = "DisplayFromStr")]
+    pub last: LaxDate,
+}
+
+impl MastersEventRow {
+    pub fn new(id: MastersEventId, event: MastersEvent) -> MastersEventRow {
+        MastersEventRow {
+            id,
+            event: event.event,
+            site: event.site,
+            games: event.games,
+            first: event.first,
+            last: event.last,
         }
     }
 }
 
 #[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct ExplorerHistoryResponse {
     pub history: Vec<ExplorerHistorySegment>,
     pub opening: Option<&'static Opening>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rating_range: Option<RatingRange>,
 }
 
 #[serde_as]
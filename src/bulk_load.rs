@@ -0,0 +1,196 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    fs::{self, File},
+    io::{self, BufRead, BufReader},
+    mem,
+    path::Path,
+};
+
+use thiserror::Error;
+
+use crate::{
+    db::Database,
+    importer::{LichessGameImport, LichessImporter},
+    model::{
+        GameId, Key, LichessEntry, LichessGame, LichessGameMoves, LichessMatchupEntry, RawUci,
+    },
+};
+
+#[derive(Error, Debug)]
+pub enum BulkLoadError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    RocksDb(#[from] rocksdb::Error),
+}
+
+/// Games and entries of the current chunk, aggregated in memory and sorted
+/// by the byte representation of their keys.
+#[derive(Default)]
+struct Chunk {
+    entries: BTreeMap<[u8; Key::SIZE], LichessEntry>,
+    matchup_entries: BTreeMap<[u8; Key::SIZE], LichessMatchupEntry>,
+    games: BTreeMap<[u8; GameId::SIZE], LichessGame>,
+    moves: BTreeMap<[u8; GameId::SIZE], LichessGameMoves>,
+    /// Estimated memory used by all of the above, in bytes, not counting
+    /// the overhead of the maps themselves.
+    size: usize,
+}
+
+impl Chunk {
+    fn add(&mut self, db: &Database, game: LichessGameImport, store_moves: bool) {
+        let prepared = match LichessImporter::prepare(game, store_moves) {
+            Ok(prepared) => prepared,
+            Err(err) => {
+                log::warn!("skipping game: {}", err);
+                return;
+            }
+        };
+
+        let id = prepared.id;
+        match self.games.entry(id.to_bytes()) {
+            Entry::Occupied(_) => {
+                log::debug!("lichess game {} already imported", id);
+                return;
+            }
+            Entry::Vacant(vacant) => {
+                if db
+                    .lichess()
+                    .game(id)
+                    .expect("get game info")
+                    .map_or(false, |info| info.indexed_lichess)
+                {
+                    log::debug!("lichess game {} already imported", id);
+                    return;
+                }
+                self.size += GameId::SIZE
+                    + mem::size_of::<LichessGame>()
+                    + prepared.game.players.white.name.len()
+                    + prepared.game.players.black.name.len();
+                vacant.insert(prepared.game);
+            }
+        }
+        if let Some(moves) = prepared.moves {
+            self.size += GameId::SIZE
+                + mem::size_of::<LichessGameMoves>()
+                + moves.moves.len() * mem::size_of::<RawUci>();
+            self.moves.insert(id.to_bytes(), moves);
+        }
+
+        let mut buf = Vec::with_capacity(LichessEntry::SIZE_HINT);
+        for (key, entry) in prepared.entries {
+            // Combine exactly like the merge operator would, to produce the
+            // same result as the incremental import.
+            buf.clear();
+            entry.write(&mut buf);
+            let aggregated = match self.entries.entry(key.into_bytes()) {
+                Entry::Occupied(occupied) => occupied.into_mut(),
+                Entry::Vacant(vacant) => {
+                    self.size += Key::SIZE + mem::size_of::<LichessEntry>();
+                    vacant.insert(LichessEntry::default())
+                }
+            };
+            let moves_before = aggregated.num_moves();
+            aggregated.extend_from_reader(&mut &buf[..]);
+            self.size += (aggregated.num_moves() - moves_before) * LichessEntry::MOVE_SIZE
+                + mem::size_of::<(u64, GameId)>();
+        }
+        for (key, entry) in prepared.matchup_entries {
+            buf.clear();
+            entry.write(&mut buf);
+            let aggregated = match self.matchup_entries.entry(key.into_bytes()) {
+                Entry::Occupied(occupied) => occupied.into_mut(),
+                Entry::Vacant(vacant) => {
+                    self.size += Key::SIZE + mem::size_of::<LichessMatchupEntry>();
+                    vacant.insert(LichessMatchupEntry::default())
+                }
+            };
+            let groups_before = aggregated.num_groups();
+            aggregated.extend_from_reader(&mut &buf[..]);
+            self.size +=
+                (aggregated.num_groups() - groups_before) * LichessMatchupEntry::GROUP_SIZE;
+        }
+    }
+
+    fn ingest(self, db: &Database, sst_dir: &Path, chunk: usize) -> Result<(), rocksdb::Error> {
+        log::info!(
+            "ingesting chunk {} with {} games and {} entries (about {} MiB) ...",
+            chunk,
+            self.games.len(),
+            self.entries.len(),
+            self.size / (1024 * 1024)
+        );
+        db.lichess().ingest_sorted(
+            sst_dir,
+            chunk,
+            self.entries
+                .into_iter()
+                .map(|(key, entry)| (Key::try_from(&key[..]).expect("key size"), entry)),
+            self.matchup_entries
+                .into_iter()
+                .map(|(key, entry)| (Key::try_from(&key[..]).expect("key size"), entry)),
+            self.games
+                .into_iter()
+                .map(|(id, game)| (GameId::read(&mut &id[..]), game)),
+            self.moves
+                .into_iter()
+                .map(|(id, moves)| (GameId::read(&mut &id[..]), moves)),
+        )
+    }
+}
+
+/// Imports games from newline delimited JSON files (as produced by
+/// `index-lichess --ndjson`) by pre-aggregating entries in memory and
+/// ingesting sorted SST files, rather than merging each game into the
+/// memtable.
+///
+/// Chunks are aggregated until their estimated size (see `Chunk::size`)
+/// reaches `max_chunk_bytes`, and ingested one after another, so that the
+/// result matches the incremental import of the same games in the same
+/// order. With `store_moves`, also stores the full move lists, like
+/// `--store-lichess-moves`. Must not run concurrently with other writers.
+///
+/// Ingestion is not atomic: Each chunk is ingested into the column families
+/// one after another, and earlier chunks stay ingested if a later chunk
+/// fails. If ingestion fails, the database may contain entries of the
+/// failed chunk, without the games being marked as imported, so that
+/// running again would count them twice. Restore a checkpoint taken before
+/// instead.
+pub fn run(
+    db: &Database,
+    inputs: &[impl AsRef<Path>],
+    sst_dir: &Path,
+    max_chunk_bytes: usize,
+    store_moves: bool,
+) -> Result<(), BulkLoadError> {
+    fs::create_dir_all(sst_dir)?;
+
+    let mut chunk = Chunk::default();
+    let mut num_chunks = 0;
+
+    for input in inputs {
+        let input = input.as_ref();
+        log::info!("reading {} ...", input.display());
+
+        for (line_no, line) in BufReader::new(File::open(input)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str(&line) {
+                Ok(game) => chunk.add(db, game, store_moves),
+                Err(err) => log::warn!("{}:{}: {}", input.display(), line_no + 1, err),
+            }
+
+            if chunk.size >= max_chunk_bytes {
+                mem::take(&mut chunk).ingest(db, sst_dir, num_chunks)?;
+                num_chunks += 1;
+            }
+        }
+    }
+
+    chunk.ingest(db, sst_dir, num_chunks)?;
+    log::info!("bulk load finished");
+    Ok(())
+}
@@ -0,0 +1,396 @@
+use std::{
+    fmt, mem,
+    panic::{self, UnwindSafe},
+};
+
+use rocksdb::ColumnFamily;
+
+use crate::{
+    db::{take_merge_error, Database},
+    model::{
+        CheckedKey, GameId, Key, LichessEntry, LichessGame, LichessGameMoves, LichessMatchupEntry,
+        MastersEntry, MastersEvent, MastersEventId, MastersGame, MastersSearchKey, PlayerEntry,
+        PlayerStatus,
+    },
+    util::panic_message,
+};
+
+enum Problem {
+    InvalidKey(String),
+    InvalidValue(String),
+    MissingGames(Vec<GameId>),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::InvalidKey(err) => write!(f, "invalid key: {}", err),
+            Problem::InvalidValue(err) => write!(f, "invalid value: {}", err),
+            Problem::MissingGames(ids) => {
+                f.write_str("references missing games:")?;
+                for id in ids {
+                    write!(f, " {}", id)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+struct HexKey<'a>(&'a [u8]);
+
+impl fmt::Display for HexKey<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct Report {
+    pub records: u64,
+    pub invalid_keys: u64,
+    pub invalid_values: u64,
+    pub missing_game_refs: u64,
+    pub repaired: u64,
+    /// Column family and key of records that can not be decoded.
+    undecodable: Vec<(&'static str, Vec<u8>)>,
+}
+
+impl Report {
+    /// Whether problems remain after the check (and repair, if any).
+    pub fn has_problems(&self) -> bool {
+        self.invalid_keys + self.invalid_values + self.missing_game_refs > self.repaired
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} records, {} invalid keys, {} invalid values, {} missing game refs, {} repaired",
+            self.records,
+            self.invalid_keys,
+            self.invalid_values,
+            self.missing_game_refs,
+            self.repaired
+        )
+    }
+}
+
+/// Scans all column families, decoding every record with the model readers,
+/// and checks that games referenced by entries exist.
+///
+/// The database should be opened with `DbOpt::for_check()`, so that merge
+/// operands that can not be decoded are reported rather than aborting the
+/// process.
+pub fn run(db: &Database) -> Result<Report, rocksdb::Error> {
+    let mut report = Report::default();
+
+    let cf_masters_game = cf(db, "masters_game");
+    let cf_lichess_game = cf(db, "lichess_game");
+
+    check_column(db, "masters", &mut report, |key, value| {
+        let key = match decode_key(key) {
+            Ok(key) => key,
+            Err(problem) => return Ok(Some(problem)),
+        };
+        if let Err(err) = key.year() {
+            return Ok(Some(Problem::InvalidKey(err.to_string())));
+        }
+        match decode(|| {
+            let mut entry = MastersEntry::default();
+            entry.extend_from_reader(&mut &value[..]);
+            entry
+        }) {
+            Ok(entry) => missing_games(db, cf_masters_game, entry.game_ids()),
+            Err(problem) => Ok(Some(problem)),
+        }
+    })?;
+
+    check_column(db, "masters_game", &mut report, |key, value| {
+        if let Some(problem) = check_game_id(key) {
+            return Ok(Some(problem));
+        }
+        Ok(serde_json::from_slice::<MastersGame>(value)
+            .err()
+            .map(|err| Problem::InvalidValue(err.to_string())))
+    })?;
+
+    check_column(db, "masters_dedup", &mut report, |key, value| {
+        if key.len() != MastersGame::FINGERPRINT_SIZE {
+            return Ok(Some(Problem::InvalidKey(format!(
+                "expected {} bytes, got {}",
+                MastersGame::FINGERPRINT_SIZE,
+                key.len()
+            ))));
+        }
+        if let Some(Problem::InvalidKey(err)) = check_game_id(value) {
+            return Ok(Some(Problem::InvalidValue(err)));
+        }
+        missing_games(db, cf_masters_game, [GameId::read(&mut &value[..])])
+    })?;
+
+    check_column(
+        db,
+        "masters_search",
+        &mut report,
+        |key, _value| match decode(|| MastersSearchKey::parse_suffix(key)) {
+            Ok(Some(suffix)) => missing_games(db, cf_masters_game, [suffix.id]),
+            Ok(None) => Ok(Some(Problem::InvalidKey("too short".to_owned()))),
+            Err(Problem::InvalidValue(err)) => Ok(Some(Problem::InvalidKey(err))),
+            Err(problem) => Ok(Some(problem)),
+        },
+    )?;
+
+    check_column(db, "masters_player", &mut report, |key, value| {
+        if key.len() != CheckedKey::SIZE {
+            return Ok(Some(Problem::InvalidKey(format!(
+                "expected {} bytes, got {}",
+                CheckedKey::SIZE,
+                key.len()
+            ))));
+        }
+        let key = match decode_key(&key[..Key::SIZE]) {
+            Ok(key) => key,
+            Err(problem) => return Ok(Some(problem)),
+        };
+        if let Err(err) = key.year() {
+            return Ok(Some(Problem::InvalidKey(err.to_string())));
+        }
+        match decode(|| {
+            let mut entry = PlayerEntry::default();
+            entry.extend_from_reader(&mut &value[..]);
+            entry
+        }) {
+            Ok(entry) => missing_games(db, cf_masters_game, entry.game_ids()),
+            Err(problem) => Ok(Some(problem)),
+        }
+    })?;
+
+    check_column(db, "masters_event", &mut report, |key, value| {
+        if key.len() != MastersEventId::SIZE {
+            return Ok(Some(Problem::InvalidKey(format!(
+                "expected {} bytes, got {}",
+                MastersEventId::SIZE,
+                key.len()
+            ))));
+        }
+        Ok(match decode(|| MastersEvent::read(&mut &value[..])) {
+            Ok(Ok(_)) => None,
+            Ok(Err(err)) => Some(Problem::InvalidValue(err.to_string())),
+            Err(problem) => Some(problem),
+        })
+    })?;
+
+    check_column(db, "masters_by_event", &mut report, |key, value| {
+        if key.len() != CheckedKey::SIZE {
+            return Ok(Some(Problem::InvalidKey(format!(
+                "expected {} bytes, got {}",
+                CheckedKey::SIZE,
+                key.len()
+            ))));
+        }
+        let key = match decode_key(&key[..Key::SIZE]) {
+            Ok(key) => key,
+            Err(problem) => return Ok(Some(problem)),
+        };
+        if let Err(err) = key.year() {
+            return Ok(Some(Problem::InvalidKey(err.to_string())));
+        }
+        match decode(|| {
+            let mut entry = MastersEntry::default();
+            entry.extend_from_reader(&mut &value[..]);
+            entry
+        }) {
+            Ok(entry) => missing_games(db, cf_masters_game, entry.game_ids()),
+            Err(problem) => Ok(Some(problem)),
+        }
+    })?;
+
+    check_column(db, "lichess", &mut report, |key, value| {
+        let key = match decode_key(key) {
+            Ok(key) => key,
+            Err(problem) => return Ok(Some(problem)),
+        };
+        if let Err(err) = key.month() {
+            return Ok(Some(Problem::InvalidKey(err.to_string())));
+        }
+        match decode(|| {
+            let mut entry = LichessEntry::default();
+            entry.extend_from_reader(&mut &value[..]);
+            entry
+        }) {
+            Ok(entry) => missing_games(db, cf_lichess_game, entry.game_ids()),
+            Err(problem) => Ok(Some(problem)),
+        }
+    })?;
+
+    check_column(db, "lichess_matchup", &mut report, |key, value| {
+        let key = match decode_key(key) {
+            Ok(key) => key,
+            Err(problem) => return Ok(Some(problem)),
+        };
+        if let Err(err) = key.month() {
+            return Ok(Some(Problem::InvalidKey(err.to_string())));
+        }
+        Ok(decode(|| {
+            let mut entry = LichessMatchupEntry::default();
+            entry.extend_from_reader(&mut &value[..]);
+            entry
+        })
+        .err())
+    })?;
+
+    check_column(db, "lichess_game", &mut report, |key, value| {
+        if let Some(problem) = check_game_id(key) {
+            return Ok(Some(problem));
+        }
+        Ok(decode(|| LichessGame::read(&mut &value[..])).err())
+    })?;
+
+    check_column(db, "lichess_game_moves", &mut report, |key, value| {
+        if let Some(problem) = check_game_id(key) {
+            return Ok(Some(problem));
+        }
+        match LichessGameMoves::read(&mut &value[..]) {
+            Ok(_) => missing_games(db, cf_lichess_game, [GameId::read(&mut &key[..])]),
+            Err(err) => Ok(Some(Problem::InvalidValue(err.to_string()))),
+        }
+    })?;
+
+    check_column(db, "player", &mut report, |key, value| {
+        // Checked keys extend legacy keys with a KeyCheck.
+        let key = match decode_key(if key.len() == CheckedKey::SIZE {
+            &key[..Key::SIZE]
+        } else {
+            key
+        }) {
+            Ok(key) => key,
+            Err(problem) => return Ok(Some(problem)),
+        };
+        if let Err(err) = key.month() {
+            return Ok(Some(Problem::InvalidKey(err.to_string())));
+        }
+        match decode(|| {
+            let mut entry = PlayerEntry::default();
+            entry.extend_from_reader(&mut &value[..]);
+            entry
+        }) {
+            Ok(entry) => missing_games(db, cf_lichess_game, entry.game_ids()),
+            Err(problem) => Ok(Some(problem)),
+        }
+    })?;
+
+    check_column(db, "player_status", &mut report, |key, value| {
+        if std::str::from_utf8(key).is_err() {
+            return Ok(Some(Problem::InvalidKey("user id not utf-8".to_owned())));
+        }
+        Ok(decode(|| PlayerStatus::read(&mut &value[..])).err())
+    })?;
+
+    Ok(report)
+}
+
+fn cf<'a>(db: &'a Database, name: &str) -> &'a ColumnFamily {
+    db.inner.cf_handle(name).expect("cf")
+}
+
+/// Deletes the records that could not be decoded by `run()`. Missing game
+/// references are only reported. Must not run concurrently with writers.
+pub fn repair(db: &Database, report: &mut Report) -> Result<(), rocksdb::Error> {
+    for (name, key) in mem::take(&mut report.undecodable) {
+        db.inner.delete_cf(cf(db, name), key)?;
+        report.repaired += 1;
+    }
+    Ok(())
+}
+
+fn check_column<F>(
+    db: &Database,
+    name: &'static str,
+    report: &mut Report,
+    mut check: F,
+) -> Result<(), rocksdb::Error>
+where
+    F: FnMut(&[u8], &[u8]) -> Result<Option<Problem>, rocksdb::Error>,
+{
+    log::info!("checking {} ...", name);
+    let cf = cf(db, name);
+    let mut iter = db.inner.raw_iterator_cf(cf);
+    take_merge_error();
+    iter.seek_to_first();
+
+    while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+        report.records += 1;
+        if report.records % 1_000_000 == 0 {
+            log::info!("checked {} records ...", report.records);
+        }
+
+        // Operands are merged when the iterator moves to the record.
+        let problem = match take_merge_error() {
+            Some(err) => Some(Problem::InvalidValue(err)),
+            None => check(key, value)?,
+        };
+
+        if let Some(problem) = problem {
+            log::warn!("{} {}: {}", name, HexKey(key), problem);
+            match problem {
+                Problem::InvalidKey(_) => report.invalid_keys += 1,
+                Problem::InvalidValue(_) => report.invalid_values += 1,
+                Problem::MissingGames(_) => report.missing_game_refs += 1,
+            }
+            if !matches!(problem, Problem::MissingGames(_)) {
+                report.undecodable.push((name, key.to_vec()));
+            }
+        }
+
+        // Discard errors of point reads in check.
+        take_merge_error();
+        iter.next();
+    }
+
+    iter.status()
+}
+
+fn decode_key(key: &[u8]) -> Result<Key, Problem> {
+    Key::try_from(key).map_err(|_| {
+        Problem::InvalidKey(format!("expected {} bytes, got {}", Key::SIZE, key.len()))
+    })
+}
+
+fn check_game_id(key: &[u8]) -> Option<Problem> {
+    if key.len() != GameId::SIZE {
+        return Some(Problem::InvalidKey(format!(
+            "expected {} bytes, got {}",
+            GameId::SIZE,
+            key.len()
+        )));
+    }
+    decode(|| GameId::read(&mut &key[..]))
+        .err()
+        .map(|problem| match problem {
+            Problem::InvalidValue(err) => Problem::InvalidKey(err),
+            problem => problem,
+        })
+}
+
+fn missing_games<I: IntoIterator<Item = GameId>>(
+    db: &Database,
+    cf_game: &ColumnFamily,
+    ids: I,
+) -> Result<Option<Problem>, rocksdb::Error> {
+    let mut missing = Vec::new();
+    for id in ids {
+        if db.inner.get_pinned_cf(cf_game, id.to_bytes())?.is_none() {
+            missing.push(id);
+        }
+    }
+    Ok((!missing.is_empty()).then_some(Problem::MissingGames(missing)))
+}
+
+fn decode<T, F: FnOnce() -> T + UnwindSafe>(f: F) -> Result<T, Problem> {
+    panic::catch_unwind(f).map_err(|err| Problem::InvalidValue(panic_message(&*err)))
+}
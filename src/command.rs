@@ -0,0 +1,252 @@
+use std::{path::PathBuf, process, sync::Arc};
+
+use clap::Subcommand;
+
+use crate::{
+    api::LichessQueryFilter,
+    bulk_load, check,
+    db::{Database, DbOpt},
+    dump, lite, merge, migration,
+    model::{RatingGroup, Speed},
+    polyglot::{self, BookOpt, BookSource, BookWeight},
+};
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Create a checkpoint (hard-linked snapshot) of the database in a new
+    /// directory. The server must not be running. Use the `/checkpoint`
+    /// endpoint while serving.
+    Checkpoint {
+        /// Target directory. Must not exist yet.
+        path: PathBuf,
+    },
+    /// Create a new incremental backup of the database. The server must not
+    /// be running. Use the `/backup` endpoint while serving.
+    Backup {
+        /// Backup directory, shared by all incremental backups.
+        backup_dir: PathBuf,
+        /// Purge old backups, keeping only this number of latest backups.
+        #[arg(long)]
+        keep: Option<usize>,
+    },
+    /// Restore the database from a backup. The server must not be running.
+    Restore {
+        /// Backup directory.
+        backup_dir: PathBuf,
+        /// Restore the given backup instead of the latest.
+        #[arg(long)]
+        backup_id: Option<u32>,
+    },
+    /// Export the database to a portable, compressed dump of decoded
+    /// records, independent of RocksDB versions and options, and of the
+    /// on-disk format. Opens the database read-only, so it also works while
+    /// the server is running.
+    Dump {
+        /// Target file.
+        path: PathBuf,
+        /// Dump only the given column families, for example `masters` and
+        /// `masters_game`. The other masters indexes are rebuilt on load.
+        #[arg(long = "column")]
+        columns: Vec<String>,
+    },
+    /// Load a dump created with `dump`, combining records with existing data.
+    /// Load into an empty database to restore an exact copy.
+    Load {
+        /// Dump file.
+        path: PathBuf,
+    },
+    /// Import Lichess games from newline delimited JSON files (see
+    /// `index-lichess --ndjson`) by ingesting pre-aggregated SST files.
+    /// Much faster than the /import/lichess endpoint for entire monthly
+    /// dumps. The server must not be running. Chunks are not ingested
+    /// atomically, so take a checkpoint before, and restore it if loading
+    /// fails.
+    BulkLoad {
+        /// Input files.
+        inputs: Vec<PathBuf>,
+        /// Directory for temporary SST files. Should be on the same file
+        /// system as the database, so that files can be moved instead of
+        /// copied.
+        #[arg(long, default_value = "_sst")]
+        sst_dir: PathBuf,
+        /// Maximum estimated memory in MiB for games and entries aggregated
+        /// before ingesting a chunk. Actual usage is somewhat higher.
+        #[arg(long, default_value = "1024")]
+        max_chunk_mib: usize,
+        /// Also store the full move lists, to serve /lichess/pgn/:id, like
+        /// the server option of the same name.
+        #[arg(long)]
+        store_lichess_moves: bool,
+    },
+    /// Merge another explorer database into this one, for example to
+    /// combine months indexed on different machines. The server must not
+    /// be running.
+    Merge {
+        /// Source database, opened read-only.
+        source: PathBuf,
+    },
+    /// Export popular positions to a small, self-contained file for offline
+    /// clients, readable with `lite::LiteDatabase`. Opens the database
+    /// read-only.
+    ExportLite {
+        /// Target file.
+        path: PathBuf,
+        /// Minimum number of lichess games for a position to be included.
+        #[arg(long, default_value = "10000")]
+        min_games: u64,
+        /// Minimum number of masters games for a position to be included.
+        #[arg(long, default_value = "100")]
+        min_masters_games: u64,
+        /// Maximum depth of positions, in plies from the starting position.
+        #[arg(long, default_value = "40")]
+        max_plies: usize,
+    },
+    /// Export a Polyglot opening book from explorer statistics. Opens the
+    /// database read-only.
+    ExportPolyglot {
+        /// Target file, usually with extension `.bin`.
+        path: PathBuf,
+        #[arg(long, value_enum, default_value = "lichess")]
+        source: BookSource,
+        #[arg(long, value_enum, default_value = "blend")]
+        weight: BookWeight,
+        /// Minimum number of games for a move to be included.
+        #[arg(long, default_value = "100")]
+        min_games: u64,
+        /// Only consider these speeds (lichess only).
+        #[arg(long, value_delimiter = ',')]
+        speeds: Vec<Speed>,
+        /// Only consider these rating groups (lichess only).
+        #[arg(long, value_delimiter = ',')]
+        ratings: Vec<RatingGroup>,
+        /// Maximum depth of positions, in plies from the starting position.
+        #[arg(long, default_value = "30")]
+        max_plies: usize,
+    },
+    /// Check the consistency of the database, decoding every record and
+    /// verifying that referenced games exist. Opens the database read-only.
+    /// Exits with status 1 if problems remain.
+    Check {
+        /// Delete records that can not be decoded. The server must not be
+        /// running.
+        #[arg(long)]
+        repair: bool,
+    },
+}
+
+impl Command {
+    pub fn run(self, db_opt: DbOpt) {
+        match self {
+            Command::Checkpoint { path } => {
+                let db = Database::open(db_opt).expect("db");
+                db.checkpoint(&path).expect("create checkpoint");
+            }
+            Command::Backup { backup_dir, keep } => {
+                let db = Database::open(db_opt).expect("db");
+                db.backup(&backup_dir, keep).expect("create backup");
+            }
+            Command::Restore {
+                backup_dir,
+                backup_id,
+            } => {
+                Database::restore(db_opt, &backup_dir, backup_id).expect("restore backup");
+            }
+            Command::Dump { path, columns } => {
+                let db = Database::open(db_opt.into_read_only()).expect("db");
+                dump::dump(&db, &path, &columns).expect("dump database");
+            }
+            Command::Load { path } => {
+                let db = Database::open(db_opt).expect("db");
+                dump::load(&db, &path).expect("load dump");
+                db.flush().expect("flush");
+            }
+            Command::BulkLoad {
+                inputs,
+                sst_dir,
+                max_chunk_mib,
+                store_lichess_moves,
+            } => {
+                let db = Database::open(db_opt).expect("db");
+                bulk_load::run(
+                    &db,
+                    &inputs,
+                    &sst_dir,
+                    max_chunk_mib.saturating_mul(1024 * 1024),
+                    store_lichess_moves,
+                )
+                .expect("bulk load");
+            }
+            Command::Merge { source } => {
+                let source = Database::open(db_opt.read_only_at(source)).expect("source db");
+                let target = Arc::new(Database::open(db_opt).expect("db"));
+                // Masters games are imported through the duplicate index.
+                migration::run_pending(&target).expect("migrate target db");
+                let report = merge::run(&source, &target).expect("merge databases");
+                log::info!("{:?}", report);
+                target.flush().expect("flush");
+            }
+            Command::ExportLite {
+                path,
+                min_games,
+                min_masters_games,
+                max_plies,
+            } => {
+                let db = Database::open(db_opt.into_read_only()).expect("db");
+                lite::export(
+                    &db,
+                    &path,
+                    &lite::ExportOpt {
+                        min_games,
+                        min_masters_games,
+                        max_plies,
+                    },
+                )
+                .expect("export lite database");
+            }
+            Command::ExportPolyglot {
+                path,
+                source,
+                weight,
+                min_games,
+                speeds,
+                ratings,
+                max_plies,
+            } => {
+                let db = Database::open(db_opt.into_read_only()).expect("db");
+                polyglot::export(
+                    &db,
+                    &path,
+                    &BookOpt {
+                        source,
+                        weight,
+                        min_games,
+                        filter: LichessQueryFilter {
+                            speeds: Some(speeds).filter(|s| !s.is_empty()),
+                            ratings: Some(ratings).filter(|r| !r.is_empty()),
+                            min_rating: None,
+                            max_rating: None,
+                            since: None,
+                            until: None,
+                        },
+                        max_plies,
+                    },
+                )
+                .expect("export polyglot book");
+            }
+            Command::Check { repair } => {
+                let mut report = {
+                    let db = Database::open(db_opt.for_check()).expect("db");
+                    check::run(&db).expect("check database")
+                };
+                if repair {
+                    let db = Database::open(db_opt).expect("db");
+                    check::repair(&db, &mut report).expect("repair database");
+                }
+                log::info!("{}", report);
+                if report.has_problems() {
+                    process::exit(1);
+                }
+            }
+        }
+    }
+}
@@ -1,17 +1,31 @@
-use std::path::PathBuf;
+use std::{
+    cell::RefCell,
+    io, mem,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+use bytes::Buf;
 use clap::Parser;
 use rocksdb::{
+    backup::{BackupEngine, BackupEngineOptions, RestoreOptions},
+    checkpoint::Checkpoint,
     BlockBasedOptions, Cache, ColumnFamily, ColumnFamilyDescriptor, DBCompressionType,
-    MergeOperands, Options, ReadOptions, SliceTransform, WriteBatch, DB,
+    IngestExternalFileOptions, MergeOperands, Options, ReadOptions, SliceTransform, SstFileWriter,
+    WriteBatch, DB,
 };
+use thiserror::Error;
 
 use crate::{
-    api::{ExplorerHistorySegment, LichessQueryFilter},
+    api::{ExplorerHistorySegment, LichessQueryFilter, MastersSearchQuery},
+    migration::{FORMAT_VERSION, UNVERSIONED_FORMAT},
     model::{
-        GameId, Key, KeyPrefix, LichessEntry, LichessGame, MastersEntry, MastersGame, Month,
-        PlayerEntry, PlayerStatus, Stats, UserId, Year,
+        CheckedKey, GameId, Key, KeyCheck, KeyPrefix, LichessEntry, LichessGame, LichessGameMoves,
+        LichessMatchupEntry, MastersEntry, MastersEvent, MastersEventId, MastersGame,
+        MastersSearchKey, Month, PlayerEntry, PlayerStatus, Stats, UserId, Year,
     },
+    util::panic_message,
 };
 
 #[derive(Parser)]
@@ -26,11 +40,88 @@ pub struct DbOpt {
     /// page cache.
     #[arg(long, default_value = "4294967296")]
     db_cache: usize,
+    /// Open the database read-only, for example to serve a synced copy.
+    /// Disables imports, compaction and indexing.
+    #[arg(long, conflicts_with = "secondary")]
+    read_only: bool,
+    /// Open the database as a secondary instance that follows the primary
+    /// at --db. The given directory holds files of the secondary instance.
+    /// Disables imports, compaction and indexing.
+    #[arg(long)]
+    secondary: Option<PathBuf>,
+    /// Interval in seconds to catch up with the primary, when running as a
+    /// secondary instance.
+    #[arg(long, default_value = "5")]
+    secondary_catch_up_interval: u64,
+    #[arg(skip)]
+    merge_mode: MergeMode,
+}
+
+impl DbOpt {
+    /// Same options, but read-only, for example to export while the server
+    /// is running.
+    pub fn into_read_only(self) -> DbOpt {
+        DbOpt {
+            read_only: true,
+            secondary: None,
+            ..self
+        }
+    }
+
+    /// Options to open another database at `path` read-only, with otherwise
+    /// identical settings.
+    pub fn read_only_at(&self, path: PathBuf) -> DbOpt {
+        DbOpt {
+            db: path,
+            db_compaction_readahead: self.db_compaction_readahead,
+            db_cache: self.db_cache,
+            read_only: true,
+            secondary: None,
+            secondary_catch_up_interval: self.secondary_catch_up_interval,
+            merge_mode: MergeMode::Combine,
+        }
+    }
+
+    /// Options to open the same database read-only for `check`, with merge
+    /// operators that report operands that can not be decoded, instead of
+    /// panicking. See `take_merge_error()`.
+    pub fn for_check(&self) -> DbOpt {
+        DbOpt {
+            merge_mode: MergeMode::Check,
+            ..self.read_only_at(self.db.clone())
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+enum MergeMode {
+    #[default]
+    Combine,
+    Check,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AccessMode {
+    Primary,
+    ReadOnly,
+    Secondary { catch_up_interval: Duration },
+}
+
+#[derive(Error, Debug)]
+pub enum OpenError {
+    #[error(transparent)]
+    RocksDb(#[from] rocksdb::Error),
+    #[error("database has format version {found}, but this build only supports versions up to {supported}")]
+    UnsupportedFormatVersion { found: u32, supported: u32 },
 }
 
+/// Key in the default column family holding the on-disk format version.
+const FORMAT_VERSION_KEY: &[u8] = b"format_version";
+
 #[derive(Debug)]
 pub struct Database {
     pub inner: DB,
+    access: AccessMode,
 }
 
 type MergeFn = fn(key: &[u8], existing: Option<&[u8]>, operands: &MergeOperands) -> Option<Vec<u8>>;
@@ -43,7 +134,11 @@ struct Column<'a> {
 }
 
 impl Column<'_> {
-    fn descriptor(self) -> ColumnFamilyDescriptor {
+    fn descriptor(self, merge_mode: MergeMode) -> ColumnFamilyDescriptor {
+        ColumnFamilyDescriptor::new(self.name, self.options(merge_mode))
+    }
+
+    fn options(&self, merge_mode: MergeMode) -> Options {
         // Mostly using modern defaults from
         // https://github.com/facebook/rocksdb/wiki/Setup-Options-and-Basic-Tuning.
         let mut table_opts = BlockBasedOptions::default();
@@ -67,15 +162,59 @@ impl Column<'_> {
         });
 
         if let Some((name, merge_fn)) = self.merge {
-            cf_opts.set_merge_operator_associative(name, merge_fn);
+            match merge_mode {
+                MergeMode::Combine => cf_opts.set_merge_operator_associative(name, merge_fn),
+                MergeMode::Check => cf_opts.set_merge_operator_associative(
+                    name,
+                    move |key: &[u8], existing: Option<&[u8]>, operands: &MergeOperands| {
+                        checked_merge(merge_fn, key, existing, operands)
+                    },
+                ),
+            }
         }
 
-        ColumnFamilyDescriptor::new(self.name, cf_opts)
+        cf_opts
+    }
+}
+
+fn lichess_column(cache: &Cache) -> Column<'_> {
+    Column {
+        name: "lichess",
+        prefix: Some(KeyPrefix::SIZE),
+        merge: Some(("lichess_merge", lichess_merge)),
+        cache,
+    }
+}
+
+fn lichess_matchup_column(cache: &Cache) -> Column<'_> {
+    Column {
+        name: "lichess_matchup",
+        prefix: Some(KeyPrefix::SIZE),
+        merge: Some(("lichess_matchup_merge", lichess_matchup_merge)),
+        cache,
+    }
+}
+
+fn lichess_game_column(cache: &Cache) -> Column<'_> {
+    Column {
+        name: "lichess_game",
+        prefix: None,
+        merge: Some(("lichess_game_merge", lichess_game_merge)),
+        cache,
+    }
+}
+
+fn lichess_game_moves_column(cache: &Cache) -> Column<'_> {
+    Column {
+        name: "lichess_game_moves",
+        prefix: None,
+        merge: None,
+        cache,
     }
 }
 
 impl Database {
-    pub fn open(opt: DbOpt) -> Result<Database, rocksdb::Error> {
+    pub fn open(opt: DbOpt) -> Result<Database, OpenError> {
         // Note on usage in async contexts: All database operations are
         // blocking (https://github.com/facebook/rocksdb/issues/3254).
         // Calls should be run in a thread-pool to avoid blocking other
@@ -95,61 +234,190 @@ impl Database {
 
         let cache = Cache::new_lru_cache(opt.db_cache)?;
 
-        let inner = DB::open_cf_descriptors(
-            &db_opts,
-            opt.db,
-            vec![
-                // Masters database
-                Column {
-                    name: "masters",
-                    prefix: Some(KeyPrefix::SIZE),
-                    merge: Some(("masters_merge", masters_merge)),
-                    cache: &cache,
-                }
-                .descriptor(),
-                Column {
-                    name: "masters_game",
-                    prefix: None,
-                    merge: None,
-                    cache: &cache,
-                }
-                .descriptor(),
-                // Lichess database
-                Column {
-                    name: "lichess",
-                    prefix: Some(KeyPrefix::SIZE),
-                    merge: Some(("lichess_merge", lichess_merge)),
-                    cache: &cache,
-                }
-                .descriptor(),
-                Column {
-                    name: "lichess_game",
-                    prefix: None,
-                    merge: Some(("lichess_game_merge", lichess_game_merge)),
-                    cache: &cache,
-                }
-                .descriptor(),
-                // Player database (also shares lichess_game)
-                Column {
-                    name: "player",
-                    prefix: Some(KeyPrefix::SIZE),
-                    merge: Some(("player_merge", player_merge)),
-                    cache: &cache,
-                }
-                .descriptor(),
-                Column {
-                    name: "player_status",
-                    prefix: None,
-                    merge: None,
-                    cache: &cache,
-                }
-                .descriptor(),
-            ],
-        )?;
+        // RocksDB creates CURRENT when creating a new database.
+        let fresh = !opt.db.join("CURRENT").exists();
 
-        log::info!("database opened");
+        let column_families = vec![
+            // Masters database
+            Column {
+                name: "masters",
+                prefix: Some(KeyPrefix::SIZE),
+                merge: Some(("masters_merge", masters_merge)),
+                cache: &cache,
+            }
+            .descriptor(opt.merge_mode),
+            Column {
+                name: "masters_game",
+                prefix: None,
+                merge: None,
+                cache: &cache,
+            }
+            .descriptor(opt.merge_mode),
+            Column {
+                name: "masters_dedup",
+                prefix: None,
+                merge: None,
+                cache: &cache,
+            }
+            .descriptor(opt.merge_mode),
+            Column {
+                name: "masters_search",
+                prefix: None,
+                merge: None,
+                cache: &cache,
+            }
+            .descriptor(opt.merge_mode),
+            Column {
+                name: "masters_player",
+                prefix: Some(KeyPrefix::SIZE),
+                merge: Some(("player_merge", player_merge)),
+                cache: &cache,
+            }
+            .descriptor(opt.merge_mode),
+            Column {
+                name: "masters_event",
+                prefix: None,
+                merge: Some(("masters_event_merge", masters_event_merge)),
+                cache: &cache,
+            }
+            .descriptor(opt.merge_mode),
+            Column {
+                name: "masters_by_event",
+                prefix: Some(KeyPrefix::SIZE),
+                merge: Some(("masters_merge", masters_merge)),
+                cache: &cache,
+            }
+            .descriptor(opt.merge_mode),
+            // Lichess database
+            lichess_column(&cache).descriptor(opt.merge_mode),
+            lichess_matchup_column(&cache).descriptor(opt.merge_mode),
+            lichess_game_column(&cache).descriptor(opt.merge_mode),
+            lichess_game_moves_column(&cache).descriptor(opt.merge_mode),
+            // Player database (also shares lichess_game)
+            Column {
+                name: "player",
+                prefix: Some(KeyPrefix::SIZE),
+                merge: Some(("player_merge", player_merge)),
+                cache: &cache,
+            }
+            .descriptor(opt.merge_mode),
+            Column {
+                name: "player_status",
+                prefix: None,
+                merge: None,
+                cache: &cache,
+            }
+            .descriptor(opt.merge_mode),
+        ];
+
+        let (inner, access) = match opt.secondary {
+            Some(ref secondary_path) => {
+                db_opts.set_max_open_files(-1); // required for secondary instances
+                (
+                    DB::open_cf_descriptors_as_secondary(
+                        &db_opts,
+                        &opt.db,
+                        secondary_path,
+                        column_families,
+                    )?,
+                    AccessMode::Secondary {
+                        catch_up_interval: Duration::from_secs(opt.secondary_catch_up_interval),
+                    },
+                )
+            }
+            None if opt.read_only => (
+                DB::open_cf_descriptors_read_only(&db_opts, &opt.db, column_families, false)?,
+                AccessMode::ReadOnly,
+            ),
+            None => (
+                DB::open_cf_descriptors(&db_opts, &opt.db, column_families)?,
+                AccessMode::Primary,
+            ),
+        };
+
+        let db = Database { inner, access };
+
+        let version = match db.stored_format_version()? {
+            Some(version) => version,
+            None if fresh => FORMAT_VERSION,
+            None => UNVERSIONED_FORMAT,
+        };
+        if version > FORMAT_VERSION {
+            return Err(OpenError::UnsupportedFormatVersion {
+                found: version,
+                supported: FORMAT_VERSION,
+            });
+        }
+        if !db.is_read_only() && db.stored_format_version()?.is_none() {
+            db.put_format_version(version)?;
+        }
+
+        log::info!(
+            "database opened ({:?}, format version {})",
+            db.access,
+            version
+        );
 
-        Ok(Database { inner })
+        Ok(db)
+    }
+
+    fn stored_format_version(&self) -> Result<Option<u32>, rocksdb::Error> {
+        Ok(self
+            .inner
+            .get_pinned(FORMAT_VERSION_KEY)?
+            .map(|buf| (&mut &buf[..]).get_u32_le()))
+    }
+
+    /// Format version of the data on disk. Lower than
+    /// `migration::FORMAT_VERSION` while migrations are pending.
+    pub fn format_version(&self) -> Result<u32, rocksdb::Error> {
+        Ok(self.stored_format_version()?.unwrap_or(UNVERSIONED_FORMAT))
+    }
+
+    pub fn put_format_version(&self, version: u32) -> Result<(), rocksdb::Error> {
+        self.inner.put(FORMAT_VERSION_KEY, version.to_le_bytes())
+    }
+
+    /// Restores the database from an incremental backup created with
+    /// `Database::backup()`. The database must not be open.
+    pub fn restore(
+        opt: DbOpt,
+        backup_dir: &Path,
+        backup_id: Option<u32>,
+    ) -> Result<(), rocksdb::Error> {
+        let mut engine = BackupEngine::open(&BackupEngineOptions::default(), backup_dir)?;
+        let restore_opts = RestoreOptions::default();
+        match backup_id {
+            Some(backup_id) => {
+                engine.restore_from_backup(&opt.db, &opt.db, &restore_opts, backup_id)?
+            }
+            None => engine.restore_from_latest_backup(&opt.db, &opt.db, &restore_opts)?,
+        }
+        log::info!("database restored from {}", backup_dir.display());
+        Ok(())
+    }
+
+    /// Creates a consistent snapshot of the database in a new directory.
+    /// Files are hard-linked if on the same filesystem, so this is cheap even
+    /// for large databases.
+    pub fn checkpoint(&self, path: &Path) -> Result<(), rocksdb::Error> {
+        Checkpoint::new(&self.inner)?.create_checkpoint(path)?;
+        log::info!("checkpoint created at {}", path.display());
+        Ok(())
+    }
+
+    /// Creates a new incremental backup. Files already present in the
+    /// backup directory from previous backups are shared. Memtables of a
+    /// read-only database can not be flushed, so its write-ahead log is
+    /// backed up instead.
+    pub fn backup(&self, backup_dir: &Path, keep: Option<usize>) -> Result<(), rocksdb::Error> {
+        let mut engine = BackupEngine::open(&BackupEngineOptions::default(), backup_dir)?;
+        engine.create_new_backup_flush(&self.inner, !self.is_read_only())?;
+        if let Some(keep) = keep {
+            engine.purge_old_backups(keep)?;
+        }
+        log::info!("backup created in {}", backup_dir.display());
+        Ok(())
     }
 
     pub fn compact(&self) {
@@ -157,6 +425,33 @@ impl Database {
         self.masters().compact();
     }
 
+    pub fn access(&self) -> AccessMode {
+        self.access
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.access != AccessMode::Primary
+    }
+
+    pub fn try_catch_up_with_primary(&self) -> Result<(), rocksdb::Error> {
+        match self.access {
+            AccessMode::Secondary { .. } => self.inner.try_catch_up_with_primary(),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn flush(&self) -> Result<(), rocksdb::Error> {
+        if self.is_read_only() {
+            return Ok(());
+        }
+
+        self.lichess().flush()?;
+        self.masters().flush()?;
+        self.inner.flush_wal(true)?;
+        log::info!("database flushed");
+        Ok(())
+    }
+
     pub fn masters(&self) -> MastersDatabase<'_> {
         MastersDatabase {
             inner: &self.inner,
@@ -165,6 +460,26 @@ impl Database {
                 .inner
                 .cf_handle("masters_game")
                 .expect("cf masters_game"),
+            cf_masters_dedup: self
+                .inner
+                .cf_handle("masters_dedup")
+                .expect("cf masters_dedup"),
+            cf_masters_search: self
+                .inner
+                .cf_handle("masters_search")
+                .expect("cf masters_search"),
+            cf_masters_player: self
+                .inner
+                .cf_handle("masters_player")
+                .expect("cf masters_player"),
+            cf_masters_event: self
+                .inner
+                .cf_handle("masters_event")
+                .expect("cf masters_event"),
+            cf_masters_by_event: self
+                .inner
+                .cf_handle("masters_by_event")
+                .expect("cf masters_by_event"),
         }
     }
 
@@ -172,10 +487,18 @@ impl Database {
         LichessDatabase {
             inner: &self.inner,
             cf_lichess: self.inner.cf_handle("lichess").expect("cf lichess"),
+            cf_lichess_matchup: self
+                .inner
+                .cf_handle("lichess_matchup")
+                .expect("cf lichess_matchup"),
             cf_lichess_game: self
                 .inner
                 .cf_handle("lichess_game")
                 .expect("cf lichess_game"),
+            cf_lichess_game_moves: self
+                .inner
+                .cf_handle("lichess_game_moves")
+                .expect("cf lichess_game_moves"),
 
             cf_player: self.inner.cf_handle("player").expect("cf player"),
             cf_player_status: self
@@ -190,12 +513,32 @@ pub struct MastersDatabase<'a> {
     inner: &'a DB,
     cf_masters: &'a ColumnFamily,
     cf_masters_game: &'a ColumnFamily,
+    cf_masters_dedup: &'a ColumnFamily,
+    cf_masters_search: &'a ColumnFamily,
+    cf_masters_player: &'a ColumnFamily,
+    cf_masters_event: &'a ColumnFamily,
+    cf_masters_by_event: &'a ColumnFamily,
 }
 
 impl MastersDatabase<'_> {
     pub fn compact(&self) {
         compact_column(self.inner, self.cf_masters);
         compact_column(self.inner, self.cf_masters_game);
+        compact_column(self.inner, self.cf_masters_dedup);
+        compact_column(self.inner, self.cf_masters_search);
+        compact_column(self.inner, self.cf_masters_player);
+        compact_column(self.inner, self.cf_masters_event);
+        compact_column(self.inner, self.cf_masters_by_event);
+    }
+
+    pub fn flush(&self) -> Result<(), rocksdb::Error> {
+        self.inner.flush_cf(self.cf_masters)?;
+        self.inner.flush_cf(self.cf_masters_game)?;
+        self.inner.flush_cf(self.cf_masters_dedup)?;
+        self.inner.flush_cf(self.cf_masters_search)?;
+        self.inner.flush_cf(self.cf_masters_player)?;
+        self.inner.flush_cf(self.cf_masters_event)?;
+        self.inner.flush_cf(self.cf_masters_by_event)
     }
 
     pub fn has_game(&self, id: GameId) -> Result<bool, rocksdb::Error> {
@@ -231,10 +574,255 @@ impl MastersDatabase<'_> {
             .collect()
     }
 
-    pub fn has(&self, key: Key) -> Result<bool, rocksdb::Error> {
-        self.inner
-            .get_pinned_cf(self.cf_masters, key.into_bytes())
-            .map(|maybe_entry| maybe_entry.is_some())
+    /// Finds an existing game with the same players, date and moves.
+    pub fn duplicate_of(&self, game: &MastersGame) -> Result<Option<GameId>, rocksdb::Error> {
+        Ok(self
+            .inner
+            .get_pinned_cf(self.cf_masters_dedup, game.fingerprint())?
+            .map(|buf| GameId::read(&mut buf.as_ref())))
+    }
+
+    /// Builds the duplicate index from all games. Keeps the first game of
+    /// existing duplicates.
+    pub fn backfill_dedup(&self) -> Result<u64, rocksdb::Error> {
+        let mut iter = self.inner.raw_iterator_cf(self.cf_masters_game);
+        iter.seek_to_first();
+
+        let mut games = 0;
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            let game: MastersGame =
+                serde_json::from_slice(value).expect("deserialize masters game");
+            let fingerprint = game.fingerprint();
+            if self
+                .inner
+                .get_pinned_cf(self.cf_masters_dedup, fingerprint)?
+                .is_none()
+            {
+                self.inner.put_cf(self.cf_masters_dedup, fingerprint, key)?;
+            }
+            games += 1;
+            iter.next();
+        }
+        iter.status().map(|_| games)
+    }
+
+    /// Builds the search index from all games.
+    pub fn backfill_search(&self) -> Result<u64, rocksdb::Error> {
+        let mut iter = self.inner.raw_iterator_cf(self.cf_masters_game);
+        iter.seek_to_first();
+
+        let mut batch = WriteBatch::default();
+        let mut games = 0;
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            let id = GameId::read(&mut &key[..]);
+            let game: MastersGame =
+                serde_json::from_slice(value).expect("deserialize masters game");
+            for search_key in MastersSearchKey::all(id, &game) {
+                batch.put_cf(self.cf_masters_search, search_key, []);
+            }
+            games += 1;
+            if batch.len() >= 10_000 {
+                self.inner.write(mem::take(&mut batch))?;
+            }
+            iter.next();
+        }
+        iter.status()?;
+        self.inner.write(batch).map(|_| games)
+    }
+
+    /// Rebuilds the player index from all games, with the entries computed
+    /// by `entries`.
+    pub fn backfill_players<F>(&self, mut entries: F) -> Result<u64, rocksdb::Error>
+    where
+        F: FnMut(GameId, &MastersGame) -> Vec<(CheckedKey, PlayerEntry)>,
+    {
+        self.rebuild(&[self.cf_masters_player], |id, game, batch| {
+            for (key, entry) in entries(id, game) {
+                batch.merge_player(key, &entry);
+            }
+        })
+    }
+
+    /// Rebuilds the event index and the explorer by event from all games,
+    /// with the entries computed by `entries`.
+    pub fn backfill_events<F>(&self, mut entries: F) -> Result<u64, rocksdb::Error>
+    where
+        F: FnMut(GameId, &MastersGame) -> Vec<(CheckedKey, MastersEntry)>,
+    {
+        self.rebuild(
+            &[self.cf_masters_event, self.cf_masters_by_event],
+            |id, game, batch| {
+                batch.merge_event(MastersEventId::of(game), &MastersEvent::new_single(game));
+                for (key, entry) in entries(id, game) {
+                    batch.merge_by_event(key, entry);
+                }
+            },
+        )
+    }
+
+    /// Clears the given column families and refills them from all games.
+    /// Starts from scratch, because merged records can not be written twice.
+    fn rebuild<F>(&self, cfs: &[&ColumnFamily], mut f: F) -> Result<u64, rocksdb::Error>
+    where
+        F: FnMut(GameId, &MastersGame, &mut MastersBatch<'_>),
+    {
+        for cf in cfs {
+            self.inner
+                .delete_range_cf(*cf, &[][..], &[0xff; CheckedKey::SIZE + 1][..])?;
+        }
+
+        let mut iter = self.inner.raw_iterator_cf(self.cf_masters_game);
+        iter.seek_to_first();
+
+        let mut batch = self.batch();
+        let mut games = 0;
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            let id = GameId::read(&mut &key[..]);
+            let game: MastersGame =
+                serde_json::from_slice(value).expect("deserialize masters game");
+            f(id, &game, &mut batch);
+            games += 1;
+            if batch.batch.len() >= 10_000 {
+                mem::replace(&mut batch, self.batch()).commit()?;
+            }
+            iter.next();
+        }
+        iter.status()?;
+        batch.commit().map(|_| games)
+    }
+
+    /// Finds games matching the query, most recent first. Returns one page
+    /// of games, whether there are more, and whether the search gave up
+    /// before finding all matches (see `search_each()`).
+    pub fn search(
+        &self,
+        query: &MastersSearchQuery,
+    ) -> Result<(Vec<(GameId, MastersGame)>, bool, bool), rocksdb::Error> {
+        let max_per_page = query.max_per_page();
+        let skip = query.page.saturating_sub(1).saturating_mul(max_per_page);
+
+        let mut page = Vec::with_capacity(max_per_page);
+        let mut more = false;
+        let mut matches = 0;
+        let truncated = self.search_each(query, |id, game| {
+            if matches >= skip {
+                if page.len() == max_per_page {
+                    more = true;
+                    return false;
+                }
+                page.push((id, game));
+            }
+            matches += 1;
+            true
+        })?;
+        Ok((page, more, truncated))
+    }
+
+    /// Calls `f` with each game matching the query, most recent first,
+    /// ignoring pagination. Stops early when `f` returns `false`, or after
+    /// visiting `MastersSearchQuery::MAX_SCANNED` index keys or games
+    /// (`MastersSearchQuery::MAX_SCANNED_WITHOUT_TERMS` games for queries
+    /// without search terms), so that unselective queries may miss older
+    /// games. Returns whether it gave up like this, with games possibly
+    /// left unvisited.
+    pub fn search_each<F>(
+        &self,
+        query: &MastersSearchQuery,
+        mut f: F,
+    ) -> Result<bool, rocksdb::Error>
+    where
+        F: FnMut(GameId, MastersGame) -> bool,
+    {
+        let since = u16::from(query.since);
+        let mut iter = self.inner.raw_iterator_cf(self.cf_masters_search);
+
+        match query.most_selective_token() {
+            Some((field, token)) => {
+                // Collect all games with a matching token, then sort by date.
+                let prefix = MastersSearchKey::token_prefix(field, &token);
+                let mut candidates = Vec::new();
+                let mut scanned = 0;
+                let mut truncated = false;
+                iter.seek(&prefix);
+                while let Some(key) = iter.key() {
+                    if !key.starts_with(&prefix) {
+                        break;
+                    }
+                    if scanned >= MastersSearchQuery::MAX_SCANNED {
+                        truncated = true;
+                        break;
+                    }
+                    scanned += 1;
+                    if let Some(suffix) = MastersSearchKey::parse_suffix(key) {
+                        if since <= suffix.year() && suffix.year() <= u16::from(query.until) {
+                            candidates.push((suffix.date, suffix.id.to_bytes(), suffix.id));
+                        }
+                    }
+                    iter.next();
+                }
+                iter.status()?;
+
+                candidates.sort_unstable_by(|a, b| (b.0, b.1).cmp(&(a.0, a.1)));
+                candidates.dedup_by_key(|(_, id_bytes, _)| *id_bytes);
+                self.visit_matches(
+                    query,
+                    candidates.into_iter().map(|(_, _, id)| id),
+                    MastersSearchQuery::MAX_SCANNED,
+                    truncated,
+                    &mut f,
+                )
+            }
+            None => {
+                // Walk the date index backwards.
+                iter.seek_for_prev(MastersSearchKey::date_upper_bound(query.until));
+                let ids = std::iter::from_fn(|| {
+                    let key = iter
+                        .key()
+                        .filter(|key| MastersSearchKey::is_date_key(key))?;
+                    let suffix = MastersSearchKey::parse_suffix(key)?;
+                    if suffix.year() < since {
+                        return None;
+                    }
+                    iter.prev();
+                    Some(suffix.id)
+                });
+                let truncated = self.visit_matches(
+                    query,
+                    ids,
+                    MastersSearchQuery::MAX_SCANNED_WITHOUT_TERMS,
+                    false,
+                    &mut f,
+                )?;
+                iter.status()?;
+                Ok(truncated)
+            }
+        }
+    }
+
+    /// Reads and filters at most `max_scanned` of the games. Returns
+    /// whether games are left unvisited, either beyond `max_scanned` or
+    /// because `ids` is already `incomplete`, unless `f` stopped early.
+    fn visit_matches<I, F>(
+        &self,
+        query: &MastersSearchQuery,
+        ids: I,
+        max_scanned: usize,
+        incomplete: bool,
+        f: &mut F,
+    ) -> Result<bool, rocksdb::Error>
+    where
+        I: IntoIterator<Item = GameId>,
+        F: FnMut(GameId, MastersGame) -> bool,
+    {
+        let mut ids = ids.into_iter();
+        for id in ids.by_ref().take(max_scanned) {
+            if let Some(game) = self.game(id)?.filter(|game| query.matches(game)) {
+                if !f(id, game) {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(incomplete || ids.next().is_some())
     }
 
     pub fn read(
@@ -261,6 +849,104 @@ impl MastersDatabase<'_> {
         iter.status().map(|_| entry)
     }
 
+    /// Reads the entries of a masters player, see
+    /// `KeyBuilder::masters_player()`. Records of other positions with
+    /// colliding keys are detected and dropped.
+    pub fn read_player(
+        &self,
+        key: &KeyPrefix,
+        check: &KeyCheck,
+        since: Year,
+        until: Year,
+    ) -> Result<PlayerEntry, rocksdb::Error> {
+        let mut entry = PlayerEntry::default();
+
+        let mut opt = ReadOptions::default();
+        opt.set_prefix_same_as_start(true);
+        opt.set_iterate_lower_bound(key.with_year(since).into_bytes());
+        opt.set_iterate_upper_bound(key.with_year(until.add_years_saturating(1)).into_bytes());
+
+        let mut iter = self.inner.raw_iterator_cf_opt(self.cf_masters_player, opt);
+        iter.seek_to_first();
+
+        while let (Some(record_key), Some(mut value)) = (iter.key(), iter.value()) {
+            if check.matches(record_key) {
+                entry.extend_from_reader(&mut value);
+            } else {
+                log::warn!("dropping masters player record with colliding key");
+            }
+            iter.next();
+        }
+
+        iter.status().map(|_| entry)
+    }
+
+    pub fn event(&self, id: MastersEventId) -> Result<Option<MastersEvent>, rocksdb::Error> {
+        Ok(self
+            .inner
+            .get_pinned_cf(self.cf_masters_event, id.to_bytes())?
+            .map(|buf| MastersEvent::read(&mut buf.as_ref()).expect("read masters event")))
+    }
+
+    /// Lists all events, most recent first. Scans the entire column family,
+    /// so callers should cache the result.
+    pub fn events(&self) -> Result<Vec<(MastersEventId, MastersEvent)>, rocksdb::Error> {
+        let mut events = Vec::new();
+        let mut iter = self.inner.raw_iterator_cf(self.cf_masters_event);
+        iter.seek_to_first();
+        while let (Some(key), Some(mut value)) = (iter.key(), iter.value()) {
+            let event = MastersEvent::read(&mut value).expect("read masters event");
+            events.push((MastersEventId::read(&mut &key[..]), event));
+            iter.next();
+        }
+        iter.status()?;
+
+        events.sort_by(|(_, a), (_, b)| {
+            b.last
+                .to_sort_bytes()
+                .cmp(&a.last.to_sort_bytes())
+                .then_with(|| a.event.cmp(&b.event))
+        });
+
+        Ok(events)
+    }
+
+    /// Reads the entries of a position, restricted to the given events, see
+    /// `KeyBuilder::masters_event()`. Records of other positions with
+    /// colliding keys are detected and dropped.
+    pub fn read_by_event(
+        &self,
+        keys: &[(KeyPrefix, KeyCheck)],
+        since: Year,
+        until: Year,
+    ) -> Result<MastersEntry, rocksdb::Error> {
+        let mut entry = MastersEntry::default();
+
+        for (key, check) in keys {
+            let mut opt = ReadOptions::default();
+            opt.set_prefix_same_as_start(true);
+            opt.set_iterate_lower_bound(key.with_year(since).into_bytes());
+            opt.set_iterate_upper_bound(key.with_year(until.add_years_saturating(1)).into_bytes());
+
+            let mut iter = self
+                .inner
+                .raw_iterator_cf_opt(self.cf_masters_by_event, opt);
+            iter.seek_to_first();
+
+            while let (Some(record_key), Some(mut value)) = (iter.key(), iter.value()) {
+                if check.matches(record_key) {
+                    entry.extend_from_reader(&mut value);
+                } else {
+                    log::warn!("dropping masters event record with colliding key");
+                }
+                iter.next();
+            }
+            iter.status()?;
+        }
+
+        Ok(entry)
+    }
+
     pub fn batch(&self) -> MastersBatch<'_> {
         MastersBatch {
             db: self,
@@ -282,12 +968,38 @@ impl MastersBatch<'_> {
             .merge_cf(self.db.cf_masters, key.into_bytes(), buf);
     }
 
+    pub fn merge_player(&mut self, key: CheckedKey, entry: &PlayerEntry) {
+        let mut buf = Vec::with_capacity(PlayerEntry::SIZE_HINT);
+        entry.write(&mut buf);
+        self.batch
+            .merge_cf(self.db.cf_masters_player, key.into_bytes(), buf);
+    }
+
+    pub fn merge_event(&mut self, id: MastersEventId, event: &MastersEvent) {
+        let mut buf = Vec::with_capacity(MastersEvent::SIZE_HINT);
+        event.write(&mut buf);
+        self.batch
+            .merge_cf(self.db.cf_masters_event, id.to_bytes(), buf);
+    }
+
+    pub fn merge_by_event(&mut self, key: CheckedKey, entry: MastersEntry) {
+        let mut buf = Vec::with_capacity(MastersEntry::SIZE_HINT);
+        entry.write(&mut buf);
+        self.batch
+            .merge_cf(self.db.cf_masters_by_event, key.into_bytes(), buf);
+    }
+
     pub fn put_game(&mut self, id: GameId, game: &MastersGame) {
         self.batch.put_cf(
             self.db.cf_masters_game,
             id.to_bytes(),
             serde_json::to_vec(game).expect("serialize masters game"),
         );
+        self.batch
+            .put_cf(self.db.cf_masters_dedup, game.fingerprint(), id.to_bytes());
+        for key in MastersSearchKey::all(id, game) {
+            self.batch.put_cf(self.db.cf_masters_search, key, []);
+        }
     }
 
     pub fn commit(self) -> Result<(), rocksdb::Error> {
@@ -298,7 +1010,9 @@ impl MastersBatch<'_> {
 pub struct LichessDatabase<'a> {
     inner: &'a DB,
     cf_lichess: &'a ColumnFamily,
+    cf_lichess_matchup: &'a ColumnFamily,
     cf_lichess_game: &'a ColumnFamily,
+    cf_lichess_game_moves: &'a ColumnFamily,
 
     cf_player: &'a ColumnFamily,
     cf_player_status: &'a ColumnFamily,
@@ -307,11 +1021,22 @@ pub struct LichessDatabase<'a> {
 impl LichessDatabase<'_> {
     pub fn compact(&self) {
         compact_column(self.inner, self.cf_lichess);
+        compact_column(self.inner, self.cf_lichess_matchup);
         compact_column(self.inner, self.cf_lichess_game);
+        compact_column(self.inner, self.cf_lichess_game_moves);
         compact_column(self.inner, self.cf_player);
         compact_column(self.inner, self.cf_player_status);
     }
 
+    pub fn flush(&self) -> Result<(), rocksdb::Error> {
+        self.inner.flush_cf(self.cf_lichess)?;
+        self.inner.flush_cf(self.cf_lichess_matchup)?;
+        self.inner.flush_cf(self.cf_lichess_game)?;
+        self.inner.flush_cf(self.cf_lichess_game_moves)?;
+        self.inner.flush_cf(self.cf_player)?;
+        self.inner.flush_cf(self.cf_player_status)
+    }
+
     pub fn game(&self, id: GameId) -> Result<Option<LichessGame>, rocksdb::Error> {
         Ok(self
             .inner
@@ -319,6 +1044,18 @@ impl LichessDatabase<'_> {
             .map(|buf| LichessGame::read(&mut buf.as_ref())))
     }
 
+    /// Full move list, only present if stored with `--store-lichess-moves`.
+    /// Corrupted records are reported in the inner result.
+    pub fn game_moves(
+        &self,
+        id: GameId,
+    ) -> Result<Option<io::Result<LichessGameMoves>>, rocksdb::Error> {
+        Ok(self
+            .inner
+            .get_pinned_cf(self.cf_lichess_game_moves, id.to_bytes())?
+            .map(|buf| LichessGameMoves::read(&mut buf.as_ref())))
+    }
+
     pub fn games<I: IntoIterator<Item = GameId>>(
         &self,
         ids: I,
@@ -367,6 +1104,38 @@ impl LichessDatabase<'_> {
         iter.status().map(|_| entry)
     }
 
+    /// Like `read_lichess()`, but from the aggregation by separate mover and
+    /// opponent rating groups.
+    pub fn read_lichess_matchup(
+        &self,
+        key: &KeyPrefix,
+        since: Option<Month>,
+        until: Option<Month>,
+    ) -> Result<LichessMatchupEntry, rocksdb::Error> {
+        let mut entry = LichessMatchupEntry::default();
+
+        let mut opt = ReadOptions::default();
+        opt.set_prefix_same_as_start(true);
+        opt.set_iterate_lower_bound(
+            key.with_month(since.unwrap_or_else(Month::min_value))
+                .into_bytes(),
+        );
+        opt.set_iterate_upper_bound(
+            key.with_month(until.map_or(Month::max_value(), |m| m.add_months_saturating(1)))
+                .into_bytes(),
+        );
+
+        let mut iter = self.inner.raw_iterator_cf_opt(self.cf_lichess_matchup, opt);
+        iter.seek_to_first();
+
+        while let Some(mut value) = iter.value() {
+            entry.extend_from_reader(&mut value);
+            iter.next();
+        }
+
+        iter.status().map(|_| entry)
+    }
+
     pub fn read_lichess_history(
         &self,
         key: &KeyPrefix,
@@ -424,9 +1193,13 @@ impl LichessDatabase<'_> {
         iter.status().map(|_| history)
     }
 
+    /// Reads the entries of a player under checked keys matching `check`, or
+    /// under legacy keys if `check` is `None`. Records of other positions
+    /// with colliding keys are detected and dropped.
     pub fn read_player(
         &self,
         key: &KeyPrefix,
+        check: Option<&KeyCheck>,
         since: Month,
         until: Month,
     ) -> Result<PlayerEntry, rocksdb::Error> {
@@ -440,8 +1213,15 @@ impl LichessDatabase<'_> {
         let mut iter = self.inner.raw_iterator_cf_opt(self.cf_player, opt);
         iter.seek_to_first();
 
-        while let Some(mut value) = iter.value() {
-            entry.extend_from_reader(&mut value);
+        while let (Some(record_key), Some(mut value)) = (iter.key(), iter.value()) {
+            match check {
+                Some(check) if check.matches(record_key) => entry.extend_from_reader(&mut value),
+                Some(_) if record_key.len() == CheckedKey::SIZE => {
+                    log::warn!("dropping player record with colliding key");
+                }
+                None if record_key.len() == Key::SIZE => entry.extend_from_reader(&mut value),
+                _ => (),
+            }
             iter.next();
         }
 
@@ -472,6 +1252,106 @@ impl LichessDatabase<'_> {
             batch: WriteBatch::default(),
         }
     }
+
+    /// Writes entries, matchup entries and games as merge operands, and move
+    /// lists as values, into new SST files in `dir`, and ingests them. All
+    /// iterators must be sorted by the byte representation of their keys,
+    /// without duplicates. Files are named after `chunk` and moved into the
+    /// database.
+    pub fn ingest_sorted<E, M, G, V>(
+        &self,
+        dir: &Path,
+        chunk: usize,
+        entries: E,
+        matchup_entries: M,
+        games: G,
+        moves: V,
+    ) -> Result<(), rocksdb::Error>
+    where
+        E: IntoIterator<Item = (Key, LichessEntry)>,
+        M: IntoIterator<Item = (Key, LichessMatchupEntry)>,
+        G: IntoIterator<Item = (GameId, LichessGame)>,
+        V: IntoIterator<Item = (GameId, LichessGameMoves)>,
+    {
+        // Block cache is not used for writing.
+        let cache = Cache::new_lru_cache(8 * 1024 * 1024)?;
+
+        let mut buf = Vec::new();
+        let mut files = Vec::with_capacity(4);
+
+        let mut entries = entries.into_iter().peekable();
+        if entries.peek().is_some() {
+            let opts = lichess_column(&cache).options(MergeMode::Combine);
+            let path = dir.join(format!("lichess-{chunk}.sst"));
+            let mut writer = SstFileWriter::create(&opts);
+            writer.open(&path)?;
+            for (key, entry) in entries {
+                buf.clear();
+                entry.write(&mut buf);
+                writer.merge(key.into_bytes(), &buf)?;
+            }
+            writer.finish()?;
+            files.push((self.cf_lichess, path));
+        }
+
+        let mut matchup_entries = matchup_entries.into_iter().peekable();
+        if matchup_entries.peek().is_some() {
+            let opts = lichess_matchup_column(&cache).options(MergeMode::Combine);
+            let path = dir.join(format!("lichess_matchup-{chunk}.sst"));
+            let mut writer = SstFileWriter::create(&opts);
+            writer.open(&path)?;
+            for (key, entry) in matchup_entries {
+                buf.clear();
+                entry.write(&mut buf);
+                writer.merge(key.into_bytes(), &buf)?;
+            }
+            writer.finish()?;
+            files.push((self.cf_lichess_matchup, path));
+        }
+
+        let mut moves = moves.into_iter().peekable();
+        if moves.peek().is_some() {
+            let opts = lichess_game_moves_column(&cache).options(MergeMode::Combine);
+            let path = dir.join(format!("lichess_game_moves-{chunk}.sst"));
+            let mut writer = SstFileWriter::create(&opts);
+            writer.open(&path)?;
+            for (id, game_moves) in moves {
+                buf.clear();
+                game_moves.write(&mut buf);
+                writer.put(id.to_bytes(), &buf)?;
+            }
+            writer.finish()?;
+            files.push((self.cf_lichess_game_moves, path));
+        }
+
+        let mut games = games.into_iter().peekable();
+        if games.peek().is_some() {
+            let opts = lichess_game_column(&cache).options(MergeMode::Combine);
+            let path = dir.join(format!("lichess_game-{chunk}.sst"));
+            let mut writer = SstFileWriter::create(&opts);
+            writer.open(&path)?;
+            for (id, game) in games {
+                buf.clear();
+                game.write(&mut buf);
+                writer.merge(id.to_bytes(), &buf)?;
+            }
+            writer.finish()?;
+            files.push((self.cf_lichess_game, path));
+        }
+
+        // RocksDB can ingest into multiple column families atomically, but
+        // the bindings only expose ingestion into a single one. Ingest only
+        // after all files have been written, and game infos last, so that
+        // games are not considered imported before their entries are.
+        let mut ingest_opts = IngestExternalFileOptions::default();
+        ingest_opts.set_move_files(true);
+        for (cf, path) in files {
+            self.inner
+                .ingest_external_file_cf_opts(cf, &ingest_opts, vec![path])?;
+        }
+
+        Ok(())
+    }
 }
 
 pub struct LichessBatch<'a> {
@@ -487,6 +1367,13 @@ impl LichessBatch<'_> {
             .merge_cf(self.inner.cf_lichess, key.into_bytes(), buf);
     }
 
+    pub fn merge_matchup(&mut self, key: Key, entry: LichessMatchupEntry) {
+        let mut buf = Vec::with_capacity(LichessMatchupEntry::SIZE_HINT);
+        entry.write(&mut buf);
+        self.batch
+            .merge_cf(self.inner.cf_lichess_matchup, key.into_bytes(), buf);
+    }
+
     pub fn merge_game(&mut self, id: GameId, info: LichessGame) {
         let mut buf = Vec::with_capacity(LichessGame::SIZE_HINT);
         info.write(&mut buf);
@@ -494,7 +1381,25 @@ impl LichessBatch<'_> {
             .merge_cf(self.inner.cf_lichess_game, id.to_bytes(), buf);
     }
 
-    pub fn merge_player(&mut self, key: Key, entry: PlayerEntry) {
+    pub fn put_game_moves(&mut self, id: GameId, moves: &LichessGameMoves) {
+        let mut buf = Vec::with_capacity(1 + 2 * moves.moves.len());
+        moves.write(&mut buf);
+        self.batch
+            .put_cf(self.inner.cf_lichess_game_moves, id.to_bytes(), buf);
+    }
+
+    pub fn merge_player(&mut self, key: Key, entry: &PlayerEntry) {
+        let mut buf = Vec::with_capacity(PlayerEntry::SIZE_HINT);
+        entry.write(&mut buf);
+        self.batch
+            .merge_cf(self.inner.cf_player, key.into_bytes(), buf);
+    }
+
+    pub fn delete_player(&mut self, key: Key) {
+        self.batch.delete_cf(self.inner.cf_player, key.into_bytes());
+    }
+
+    pub fn merge_player_checked(&mut self, key: CheckedKey, entry: &PlayerEntry) {
         let mut buf = Vec::with_capacity(PlayerEntry::SIZE_HINT);
         entry.write(&mut buf);
         self.batch
@@ -506,6 +1411,34 @@ impl LichessBatch<'_> {
     }
 }
 
+thread_local! {
+    static MERGE_ERROR: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Takes the error of the latest merge on this thread that failed to decode
+/// its operands. Only recorded for databases opened with
+/// `DbOpt::for_check()`, where the failed merge yields an empty value.
+pub fn take_merge_error() -> Option<String> {
+    MERGE_ERROR.with(|err| err.borrow_mut().take())
+}
+
+/// Calls `merge_fn`, but catches panics on corrupt operands, which must not
+/// unwind into RocksDB.
+fn checked_merge(
+    merge_fn: MergeFn,
+    key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    match panic::catch_unwind(AssertUnwindSafe(|| merge_fn(key, existing, operands))) {
+        Ok(merged) => merged,
+        Err(err) => {
+            MERGE_ERROR.with(|cell| *cell.borrow_mut() = Some(panic_message(&*err)));
+            Some(Vec::new())
+        }
+    }
+}
+
 fn lichess_merge(
     _key: &[u8],
     existing: Option<&[u8]>,
@@ -520,12 +1453,27 @@ fn lichess_merge(
     Some(buf)
 }
 
+fn lichess_matchup_merge(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    let mut entry = LichessMatchupEntry::default();
+    for mut op in existing.into_iter().chain(operands.into_iter()) {
+        entry.extend_from_reader(&mut op);
+    }
+    let mut buf = Vec::new();
+    entry.write(&mut buf);
+    Some(buf)
+}
+
 fn lichess_game_merge(
     _key: &[u8],
     existing: Option<&[u8]>,
     operands: &MergeOperands,
 ) -> Option<Vec<u8>> {
-    // Take latest game info, but merge index status.
+    // Take latest game info, but merge index status, and keep metadata that
+    // the latest info may lack.
     let mut info: Option<LichessGame> = None;
     let mut size_hint = 0;
     for mut op in existing.into_iter().chain(operands.into_iter()) {
@@ -535,6 +1483,11 @@ fn lichess_game_merge(
             new_info.indexed_player.white |= old_info.indexed_player.white;
             new_info.indexed_player.black |= old_info.indexed_player.black;
             new_info.indexed_lichess |= old_info.indexed_lichess;
+            new_info.hardened_player.white |= old_info.hardened_player.white;
+            new_info.hardened_player.black |= old_info.hardened_player.black;
+            new_info.clock = new_info.clock.or(old_info.clock);
+            new_info.termination = new_info.termination.or(old_info.termination);
+            new_info.plies = new_info.plies.or(old_info.plies);
         }
         info = Some(new_info);
     }
@@ -569,6 +1522,24 @@ fn masters_merge(
     Some(buf)
 }
 
+fn masters_event_merge(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    let mut event: Option<MastersEvent> = None;
+    for mut op in existing.into_iter().chain(operands.into_iter()) {
+        let other = MastersEvent::read(&mut op).expect("read masters event");
+        match event {
+            Some(ref mut event) => event.merge(other),
+            None => event = Some(other),
+        }
+    }
+    let mut buf = Vec::new();
+    event?.write(&mut buf);
+    Some(buf)
+}
+
 fn compact_column(db: &DB, cf: &ColumnFamily) {
     db.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
 }
@@ -0,0 +1,769 @@
+//! Portable dump format, independent of RocksDB versions and options, and of
+//! the on-disk encoding of records.
+//!
+//! A dump is a zstd compressed stream of JSON objects, one per line, each
+//! with a `type`:
+//!
+//! * `{"type": "header", "version": 1}` first. See `DUMP_VERSION`.
+//! * One line per record, with the name of its column family as `type`.
+//! * `{"type": "end", "records": 123}` last, with the number of records, so
+//!   that truncated dumps are detected.
+//!
+//! Records consist of the key and the decoded entry:
+//!
+//! * `masters`, `lichess`, `lichess_matchup`, `player`: `key` (hex) and
+//!   `entry`, a list of groups by move, speed and rating group or mode, with
+//!   their `stats` and `games`. Position keys are hashes (see `KeyBuilder`),
+//!   and can not be decoded further.
+//! * `masters_game`: `id` and `game`, as accepted by `/import/masters`.
+//! * `lichess_game`: `id` and `game`, the game info of a Lichess game.
+//! * `lichess_game_moves`: `id` and `moves`, with `variant`, `fen`, and the
+//!   moves in UCI notation.
+//! * `player_status`: `user` (lowercase user id) and `status`.
+//!
+//! Games of an entry are listed as pairs of an index and the game id. The
+//! index orders games across all groups of the entry (Lichess and player
+//! entries), or is the sum of both ratings (masters entries).
+//!
+//! The masters indexes (`masters_dedup`, `masters_search`, `masters_player`,
+//! `masters_event`, and `masters_by_event`) are derived from `masters_game`.
+//! They are not dumped, but rebuilt when loading masters games.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read as _, Write as _},
+    path::Path,
+    time::SystemTime,
+};
+
+use rocksdb::WriteBatch;
+use serde::{Deserialize, Serialize};
+use serde_with::{
+    formats::SpaceSeparator, serde_as, DisplayFromStr, StringWithSeparator, TimestampSeconds,
+};
+use shakmaty::{fen::Fen, uci::Uci, variant::Variant, ByColor, Color, Outcome};
+use thiserror::Error;
+
+use crate::{
+    db::Database,
+    migration::{self, MASTERS_INDEX_FORMAT, UNVERSIONED_FORMAT},
+    model::{
+        ByTerminationGroup, CheckedKey, Clock, GameId, GamePlayer, Key, KeyScheme, LichessEntry,
+        LichessGame, LichessGameMoves, LichessGroup, LichessMatchupEntry, MastersEntry,
+        MastersGame, Mode, Month, PlayerEntry, PlayerStatus, RatingGroup, RawUci, Speed, Stats,
+        Termination,
+    },
+    util::ByColorDef,
+};
+
+const DUMP_VERSION: u32 = 1;
+
+const BATCH_SIZE: usize = 10_000;
+
+/// Far above the largest entries, but bounds the memory used for corrupt
+/// or hostile dumps.
+const MAX_LINE_LEN: usize = 64 * 1024 * 1024;
+
+const COLUMNS: &[&str] = &[
+    "masters",
+    "masters_game",
+    "lichess",
+    "lichess_matchup",
+    "lichess_game",
+    "lichess_game_moves",
+    "player",
+    "player_status",
+];
+
+const DERIVED_COLUMNS: &[&str] = &[
+    "masters_dedup",
+    "masters_search",
+    "masters_player",
+    "masters_event",
+    "masters_by_event",
+];
+
+#[derive(Error, Debug)]
+pub enum DumpError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    RocksDb(#[from] rocksdb::Error),
+    #[error("line {line}: {err}")]
+    Json { line: u64, err: serde_json::Error },
+    #[error("not an explorer dump")]
+    MissingHeader,
+    #[error("unsupported dump version {0}")]
+    UnsupportedDumpVersion(u32),
+    #[error("unknown column family {0}")]
+    UnknownColumn(String),
+    #[error("column family {0} is rebuilt from masters_game when loading, and not dumped")]
+    DerivedColumn(String),
+    #[error("line {0} is too long")]
+    LineTooLong(u64),
+    #[error("dump is truncated")]
+    Truncated,
+    #[error("dump ends with {found} records, but has {expected}")]
+    RecordCount { found: u64, expected: u64 },
+    #[error("line {line}: invalid key: {err}")]
+    InvalidKey { line: u64, err: String },
+    #[error("{column} record {key}: {err}")]
+    InvalidRecord {
+        column: &'static str,
+        key: String,
+        err: String,
+    },
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Line {
+    Header {
+        version: u32,
+    },
+    Masters {
+        key: String,
+        entry: Vec<MastersGroupDump>,
+    },
+    MastersGame {
+        #[serde_as(as = "DisplayFromStr")]
+        id: GameId,
+        game: MastersGame,
+    },
+    Lichess {
+        key: String,
+        entry: Vec<LichessGroupDump>,
+    },
+    LichessMatchup {
+        key: String,
+        entry: Vec<MatchupGroupDump>,
+    },
+    LichessGame {
+        #[serde_as(as = "DisplayFromStr")]
+        id: GameId,
+        game: LichessGameDump,
+    },
+    LichessGameMoves {
+        #[serde_as(as = "DisplayFromStr")]
+        id: GameId,
+        moves: LichessGameMovesDump,
+    },
+    Player {
+        key: String,
+        entry: Vec<PlayerGroupDump>,
+    },
+    PlayerStatus {
+        user: String,
+        status: PlayerStatusDump,
+    },
+    End {
+        records: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StatsDump {
+    white: u64,
+    draws: u64,
+    black: u64,
+    /// Sum of the ratings of the opponents (player entries) or of the
+    /// players to move (all other entries).
+    rating_sum: u64,
+}
+
+impl From<&Stats> for StatsDump {
+    fn from(stats: &Stats) -> StatsDump {
+        StatsDump {
+            white: stats.white(),
+            draws: stats.draws(),
+            black: stats.black(),
+            rating_sum: stats.rating_sum(),
+        }
+    }
+}
+
+impl From<&StatsDump> for Stats {
+    fn from(stats: &StatsDump) -> Stats {
+        Stats::new(stats.white, stats.draws, stats.black, stats.rating_sum)
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GroupDump {
+    stats: StatsDump,
+    /// Parts of `stats` with known termination.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    terminations: Option<ByTerminationGroup<StatsDump>>,
+    #[serde_as(as = "Vec<(_, DisplayFromStr)>")]
+    games: Vec<(u64, GameId)>,
+}
+
+impl From<&LichessGroup> for GroupDump {
+    fn from(group: &LichessGroup) -> GroupDump {
+        GroupDump {
+            stats: StatsDump::from(&group.stats),
+            terminations: group.terminations.as_ref().map(|t| ByTerminationGroup {
+                decisive: StatsDump::from(&t.decisive),
+                time: StatsDump::from(&t.time),
+                draw: StatsDump::from(&t.draw),
+                stalemate: StatsDump::from(&t.stalemate),
+            }),
+            games: group.games.iter().copied().collect(),
+        }
+    }
+}
+
+impl From<GroupDump> for LichessGroup {
+    fn from(group: GroupDump) -> LichessGroup {
+        LichessGroup {
+            stats: Stats::from(&group.stats),
+            games: group.games.into_iter().collect(),
+            terminations: group.terminations.map(|t| {
+                Box::new(ByTerminationGroup {
+                    decisive: Stats::from(&t.decisive),
+                    time: Stats::from(&t.time),
+                    draw: Stats::from(&t.draw),
+                    stalemate: Stats::from(&t.stalemate),
+                })
+            }),
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LichessGroupDump {
+    #[serde_as(as = "DisplayFromStr")]
+    uci: Uci,
+    speed: Speed,
+    rating_group: RatingGroup,
+    #[serde(flatten)]
+    group: GroupDump,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlayerGroupDump {
+    #[serde_as(as = "DisplayFromStr")]
+    uci: Uci,
+    speed: Speed,
+    mode: Mode,
+    #[serde(flatten)]
+    group: GroupDump,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MatchupGroupDump {
+    #[serde_as(as = "DisplayFromStr")]
+    uci: Uci,
+    speed: Speed,
+    mover: RatingGroup,
+    opponent: RatingGroup,
+    stats: StatsDump,
+    opponent_rating_sum: u64,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MastersGroupDump {
+    #[serde_as(as = "DisplayFromStr")]
+    uci: Uci,
+    stats: StatsDump,
+    #[serde_as(as = "Vec<(_, DisplayFromStr)>")]
+    games: Vec<(u16, GameId)>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LichessGameDump {
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    winner: Option<Color>,
+    speed: Speed,
+    mode: Mode,
+    #[serde(with = "ByColorDef")]
+    players: ByColor<GamePlayer>,
+    #[serde_as(as = "DisplayFromStr")]
+    month: Month,
+    #[serde(with = "ByColorDef")]
+    indexed_player: ByColor<bool>,
+    indexed_lichess: bool,
+    #[serde(with = "ByColorDef")]
+    hardened_player: ByColor<bool>,
+    clock: Option<Clock>,
+    termination: Option<Termination>,
+    plies: Option<u32>,
+}
+
+impl From<LichessGame> for LichessGameDump {
+    fn from(game: LichessGame) -> LichessGameDump {
+        LichessGameDump {
+            winner: game.outcome.winner(),
+            speed: game.speed,
+            mode: game.mode,
+            players: game.players,
+            month: game.month,
+            indexed_player: game.indexed_player,
+            indexed_lichess: game.indexed_lichess,
+            hardened_player: game.hardened_player,
+            clock: game.clock,
+            termination: game.termination,
+            plies: game.plies,
+        }
+    }
+}
+
+impl From<LichessGameDump> for LichessGame {
+    fn from(game: LichessGameDump) -> LichessGame {
+        LichessGame {
+            outcome: Outcome::from_winner(game.winner),
+            speed: game.speed,
+            mode: game.mode,
+            players: game.players,
+            month: game.month,
+            indexed_player: game.indexed_player,
+            indexed_lichess: game.indexed_lichess,
+            hardened_player: game.hardened_player,
+            clock: game.clock,
+            termination: game.termination,
+            plies: game.plies,
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LichessGameMovesDump {
+    #[serde_as(as = "DisplayFromStr")]
+    variant: Variant,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    fen: Option<Fen>,
+    /// As in `Uci::from_chess960()`.
+    #[serde_as(as = "StringWithSeparator<SpaceSeparator, Uci>")]
+    moves: Vec<Uci>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlayerStatusDump {
+    latest_created_at: u64,
+    revisit_ongoing_created_at: Option<u64>,
+    #[serde_as(as = "TimestampSeconds<i64>")]
+    indexed_at: SystemTime,
+    #[serde_as(as = "TimestampSeconds<i64>")]
+    revisited_at: SystemTime,
+    key_scheme: KeyScheme,
+}
+
+impl From<PlayerStatus> for PlayerStatusDump {
+    fn from(status: PlayerStatus) -> PlayerStatusDump {
+        PlayerStatusDump {
+            latest_created_at: status.latest_created_at,
+            revisit_ongoing_created_at: status.revisit_ongoing_created_at,
+            indexed_at: status.indexed_at,
+            revisited_at: status.revisited_at,
+            key_scheme: status.key_scheme,
+        }
+    }
+}
+
+impl From<PlayerStatusDump> for PlayerStatus {
+    fn from(status: PlayerStatusDump) -> PlayerStatus {
+        PlayerStatus {
+            latest_created_at: status.latest_created_at,
+            revisit_ongoing_created_at: status.revisit_ongoing_created_at,
+            indexed_at: status.indexed_at,
+            revisited_at: status.revisited_at,
+            key_scheme: status.key_scheme,
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Position keys, with or without `KeyCheck`.
+fn position_key(hex: &str) -> Result<Vec<u8>, String> {
+    if hex.len() != 2 * Key::SIZE && hex.len() != 2 * CheckedKey::SIZE {
+        return Err(format!(
+            "expected {} or {} bytes",
+            Key::SIZE,
+            CheckedKey::SIZE
+        ));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| "not hex".to_owned())
+        })
+        .collect()
+}
+
+/// Decodes a record from the database, with the panicking model readers.
+fn decode_record(column: &'static str, key: &[u8], value: &[u8]) -> Result<Line, DumpError> {
+    let invalid = |err: String| DumpError::InvalidRecord {
+        column,
+        key: to_hex(key),
+        err,
+    };
+    let game_id = || {
+        if key.len() == GameId::SIZE {
+            Ok(GameId::read(&mut &key[..]))
+        } else {
+            Err(invalid("invalid game id".to_owned()))
+        }
+    };
+
+    Ok(match column {
+        "masters" => {
+            let mut entry = MastersEntry::default();
+            entry.extend_from_reader(&mut &value[..]);
+            Line::Masters {
+                key: to_hex(key),
+                entry: entry
+                    .groups()
+                    .map(|(uci, stats, games)| MastersGroupDump {
+                        uci: Uci::from(uci),
+                        stats: StatsDump::from(stats),
+                        games: games.to_vec(),
+                    })
+                    .collect(),
+            }
+        }
+        "masters_game" => Line::MastersGame {
+            id: game_id()?,
+            game: serde_json::from_slice(value).map_err(|err| invalid(err.to_string()))?,
+        },
+        "lichess" => {
+            let mut entry = LichessEntry::default();
+            entry.extend_from_reader(&mut &value[..]);
+            Line::Lichess {
+                key: to_hex(key),
+                entry: entry
+                    .groups()
+                    .map(|(uci, speed, rating_group, group)| LichessGroupDump {
+                        uci: Uci::from(uci),
+                        speed,
+                        rating_group,
+                        group: GroupDump::from(group),
+                    })
+                    .collect(),
+            }
+        }
+        "lichess_matchup" => {
+            let mut entry = LichessMatchupEntry::default();
+            entry.extend_from_reader(&mut &value[..]);
+            Line::LichessMatchup {
+                key: to_hex(key),
+                entry: entry
+                    .groups()
+                    .map(
+                        |(uci, speed, mover, opponent, stats, opponent_rating_sum)| {
+                            MatchupGroupDump {
+                                uci: Uci::from(uci),
+                                speed,
+                                mover,
+                                opponent,
+                                stats: StatsDump::from(stats),
+                                opponent_rating_sum,
+                            }
+                        },
+                    )
+                    .collect(),
+            }
+        }
+        "lichess_game" => Line::LichessGame {
+            id: game_id()?,
+            game: LichessGameDump::from(LichessGame::read(&mut &value[..])),
+        },
+        "lichess_game_moves" => {
+            let moves =
+                LichessGameMoves::read(&mut &value[..]).map_err(|err| invalid(err.to_string()))?;
+            Line::LichessGameMoves {
+                id: game_id()?,
+                moves: LichessGameMovesDump {
+                    variant: moves.variant,
+                    fen: moves.fen,
+                    moves: moves.moves.into_iter().map(Uci::from).collect(),
+                },
+            }
+        }
+        "player" => {
+            let mut entry = PlayerEntry::default();
+            entry.extend_from_reader(&mut &value[..]);
+            Line::Player {
+                key: to_hex(key),
+                entry: entry
+                    .groups()
+                    .map(|(uci, speed, mode, group)| PlayerGroupDump {
+                        uci: Uci::from(uci),
+                        speed,
+                        mode,
+                        group: GroupDump::from(group),
+                    })
+                    .collect(),
+            }
+        }
+        "player_status" => Line::PlayerStatus {
+            user: String::from_utf8(key.to_vec()).map_err(|err| invalid(err.to_string()))?,
+            status: PlayerStatusDump::from(PlayerStatus::read(&mut &value[..])),
+        },
+        _ => return Err(DumpError::UnknownColumn(column.to_owned())),
+    })
+}
+
+fn column_by_name(name: &str) -> Result<&'static str, DumpError> {
+    if let Some(column) = COLUMNS.iter().find(|column| **column == name) {
+        Ok(column)
+    } else if DERIVED_COLUMNS.contains(&name) {
+        Err(DumpError::DerivedColumn(name.to_owned()))
+    } else {
+        Err(DumpError::UnknownColumn(name.to_owned()))
+    }
+}
+
+/// Writes a dump of the given column families (all, if empty) to `path`.
+///
+/// Records are decoded with the model readers, which panic on corrupt
+/// records. Use `check --repair` first, if in doubt.
+pub fn dump(db: &Database, path: &Path, columns: &[String]) -> Result<(), DumpError> {
+    let columns = if columns.is_empty() {
+        COLUMNS.to_vec()
+    } else {
+        columns
+            .iter()
+            .map(|name| column_by_name(name))
+            .collect::<Result<Vec<_>, _>>()?
+    };
+
+    let mut writer = BufWriter::new(zstd::Encoder::new(File::create(path)?, 0)?);
+    write_line(
+        &mut writer,
+        &Line::Header {
+            version: DUMP_VERSION,
+        },
+    )?;
+
+    let mut records = 0u64;
+    for column in columns {
+        log::info!("dumping {} ...", column);
+        let cf = db.inner.cf_handle(column).expect("cf");
+        let mut iter = db.inner.raw_iterator_cf(cf);
+        iter.seek_to_first();
+
+        let mut column_records = 0u64;
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            write_line(&mut writer, &decode_record(column, key, value)?)?;
+            column_records += 1;
+            iter.next();
+        }
+        iter.status()?;
+        log::info!("dumped {} records of {}", column_records, column);
+        records += column_records;
+    }
+
+    write_line(&mut writer, &Line::End { records })?;
+    writer
+        .into_inner()
+        .map_err(|err| err.into_error())?
+        .finish()?
+        .sync_all()?;
+    Ok(())
+}
+
+fn write_line<W: io::Write>(writer: &mut W, line: &Line) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, line)?;
+    writer.write_all(b"\n")
+}
+
+/// Loads a dump into the database, combining records with existing data
+/// through the merge operators. Records are re-encoded in the current
+/// on-disk format.
+///
+/// The masters indexes are rebuilt from all masters games if the dump
+/// contains any.
+pub fn load(db: &Database, path: &Path) -> Result<(), DumpError> {
+    let mut reader = BufReader::new(zstd::Decoder::new(File::open(path)?)?);
+    let mut line = String::new();
+    let mut line_no = 0;
+
+    match read_line(&mut reader, &mut line, &mut line_no)? {
+        Some(Line::Header { version }) if version == DUMP_VERSION => (),
+        Some(Line::Header { version }) => return Err(DumpError::UnsupportedDumpVersion(version)),
+        _ => return Err(DumpError::MissingHeader),
+    }
+
+    let mut batch = WriteBatch::default();
+    let mut records = 0u64;
+    let mut masters_games = 0u64;
+    let mut buf = Vec::new();
+    loop {
+        let record = match read_line(&mut reader, &mut line, &mut line_no)? {
+            Some(Line::End { records: expected }) if expected == records => break,
+            Some(Line::End { records: expected }) => {
+                return Err(DumpError::RecordCount {
+                    found: records,
+                    expected,
+                })
+            }
+            Some(Line::Header { .. }) => return Err(DumpError::MissingHeader),
+            Some(record) => record,
+            None => return Err(DumpError::Truncated),
+        };
+
+        let invalid_key = |err| DumpError::InvalidKey { line: line_no, err };
+        buf.clear();
+        let (column, key, merge) = match record {
+            Line::Masters { key, entry } => {
+                let mut decoded = MastersEntry::default();
+                for group in entry {
+                    decoded.add_group(
+                        RawUci::from(group.uci),
+                        &Stats::from(&group.stats),
+                        &group.games,
+                    );
+                }
+                decoded.write(&mut buf);
+                ("masters", position_key(&key).map_err(invalid_key)?, true)
+            }
+            Line::MastersGame { id, game } => {
+                masters_games += 1;
+                buf = serde_json::to_vec(&game).expect("serialize masters game");
+                ("masters_game", id.to_bytes().to_vec(), false)
+            }
+            Line::Lichess { key, entry } => {
+                let mut decoded = LichessEntry::default();
+                for group in entry {
+                    decoded.add_group(
+                        RawUci::from(group.uci),
+                        group.speed,
+                        group.rating_group,
+                        LichessGroup::from(group.group),
+                    );
+                }
+                decoded.write(&mut buf);
+                ("lichess", position_key(&key).map_err(invalid_key)?, true)
+            }
+            Line::LichessMatchup { key, entry } => {
+                let mut decoded = LichessMatchupEntry::default();
+                for group in entry {
+                    decoded.add_group(
+                        RawUci::from(group.uci),
+                        group.speed,
+                        group.mover,
+                        group.opponent,
+                        &Stats::from(&group.stats),
+                        group.opponent_rating_sum,
+                    );
+                }
+                decoded.write(&mut buf);
+                (
+                    "lichess_matchup",
+                    position_key(&key).map_err(invalid_key)?,
+                    true,
+                )
+            }
+            Line::LichessGame { id, game } => {
+                LichessGame::from(game).write(&mut buf);
+                ("lichess_game", id.to_bytes().to_vec(), true)
+            }
+            Line::LichessGameMoves { id, moves } => {
+                LichessGameMoves {
+                    variant: moves.variant,
+                    fen: moves.fen,
+                    moves: moves.moves.into_iter().map(RawUci::from).collect(),
+                }
+                .write(&mut buf);
+                ("lichess_game_moves", id.to_bytes().to_vec(), false)
+            }
+            Line::Player { key, entry } => {
+                let mut decoded = PlayerEntry::default();
+                for group in entry {
+                    decoded.add_group(
+                        RawUci::from(group.uci),
+                        group.speed,
+                        group.mode,
+                        LichessGroup::from(group.group),
+                    );
+                }
+                decoded.write(&mut buf);
+                ("player", position_key(&key).map_err(invalid_key)?, true)
+            }
+            Line::PlayerStatus { user, status } => {
+                PlayerStatus::from(status).write(&mut buf);
+                ("player_status", user.into_bytes(), false)
+            }
+            Line::Header { .. } | Line::End { .. } => unreachable!(),
+        };
+
+        let cf = db.inner.cf_handle(column).expect("cf");
+        if merge {
+            batch.merge_cf(cf, &key, &buf);
+        } else {
+            batch.put_cf(cf, &key, &buf);
+        }
+
+        records += 1;
+        if batch.len() >= BATCH_SIZE {
+            db.inner.write(std::mem::take(&mut batch))?;
+            if records % 1_000_000 == 0 {
+                log::info!("loaded {} records ...", records);
+            }
+        }
+    }
+    db.inner.write(batch)?;
+
+    log::info!("loaded {} records", records);
+
+    if masters_games > 0 {
+        log::info!("rebuilding masters indexes ...");
+        migration::rerun(db, UNVERSIONED_FORMAT, MASTERS_INDEX_FORMAT)?;
+    }
+
+    Ok(())
+}
+
+/// Reads the next line, if any. Lines are bounded by `MAX_LINE_LEN`, before
+/// allocating.
+fn read_line<R: BufRead>(
+    reader: &mut R,
+    line: &mut String,
+    line_no: &mut u64,
+) -> Result<Option<Line>, DumpError> {
+    line.clear();
+    *line_no += 1;
+    let len = reader
+        .by_ref()
+        .take(MAX_LINE_LEN as u64 + 1)
+        .read_line(line)?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(if len > MAX_LINE_LEN {
+            DumpError::LineTooLong(*line_no)
+        } else {
+            DumpError::Truncated
+        });
+    }
+    serde_json::from_str(line)
+        .map(Some)
+        .map_err(|err| DumpError::Json {
+            line: *line_no,
+            err,
+        })
+}
@@ -1,10 +1,12 @@
 use std::{
+    cmp::min,
     collections::HashMap,
     sync::{Arc, Mutex},
 };
 
+use clap::Parser;
 use nohash_hasher::IntMap;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_with::{
     formats::SpaceSeparator, serde_as, DefaultOnNull, DisplayFromStr, StringWithSeparator,
 };
@@ -20,49 +22,138 @@ use shakmaty::{
 use crate::{
     api::Error,
     db::Database,
+    migration::MASTERS_INDEX_FORMAT,
     model::{
-        GameId, GamePlayer, KeyBuilder, LaxDate, LichessEntry, LichessGame, MastersEntry,
-        MastersGameWithId, Mode, Speed, Year,
+        CheckedKey, Clock, GameId, GamePlayer, Key, KeyBuilder, KeyCheck, LaxDate, LichessEntry,
+        LichessGame, LichessGameMoves, LichessMatchupEntry, MastersEntry, MastersEvent,
+        MastersEventId, MastersGame, MastersGameWithId, Mode, PlayerEntry, RawUci, Speed,
+        Termination, Year,
     },
     util::{midpoint, ByColorDef},
 };
 
 const MAX_PLIES: usize = 50;
 
-#[derive(Clone)]
-pub struct MastersImporter {
-    db: Arc<Database>,
-    mutex: Arc<Mutex<()>>,
+/// Acceptance policy for masters imports.
+#[derive(Parser, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MastersPolicyOpt {
+    /// Minimum average rating of imported masters games.
+    #[arg(long = "masters-min-avg-rating", default_value = "2200")]
+    min_avg_rating: u16,
+    /// Minimum rating of each player of imported masters games.
+    #[arg(long = "masters-min-rating", default_value = "0")]
+    min_rating: u16,
+    /// Latest year of imported masters games. Defaults to the current year.
+    #[arg(long = "masters-max-year")]
+    max_year: Option<u16>,
+    /// Import only masters games with an event containing one of these
+    /// (case-insensitive). May be given multiple times.
+    #[arg(long = "masters-allow-event")]
+    allow_events: Vec<String>,
+    /// Reject masters games with an event containing one of these
+    /// (case-insensitive). May be given multiple times.
+    #[arg(long = "masters-deny-event")]
+    deny_events: Vec<String>,
+    /// Import only masters games with a site containing one of these
+    /// (case-insensitive). May be given multiple times.
+    #[arg(long = "masters-allow-site")]
+    allow_sites: Vec<String>,
+    /// Reject masters games with a site containing one of these
+    /// (case-insensitive). May be given multiple times.
+    #[arg(long = "masters-deny-site")]
+    deny_sites: Vec<String>,
 }
 
-impl MastersImporter {
-    pub fn new(db: Arc<Database>) -> MastersImporter {
-        MastersImporter {
-            db,
-            mutex: Arc::new(Mutex::new(())),
+impl MastersPolicyOpt {
+    fn max_year(&self) -> u16 {
+        self.max_year.unwrap_or_else(|| u16::from(Year::current()))
+    }
+
+    /// The policy as currently applied, with the default maximum year
+    /// resolved.
+    pub fn resolved(&self) -> MastersPolicyOpt {
+        MastersPolicyOpt {
+            max_year: Some(self.max_year()),
+            ..self.clone()
         }
     }
 
-    pub fn import(&self, body: MastersGameWithId) -> Result<(), Error> {
-        let avg_rating = midpoint(
-            body.game.players.white.rating,
-            body.game.players.black.rating,
-        );
-        if avg_rating < 2200 {
+    pub fn check(&self, body: &MastersGameWithId) -> Result<(), Error> {
+        let players = &body.game.players;
+
+        let avg_rating = midpoint(players.white.rating, players.black.rating);
+        if avg_rating < self.min_avg_rating {
             return Err(Error::RejectedRating {
                 id: body.id,
                 rating: avg_rating,
             });
         }
 
-        let year = body.game.date.year();
-        if Year::max_masters() < year {
+        let min_rating = min(players.white.rating, players.black.rating);
+        if min_rating < self.min_rating {
+            return Err(Error::RejectedPlayerRating {
+                id: body.id,
+                rating: min_rating,
+            });
+        }
+
+        if self.max_year() < u16::from(body.game.date.year()) {
             return Err(Error::RejectedDate {
                 id: body.id,
                 date: body.game.date,
             });
         }
 
+        if !accepts(&self.allow_events, &self.deny_events, &body.game.event) {
+            return Err(Error::RejectedEvent {
+                id: body.id,
+                event: body.game.event.clone(),
+            });
+        }
+
+        if !accepts(&self.allow_sites, &self.deny_sites, &body.game.site) {
+            return Err(Error::RejectedSite {
+                id: body.id,
+                site: body.game.site.clone(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+fn accepts(allow: &[String], deny: &[String], value: &str) -> bool {
+    let value = value.to_lowercase();
+    let matches = |patterns: &[String]| {
+        patterns
+            .iter()
+            .any(|pattern| value.contains(&pattern.to_lowercase()))
+    };
+    (allow.is_empty() || matches(allow)) && !matches(deny)
+}
+
+#[derive(Clone)]
+pub struct MastersImporter {
+    db: Arc<Database>,
+    mutex: Arc<Mutex<()>>,
+}
+
+impl MastersImporter {
+    pub fn new(db: Arc<Database>) -> MastersImporter {
+        MastersImporter {
+            db,
+            mutex: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Adds a game without checking the `MastersPolicyOpt`, rejecting only
+    /// duplicates.
+    pub fn insert(&self, body: MastersGameWithId) -> Result<(), Error> {
+        if self.db.format_version().expect("get format version") < MASTERS_INDEX_FORMAT {
+            return Err(Error::MigrationPending);
+        }
+
         let _guard = self.mutex.lock().expect("lock masters db");
         let masters_db = self.db.masters();
 
@@ -70,56 +161,135 @@ impl MastersImporter {
             .has_game(body.id)
             .expect("check for masters game")
         {
-            return Err(Error::DuplicateGame { id: body.id });
+            return Err(Error::DuplicateGame {
+                id: body.id,
+                existing: body.id,
+            });
         }
 
-        let mut without_loops: IntMap<Zobrist128, (Uci, Color)> =
-            HashMap::with_capacity_and_hasher(body.game.moves.len(), Default::default());
+        if let Some(existing) = masters_db
+            .duplicate_of(&body.game)
+            .expect("check for duplicate masters game")
+        {
+            return Err(Error::DuplicateGame {
+                id: body.id,
+                existing,
+            });
+        }
+
+        let prepared = MastersImporter::prepare(body.id, &body.game)?;
+
+        let mut batch = masters_db.batch();
+        batch.put_game(body.id, &body.game);
+        for (key, entry) in prepared.entries {
+            batch.merge(key, entry);
+        }
+        for (key, entry) in prepared.player_entries {
+            batch.merge_player(key, &entry);
+        }
+        batch.merge_event(
+            MastersEventId::of(&body.game),
+            &MastersEvent::new_single(&body.game),
+        );
+        for (key, entry) in prepared.event_entries {
+            batch.merge_by_event(key, entry);
+        }
+
+        batch.commit().expect("commit masters game");
+        Ok(())
+    }
+
+    /// Computes the explorer, player and event entries to be merged for a
+    /// game, without touching the database.
+    pub fn prepare(id: GameId, game: &MastersGame) -> Result<PreparedMastersGame, Error> {
+        let year = game.date.year();
+        let players = ByColor::new_with(|color| {
+            KeyBuilder::masters_player(&game.players.get(color).name, color)
+        });
+        let event = KeyBuilder::masters_event(MastersEventId::of(game));
+
+        let mut without_loops: IntMap<Zobrist128, (Uci, Color, ByColor<KeyCheck>, KeyCheck)> =
+            HashMap::with_capacity_and_hasher(game.moves.len(), Default::default());
         let mut pos = Chess::default();
-        let mut final_key = None;
-        for uci in &body.game.moves {
+        for uci in &game.moves {
             let key = pos.zobrist_hash(EnPassantMode::Legal);
-            final_key = Some(key);
             let m = uci.to_move(&pos)?;
-            without_loops.insert(key, (Uci::from_chess960(&m), pos.turn()));
+            let variant_pos = VariantPosition::Chess(pos.clone());
+            without_loops.insert(
+                key,
+                (
+                    Uci::from_chess960(&m),
+                    pos.turn(),
+                    ByColor::new_with(|color| players.get(color).check(&variant_pos)),
+                    event.check(&variant_pos),
+                ),
+            );
             pos.play_unchecked(&m);
         }
 
-        if let Some(final_key) = final_key {
-            if masters_db
-                .has(
-                    KeyBuilder::masters()
-                        .with_zobrist(Variant::Chess, final_key)
-                        .with_year(year),
-                )
-                .expect("check for masters entry")
-            {
-                return Err(Error::DuplicateGame { id: body.id });
+        let outcome = Outcome::from_winner(game.winner);
+        let mut entries = Vec::with_capacity(without_loops.len());
+        let mut player_entries = Vec::with_capacity(2 * without_loops.len());
+        let mut event_entries = Vec::with_capacity(without_loops.len());
+        for (key, (uci, turn, player_checks, event_check)) in without_loops {
+            for color in Color::ALL {
+                player_entries.push((
+                    players
+                        .get(color)
+                        .with_zobrist(Variant::Chess, key)
+                        .with_year(year)
+                        .with_check(player_checks.get(color)),
+                    PlayerEntry::new_single(
+                        uci.clone(),
+                        Speed::Classical,
+                        Mode::Rated,
+                        id,
+                        outcome,
+                        None,
+                        game.players.get(!color).rating,
+                    ),
+                ));
             }
-        }
-
-        let mut batch = masters_db.batch();
-        batch.put_game(body.id, &body.game);
-        for (key, (uci, turn)) in without_loops {
-            batch.merge(
+            let entry = || {
+                MastersEntry::new_single(
+                    uci.clone(),
+                    id,
+                    outcome,
+                    game.players.get(turn).rating,
+                    game.players.get(!turn).rating,
+                )
+            };
+            event_entries.push((
+                event
+                    .with_zobrist(Variant::Chess, key)
+                    .with_year(year)
+                    .with_check(&event_check),
+                entry(),
+            ));
+            entries.push((
                 KeyBuilder::masters()
                     .with_zobrist(Variant::Chess, key)
                     .with_year(year),
-                MastersEntry::new_single(
-                    uci,
-                    body.id,
-                    Outcome::from_winner(body.game.winner),
-                    body.game.players.get(turn).rating,
-                    body.game.players.get(!turn).rating,
-                ),
-            );
+                entry(),
+            ));
         }
 
-        batch.commit().expect("commit masters game");
-        Ok(())
+        Ok(PreparedMastersGame {
+            entries,
+            player_entries,
+            event_entries,
+        })
     }
 }
 
+/// Masters games have no speed or mode. Their player entries are recorded
+/// as rated classical games.
+pub struct PreparedMastersGame {
+    pub entries: Vec<(Key, MastersEntry)>,
+    pub player_entries: Vec<(CheckedKey, PlayerEntry)>,
+    pub event_entries: Vec<(CheckedKey, MastersEntry)>,
+}
+
 #[serde_as]
 #[derive(Deserialize)]
 pub struct LichessGameImport {
@@ -138,18 +308,24 @@ pub struct LichessGameImport {
     winner: Option<Color>,
     #[serde_as(as = "StringWithSeparator<SpaceSeparator, San>")]
     moves: Vec<San>,
+    #[serde(default)]
+    clock: Option<Clock>,
+    #[serde(default)]
+    termination: Option<Termination>,
 }
 
 #[derive(Clone)]
 pub struct LichessImporter {
     db: Arc<Database>,
+    store_moves: bool,
     mutex: Arc<Mutex<()>>,
 }
 
 impl LichessImporter {
-    pub fn new(db: Arc<Database>) -> LichessImporter {
+    pub fn new(db: Arc<Database>, store_moves: bool) -> LichessImporter {
         LichessImporter {
             db,
+            store_moves,
             mutex: Arc::new(Mutex::new(())),
         }
     }
@@ -162,6 +338,41 @@ impl LichessImporter {
     }
 
     fn import(&self, game: LichessGameImport) -> Result<(), Error> {
+        let prepared = LichessImporter::prepare(game, self.store_moves)?;
+
+        let lichess_db = self.db.lichess();
+        let mut batch = lichess_db.batch();
+        for (key, entry) in prepared.entries {
+            batch.merge_lichess(key, entry);
+        }
+        for (key, entry) in prepared.matchup_entries {
+            batch.merge_matchup(key, entry);
+        }
+        batch.merge_game(prepared.id, prepared.game);
+        if let Some(moves) = prepared.moves {
+            batch.put_game_moves(prepared.id, &moves);
+        }
+
+        let _guard = self.mutex.lock().expect("lock lichess db");
+        if lichess_db
+            .game(prepared.id)
+            .expect("get game info")
+            .map_or(false, |info| info.indexed_lichess)
+        {
+            log::debug!("lichess game {} already imported", prepared.id);
+        } else {
+            batch.commit().expect("commit lichess game");
+        }
+        Ok(())
+    }
+
+    /// Computes the entries and game info to be merged for a game, without
+    /// touching the database. With `store_moves`, also collects the full
+    /// move list.
+    pub fn prepare(
+        game: LichessGameImport,
+        store_moves: bool,
+    ) -> Result<PreparedLichessGame, Error> {
         let month = match game.date.month() {
             Some(month) => month,
             None => {
@@ -173,6 +384,13 @@ impl LichessImporter {
             }
         };
         let outcome = Outcome::from_winner(game.winner);
+        let plies = u32::try_from(game.moves.len()).ok();
+
+        let mut moves = store_moves.then(|| LichessGameMoves {
+            variant: game.variant,
+            fen: game.fen.clone(),
+            moves: Vec::with_capacity(game.moves.len()),
+        });
 
         let mut pos = match game.fen {
             Some(fen) => {
@@ -183,55 +401,95 @@ impl LichessImporter {
 
         let mut without_loops: IntMap<Zobrist128, (Uci, Color)> =
             HashMap::with_capacity_and_hasher(game.moves.len(), Default::default());
-        for san in game.moves.into_iter().take(MAX_PLIES) {
-            let m = san.to_move(&pos)?;
-            without_loops.insert(
-                pos.zobrist_hash(EnPassantMode::Legal),
-                (Uci::from_chess960(&m), pos.turn()),
-            );
+        let take_plies = if store_moves {
+            game.moves.len()
+        } else {
+            min(game.moves.len(), MAX_PLIES)
+        };
+        for (ply, san) in game.moves.into_iter().take(take_plies).enumerate() {
+            let m = match san.to_move(&pos) {
+                Ok(m) => m,
+                Err(err) if ply >= MAX_PLIES => {
+                    log::warn!("cutting off moves of lichess game {}: {}", game.id, err);
+                    break;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let uci = Uci::from_chess960(&m);
+            if ply < MAX_PLIES {
+                without_loops.insert(
+                    pos.zobrist_hash(EnPassantMode::Legal),
+                    (uci.clone(), pos.turn()),
+                );
+            }
+            if let Some(ref mut moves) = moves {
+                moves.moves.push(RawUci::from(uci));
+            }
             pos.play_unchecked(&m);
         }
 
-        let lichess_db = self.db.lichess();
-        let mut batch = lichess_db.batch();
-        for (key, (uci, turn)) in without_loops {
-            batch.merge_lichess(
-                KeyBuilder::lichess()
-                    .with_zobrist(game.variant, key)
-                    .with_month(month),
-                LichessEntry::new_single(
-                    uci,
-                    game.speed,
-                    game.id,
-                    outcome,
-                    game.players.get(turn).rating,
-                    game.players.get(!turn).rating,
-                ),
-            );
-        }
-        batch.merge_game(
-            game.id,
-            LichessGame {
+        let matchup_entries = without_loops
+            .iter()
+            .map(|(key, (uci, turn))| {
+                (
+                    KeyBuilder::lichess()
+                        .with_zobrist(game.variant, *key)
+                        .with_month(month),
+                    LichessMatchupEntry::new_single(
+                        uci.clone(),
+                        game.speed,
+                        outcome,
+                        game.players.get(*turn).rating,
+                        game.players.get(!*turn).rating,
+                    ),
+                )
+            })
+            .collect();
+
+        Ok(PreparedLichessGame {
+            id: game.id,
+            entries: without_loops
+                .into_iter()
+                .map(|(key, (uci, turn))| {
+                    (
+                        KeyBuilder::lichess()
+                            .with_zobrist(game.variant, key)
+                            .with_month(month),
+                        LichessEntry::new_single(
+                            uci,
+                            game.speed,
+                            game.id,
+                            outcome,
+                            game.termination,
+                            game.players.get(turn).rating,
+                            game.players.get(!turn).rating,
+                        ),
+                    )
+                })
+                .collect(),
+            matchup_entries,
+            game: LichessGame {
                 mode: Mode::Rated,
                 indexed_player: Default::default(),
                 indexed_lichess: true,
+                hardened_player: Default::default(),
                 outcome,
                 players: game.players,
                 month,
                 speed: game.speed,
+                clock: game.clock,
+                termination: game.termination,
+                plies,
             },
-        );
-
-        let _guard = self.mutex.lock().expect("lock lichess db");
-        if lichess_db
-            .game(game.id)
-            .expect("get game info")
-            .map_or(false, |info| info.indexed_lichess)
-        {
-            log::debug!("lichess game {} already imported", game.id);
-        } else {
-            batch.commit().expect("commit lichess game");
-        }
-        Ok(())
+            moves,
+        })
     }
 }
+
+pub struct PreparedLichessGame {
+    pub id: GameId,
+    pub entries: Vec<(Key, LichessEntry)>,
+    pub matchup_entries: Vec<(Key, LichessMatchupEntry)>,
+    pub game: LichessGame,
+    pub moves: Option<LichessGameMoves>,
+}
@@ -13,7 +13,7 @@ use tokio_util::io::StreamReader;
 
 use crate::{
     indexer::IndexerOpt,
-    model::{GameId, Speed, UserId, UserName},
+    model::{Clock, GameId, Speed, Termination, UserId, UserName},
     util::ByColorDef,
 };
 
@@ -96,6 +96,8 @@ pub struct Game {
     #[serde_as(as = "Option<DisplayFromStr>")]
     #[serde(default)]
     pub initial_fen: Option<Fen>,
+    #[serde(default)]
+    pub clock: Option<Clock>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -143,6 +145,24 @@ impl Status {
             Status::UnknownFinish | Status::NoStart | Status::Aborted
         )
     }
+
+    pub fn termination(self) -> Option<Termination> {
+        Some(match self {
+            Status::Mate => Termination::Mate,
+            Status::Resign => Termination::Resign,
+            Status::Stalemate => Termination::Stalemate,
+            Status::Timeout => Termination::Timeout,
+            Status::Draw => Termination::Draw,
+            Status::OutOfTime => Termination::OutOfTime,
+            Status::Cheat => Termination::Cheat,
+            Status::VariantEnd => Termination::VariantEnd,
+            Status::Created
+            | Status::Started
+            | Status::Aborted
+            | Status::NoStart
+            | Status::UnknownFinish => return None,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -157,5 +177,13 @@ mod tests {
         let game: Game = serde_json::from_str(record).expect("deserialize");
         let month = Month::from_time_saturating(game.last_move_at);
         assert_eq!(month, Month::try_from(24267).unwrap());
+        assert_eq!(game.status.termination(), Some(Termination::Mate));
+        assert_eq!(
+            game.clock,
+            Some(Clock {
+                initial: 600,
+                increment: 0
+            })
+        );
     }
 }
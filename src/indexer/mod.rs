@@ -24,8 +24,8 @@ use tokio::{
 use crate::{
     db::Database,
     model::{
-        GamePlayer, IndexRun, KeyBuilder, LichessGame, Mode, Month, PlayerEntry, PlayerStatus,
-        UserId,
+        GamePlayer, IndexRun, KeyBuilder, KeyCheck, KeyScheme, LichessGame, LichessGameMoves, Mode,
+        Month, PlayerEntry, PlayerStatus, RawUci, UserId,
     },
 };
 
@@ -53,11 +53,17 @@ pub struct IndexerStub {
     db: Arc<Database>,
     indexing: Arc<RwLock<HashMap<UserId, watch::Sender<()>>>>,
     tx: async_channel::Sender<IndexerMessage>,
+    shutdown: Arc<watch::Sender<bool>>,
 }
 
 impl IndexerStub {
-    pub fn spawn(db: Arc<Database>, opt: IndexerOpt) -> (IndexerStub, Vec<JoinHandle<()>>) {
+    pub fn spawn(
+        db: Arc<Database>,
+        opt: IndexerOpt,
+        store_moves: bool,
+    ) -> (IndexerStub, Vec<JoinHandle<()>>) {
         let indexing = Arc::new(RwLock::new(HashMap::new()));
+        let (shutdown, shutdown_rx) = watch::channel(false);
 
         let (tx, rx) = async_channel::bounded(opt.indexers * 10);
         let mut join_handles = Vec::with_capacity(opt.indexers);
@@ -69,12 +75,33 @@ impl IndexerStub {
                     indexing: Arc::clone(&indexing),
                     db: Arc::clone(&db),
                     lila: Lila::new(opt.clone()),
+                    store_moves,
+                    shutdown: shutdown_rx.clone(),
                 }
                 .run(),
             ));
         }
 
-        (IndexerStub { db, indexing, tx }, join_handles)
+        (
+            IndexerStub {
+                db,
+                indexing,
+                tx,
+                shutdown: Arc::new(shutdown),
+            },
+            join_handles,
+        )
+    }
+
+    /// Stops accepting new indexing requests and asks all indexers to
+    /// persist their progress and stop. Subscribers of queued and ongoing
+    /// indexing runs are notified immediately.
+    pub async fn shutdown(&self) {
+        self.tx.close();
+        self.shutdown.send_replace(true);
+
+        let mut guard = self.indexing.write().await;
+        guard.clear();
     }
 
     pub async fn num_indexing(&self) -> usize {
@@ -101,7 +128,8 @@ impl IndexerStub {
             .unwrap_or_default();
 
         let index_run = match status
-            .maybe_revisit_ongoing()
+            .maybe_rehash()
+            .or_else(|| status.maybe_revisit_ongoing())
             .or_else(|| status.maybe_index())
         {
             Some(since) => since,
@@ -132,6 +160,7 @@ impl IndexerStub {
                 );
                 None
             }
+            Err(TrySendError::Closed(_)) if *self.shutdown.borrow() => None,
             Err(TrySendError::Closed(_)) => panic!("all indexers died"),
         }
     }
@@ -143,11 +172,19 @@ struct IndexerActor {
     rx: async_channel::Receiver<IndexerMessage>,
     db: Arc<Database>,
     lila: Lila,
+    store_moves: bool,
+    shutdown: watch::Receiver<bool>,
 }
 
 impl IndexerActor {
     async fn run(self) {
         while let Ok(msg) = self.rx.recv().await {
+            if self.is_shutting_down() {
+                // Drop queued requests. The player status is unchanged, so
+                // they will simply be queued again after a restart.
+                continue;
+            }
+
             match msg {
                 IndexerMessage::IndexPlayer {
                     player,
@@ -161,6 +198,20 @@ impl IndexerActor {
                 }
             }
         }
+
+        log::info!("indexer {:02}: stopped", self.idx);
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+        while !*shutdown.borrow() {
+            if shutdown.changed().await.is_err() {
+                break;
+            }
+        }
     }
 
     async fn index_player(&self, player: &UserId, mut status: PlayerStatus, index_run: IndexRun) {
@@ -172,12 +223,24 @@ impl IndexerActor {
             index_run,
         );
 
-        let mut games = match timeout(
-            Duration::from_secs(60),
-            self.lila.user_games(player, index_run.since()),
-        )
-        .await
-        {
+        let mut shutdown = self.shutdown.clone();
+
+        let res = tokio::select! {
+            res = timeout(
+                Duration::from_secs(60),
+                self.lila.user_games(player, index_run.since()),
+            ) => res,
+            _ = IndexerActor::shutdown_requested(&mut shutdown) => {
+                log::info!(
+                    "indexer {:02}: shutdown before starting {}",
+                    self.idx,
+                    player.as_lowercase_str()
+                );
+                return;
+            }
+        };
+
+        let mut games = match res {
             Ok(Ok(games)) => games,
             Ok(Err(err)) if err.status() == Some(StatusCode::NOT_FOUND) => {
                 log::warn!(
@@ -202,7 +265,27 @@ impl IndexerActor {
 
         let mut num_games = 0;
         loop {
-            let game = match timeout(Duration::from_secs(60), games.next()).await {
+            let res = tokio::select! {
+                res = timeout(Duration::from_secs(60), games.next()) => res,
+                _ = IndexerActor::shutdown_requested(&mut shutdown) => {
+                    // Persist progress, but do not finish the run, so that
+                    // indexing continues after a restart.
+                    self.db
+                        .lichess()
+                        .put_player_status(player, &status)
+                        .expect("put player status");
+
+                    log::info!(
+                        "indexer {:02}: interrupted {} after {} games due to shutdown",
+                        self.idx,
+                        player.as_lowercase_str(),
+                        num_games
+                    );
+                    return;
+                }
+            };
+
+            let game = match res {
                 Ok(Some(Ok(game))) => game,
                 Ok(Some(Err(err))) => {
                     log::error!("indexer {:02}: {}", self.idx, err);
@@ -312,12 +395,19 @@ impl IndexerActor {
         // Skip game if already indexed from this side. This cannot race with
         // writes, because all writes for the same player are sequenced by
         // this actor. So making a transaction is not required.
+        //
+        // While the player is not yet completely moved to checked keys, also
+        // keep the legacy keys complete. Afterwards, revisit all games once
+        // more to delete them.
         let lichess_db = self.db.lichess();
-        if lichess_db
-            .game(game.id)
-            .expect("get game info")
-            .map_or(false, |info| *info.indexed_player.get(color))
-        {
+        let info = lichess_db.game(game.id).expect("get game info");
+        let write_legacy = status.key_scheme.reads_legacy()
+            && !info
+                .as_ref()
+                .map_or(false, |info| *info.indexed_player.get(color));
+        let write_checked = !info.map_or(false, |info| *info.hardened_player.get(color));
+        let delete_legacy = status.key_scheme == KeyScheme::Cleaning;
+        if !write_legacy && !write_checked && !delete_legacy {
             log::debug!(
                 "indexer {:02}: {}/{} already indexed",
                 self.idx,
@@ -330,6 +420,12 @@ impl IndexerActor {
         // Prepare basic information and setup initial position.
         let month = Month::from_time_saturating(game.last_move_at);
         let outcome = Outcome::from_winner(game.winner);
+        let plies = u32::try_from(game.moves.len()).ok();
+        let mut moves = self.store_moves.then(|| LichessGameMoves {
+            variant: game.variant,
+            fen: game.initial_fen.clone(),
+            moves: Vec::with_capacity(game.moves.len()),
+        });
         let mut pos = match game.initial_fen {
             Some(fen) => {
                 match VariantPosition::from_setup(
@@ -359,11 +455,11 @@ impl IndexerActor {
         };
 
         // Build an intermediate table to remove loops (due to repetitions).
-        let mut without_loops: IntMap<Zobrist128, Uci> =
+        let mut without_loops: IntMap<Zobrist128, (Uci, KeyCheck)> =
             HashMap::with_capacity_and_hasher(game.moves.len(), Default::default());
 
         for (ply, san) in game.moves.into_iter().enumerate() {
-            if ply >= MAX_PLIES {
+            if ply >= MAX_PLIES && moves.is_none() {
                 break;
             }
 
@@ -383,7 +479,15 @@ impl IndexerActor {
             };
 
             let uci = m.to_uci(CastlingMode::Chess960);
-            without_loops.insert(pos.zobrist_hash(shakmaty::EnPassantMode::Legal), uci);
+            if let Some(ref mut moves) = moves {
+                moves.moves.push(RawUci::from(uci.clone()));
+            }
+            if ply < MAX_PLIES {
+                without_loops.insert(
+                    pos.zobrist_hash(shakmaty::EnPassantMode::Legal),
+                    (uci, hash.get(color).check(&pos)),
+                );
+            }
 
             pos.play_unchecked(&m);
         }
@@ -393,6 +497,18 @@ impl IndexerActor {
         // state.
         let mut batch = lichess_db.batch();
 
+        if !write_legacy && !write_checked {
+            for zobrist in without_loops.into_keys() {
+                batch.delete_player(
+                    hash.get(color)
+                        .with_zobrist(game.variant, zobrist)
+                        .with_month(month),
+                );
+            }
+            batch.commit().expect("atomically delete legacy keys");
+            return;
+        }
+
         batch.merge_game(
             game.id,
             LichessGame {
@@ -406,23 +522,38 @@ impl IndexerActor {
                 }),
                 indexed_player: ByColor::new_with(|c| color == c),
                 indexed_lichess: false,
+                hardened_player: ByColor::new_with(|c| color == c),
+                clock: game.clock,
+                termination: game.status.termination(),
+                plies,
             },
         );
+        if let Some(moves) = moves {
+            batch.put_game_moves(game.id, &moves);
+        }
 
-        for (zobrist, uci) in without_loops {
-            batch.merge_player(
-                hash.get(color)
-                    .with_zobrist(game.variant, zobrist)
-                    .with_month(month),
-                PlayerEntry::new_single(
-                    uci.clone(),
-                    game.speed,
-                    Mode::from_rated(game.rated),
-                    game.id,
-                    outcome,
-                    opponent_rating,
-                ),
+        for (zobrist, (uci, check)) in without_loops {
+            let key = hash
+                .get(color)
+                .with_zobrist(game.variant, zobrist)
+                .with_month(month);
+            let entry = PlayerEntry::new_single(
+                uci,
+                game.speed,
+                Mode::from_rated(game.rated),
+                game.id,
+                outcome,
+                game.status.termination(),
+                opponent_rating,
             );
+            if write_checked {
+                batch.merge_player_checked(key.with_check(&check), &entry);
+            }
+            if write_legacy {
+                batch.merge_player(key, &entry);
+            } else if delete_legacy {
+                batch.delete_player(key);
+            }
         }
 
         batch.commit().expect("atomically commit game and moves");
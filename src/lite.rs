@@ -0,0 +1,441 @@
+//! Compact, self-contained export of popular positions, for offline clients.
+//!
+//! File format:
+//!
+//! * Magic bytes `b"explorer-lite"`.
+//! * Format version (u8), currently 1.
+//! * Number of records (u32, little endian).
+//! * Index of fixed size records, sorted by table and key: table (u8, 0 for
+//!   lichess, 1 for masters), `KeyPrefix` (12 bytes), offset into the data
+//!   section (u32, little endian), and length (u32, little endian).
+//! * Data section with entries in the binary encoding of the `model`
+//!   module. Lichess entries contain no game references. Entries aggregate
+//!   all months, so `since` and `until` filters are not supported.
+//!
+//! All entries are decoded once when opening, so that corrupt files are
+//! rejected up front rather than failing queries.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    panic,
+    path::Path,
+};
+
+use shakmaty::{uci::Uci, variant::Variant, zobrist::ZobristHash, EnPassantMode};
+use thiserror::Error;
+
+use crate::{
+    api::{Breakdowns, Error, LichessQueryFilter, Limits, Play},
+    db::Database,
+    model::{KeyBuilder, KeyPrefix, LichessEntry, MastersEntry, PreparedResponse, Year},
+    opening::Openings,
+    util::walk_positions,
+};
+
+const MAGIC: &[u8] = b"explorer-lite";
+
+const VERSION: u8 = 1;
+
+const HEADER_SIZE: usize = MAGIC.len() + 1 + 4;
+
+const INDEX_KEY_SIZE: usize = 1 + KeyPrefix::SIZE;
+
+const INDEX_RECORD_SIZE: usize = INDEX_KEY_SIZE + 4 + 4;
+
+const TABLE_LICHESS: u8 = 0;
+
+const TABLE_MASTERS: u8 = 1;
+
+const VARIANTS: [Variant; 8] = [
+    Variant::Chess,
+    Variant::Antichess,
+    Variant::Atomic,
+    Variant::Crazyhouse,
+    Variant::Horde,
+    Variant::KingOfTheHill,
+    Variant::RacingKings,
+    Variant::ThreeCheck,
+];
+
+#[derive(Error, Debug)]
+pub enum LiteError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    RocksDb(#[from] rocksdb::Error),
+    #[error("not a lite explorer database")]
+    InvalidMagic,
+    #[error("unsupported lite format version {0}")]
+    UnsupportedVersion(u8),
+    #[error("truncated lite explorer database")]
+    Truncated,
+    #[error("index record {0} out of bounds of data section")]
+    OutOfBounds(usize),
+    #[error("index record {0} has an invalid entry")]
+    InvalidEntry(usize),
+    #[error("lite explorer database too large")]
+    TooLarge,
+}
+
+pub struct ExportOpt {
+    /// Minimum number of lichess games for a position to be included.
+    pub min_games: u64,
+    /// Minimum number of masters games for a position to be included.
+    pub min_masters_games: u64,
+    /// Maximum depth of positions, in plies from the starting position.
+    pub max_plies: usize,
+}
+
+fn all_games() -> LichessQueryFilter {
+    LichessQueryFilter {
+        speeds: None,
+        ratings: None,
+        min_rating: None,
+        max_rating: None,
+        since: None,
+        until: None,
+    }
+}
+
+fn all_moves() -> Limits {
+    Limits {
+        top_games: 0,
+        recent_games: 0,
+        moves: Some(usize::MAX),
+    }
+}
+
+/// Walks positions reachable from the starting position of each variant
+/// through moves played at least `min_games` times, and writes their entries
+/// to a lite database at `path`.
+pub fn export(db: &Database, path: &Path, opt: &ExportOpt) -> Result<(), LiteError> {
+    let mut records = BTreeMap::new();
+
+    for variant in VARIANTS {
+        log::info!("exporting lichess {:?} ...", variant);
+        walk_positions(variant, opt.max_plies, |pos| {
+            let key = KeyBuilder::lichess()
+                .with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
+            let mut entry = db.lichess().read_lichess(&key, None, None)?;
+            entry.clear_games();
+            let mut buf = Vec::with_capacity(LichessEntry::SIZE_HINT);
+            entry.write(&mut buf);
+
+            let prepared = entry.prepare(&all_games(), &all_moves(), &Breakdowns::default());
+            if prepared.total.total() >= opt.min_games {
+                records.insert(index_key(TABLE_LICHESS, &key), buf);
+            }
+            Ok(popular_moves(prepared, opt.min_games))
+        })?;
+    }
+
+    log::info!("exporting masters ...");
+    walk_positions(Variant::Chess, opt.max_plies, |pos| {
+        let key = KeyBuilder::masters()
+            .with_zobrist(Variant::Chess, pos.zobrist_hash(EnPassantMode::Legal));
+        let index = index_key(TABLE_MASTERS, &key);
+        let entry = db
+            .masters()
+            .read(key, Year::min_value(), Year::max_value())?;
+        let mut buf = Vec::with_capacity(MastersEntry::SIZE_HINT);
+        entry.write(&mut buf);
+
+        let prepared = entry.prepare(&all_moves());
+        if prepared.total.total() >= opt.min_masters_games {
+            records.insert(index, buf);
+        }
+        Ok(popular_moves(prepared, opt.min_masters_games))
+    })?;
+
+    write(path, &records)?;
+    log::info!("exported {} positions", records.len());
+    Ok(())
+}
+
+fn popular_moves(prepared: PreparedResponse, min_games: u64) -> Vec<Uci> {
+    prepared
+        .moves
+        .into_iter()
+        .filter(|m| m.stats.total() >= min_games)
+        .map(|m| m.uci)
+        .collect()
+}
+
+fn index_key(table: u8, key: &KeyPrefix) -> [u8; INDEX_KEY_SIZE] {
+    let mut buf = [0; INDEX_KEY_SIZE];
+    buf[0] = table;
+    buf[1..].clone_from_slice(&key.to_bytes());
+    buf
+}
+
+fn write(path: &Path, records: &BTreeMap<[u8; INDEX_KEY_SIZE], Vec<u8>>) -> Result<(), LiteError> {
+    let num_records = u32::try_from(records.len()).map_err(|_| LiteError::TooLarge)?;
+
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])?;
+    writer.write_all(&num_records.to_le_bytes())?;
+
+    let mut offset = 0u32;
+    for (key, value) in records {
+        let len = u32::try_from(value.len()).map_err(|_| LiteError::TooLarge)?;
+        writer.write_all(key)?;
+        writer.write_all(&offset.to_le_bytes())?;
+        writer.write_all(&len.to_le_bytes())?;
+        offset = offset.checked_add(len).ok_or(LiteError::TooLarge)?;
+    }
+    for value in records.values() {
+        writer.write_all(value)?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|err| err.into_error())?
+        .sync_all()?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+fn decode_lichess(mut buf: &[u8]) -> LichessEntry {
+    let mut entry = LichessEntry::default();
+    entry.extend_from_reader(&mut buf);
+    entry
+}
+
+fn decode_masters(mut buf: &[u8]) -> MastersEntry {
+    let mut entry = MastersEntry::default();
+    entry.extend_from_reader(&mut buf);
+    entry
+}
+
+/// Reader for lite databases created by `export()`. Loads the entire file
+/// into memory.
+pub struct LiteDatabase {
+    buf: Vec<u8>,
+    num_records: usize,
+}
+
+impl LiteDatabase {
+    pub fn open(path: &Path) -> Result<LiteDatabase, LiteError> {
+        let buf = fs::read(path)?;
+        if buf.len() < HEADER_SIZE {
+            return Err(LiteError::Truncated);
+        }
+        if &buf[..MAGIC.len()] != MAGIC {
+            return Err(LiteError::InvalidMagic);
+        }
+        let version = buf[MAGIC.len()];
+        if version != VERSION {
+            return Err(LiteError::UnsupportedVersion(version));
+        }
+        let num_records = read_u32(&buf[MAGIC.len() + 1..]) as usize;
+        if num_records
+            .checked_mul(INDEX_RECORD_SIZE)
+            .and_then(|index_len| index_len.checked_add(HEADER_SIZE))
+            .map_or(true, |data_start| data_start > buf.len())
+        {
+            return Err(LiteError::Truncated);
+        }
+
+        // Validate all records up front, so that get() can not go out of
+        // bounds, and decoding entries can not panic.
+        let db = LiteDatabase { buf, num_records };
+        let data_start = db.data_start();
+        let data_len = db.buf.len() - data_start;
+        let mut total_len = 0u64;
+        for i in 0..num_records {
+            let record = db.index_record(i);
+            let (offset, len) = data_range(record);
+            if offset.checked_add(len).map_or(true, |end| end > data_len) {
+                return Err(LiteError::OutOfBounds(i));
+            }
+            let entry = &db.buf[data_start + offset..data_start + offset + len];
+            let valid = match record[0] {
+                TABLE_LICHESS => panic::catch_unwind(|| decode_lichess(entry)).is_ok(),
+                TABLE_MASTERS => panic::catch_unwind(|| decode_masters(entry)).is_ok(),
+                _ => false,
+            };
+            if !valid {
+                return Err(LiteError::InvalidEntry(i));
+            }
+            total_len += len as u64;
+        }
+        if total_len != data_len as u64 {
+            return Err(LiteError::Truncated);
+        }
+        Ok(db)
+    }
+
+    fn data_start(&self) -> usize {
+        HEADER_SIZE + self.num_records * INDEX_RECORD_SIZE
+    }
+
+    fn index_record(&self, i: usize) -> &[u8] {
+        let start = HEADER_SIZE + i * INDEX_RECORD_SIZE;
+        &self.buf[start..start + INDEX_RECORD_SIZE]
+    }
+
+    fn get(&self, table: u8, key: &KeyPrefix) -> Option<&[u8]> {
+        let needle = index_key(table, key);
+        let (mut lo, mut hi) = (0, self.num_records);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let record = self.index_record(mid);
+            match record[..INDEX_KEY_SIZE].cmp(&needle[..]) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => {
+                    let (offset, len) = data_range(record);
+                    let start = self.data_start() + offset;
+                    return Some(&self.buf[start..start + len]);
+                }
+            }
+        }
+        None
+    }
+
+    /// Queries the lichess explorer like `/lichess`, but without games.
+    /// Returns `None` for positions that were not exported.
+    ///
+    /// `since` and `until` are rejected. `minRating` and `maxRating` are
+    /// widened to the boundaries of the rating groups, like in
+    /// `LichessQueryFilter::rating_range()`.
+    pub fn lichess(
+        &self,
+        play: Play,
+        openings: &Openings,
+        filter: &LichessQueryFilter,
+        limits: &Limits,
+    ) -> Result<Option<PreparedResponse>, Error> {
+        if filter.since.is_some() || filter.until.is_some() {
+            return Err(Error::UnsupportedDateFilter);
+        }
+        filter.rating_range()?;
+        let pos = play.position(openings)?.pos;
+        let key = KeyBuilder::lichess()
+            .with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
+        Ok(self
+            .get(TABLE_LICHESS, &key)
+            .map(|buf| decode_lichess(buf).prepare(filter, limits, &Breakdowns::default())))
+    }
+
+    /// Queries the masters explorer like `/masters`, over all years. Returns
+    /// `None` for positions that were not exported.
+    pub fn masters(
+        &self,
+        play: Play,
+        openings: &Openings,
+        limits: &Limits,
+    ) -> Result<Option<PreparedResponse>, Error> {
+        let pos = play.position(openings)?.pos;
+        let key = KeyBuilder::masters()
+            .with_zobrist(Variant::Chess, pos.zobrist_hash(EnPassantMode::Legal));
+        Ok(self
+            .get(TABLE_MASTERS, &key)
+            .map(|buf| decode_masters(buf).prepare(limits)))
+    }
+}
+
+/// Offset into the data section and length of the entry of an index record.
+fn data_range(record: &[u8]) -> (usize, usize) {
+    let offset = read_u32(&record[INDEX_KEY_SIZE..]) as usize;
+    let len = read_u32(&record[INDEX_KEY_SIZE + 4..]) as usize;
+    (offset, len)
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes(buf[..4].try_into().expect("u32"))
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::{Chess, Outcome};
+
+    use super::*;
+    use crate::model::{GameId, Speed};
+
+    #[test]
+    fn test_write_and_get() {
+        let lichess = KeyBuilder::lichess().with_zobrist(
+            Variant::Chess,
+            Chess::default().zobrist_hash(EnPassantMode::Legal),
+        );
+        let masters = KeyBuilder::masters().with_zobrist(
+            Variant::Chess,
+            Chess::default().zobrist_hash(EnPassantMode::Legal),
+        );
+        let uci: Uci = "e2e4".parse().unwrap();
+        let id: GameId = "aaaaaaaa".parse().unwrap();
+        let mut lichess_buf = Vec::new();
+        LichessEntry::new_single(
+            uci.clone(),
+            Speed::Blitz,
+            id,
+            Outcome::Draw,
+            None,
+            2000,
+            2000,
+        )
+        .write(&mut lichess_buf);
+        let mut masters_buf = Vec::new();
+        MastersEntry::new_single(uci, id, Outcome::Draw, 2700, 2700).write(&mut masters_buf);
+        let mut records = BTreeMap::new();
+        records.insert(index_key(TABLE_MASTERS, &masters), masters_buf.clone());
+        records.insert(index_key(TABLE_LICHESS, &lichess), lichess_buf.clone());
+
+        let path = std::env::temp_dir().join(format!("explorer-lite-{}", std::process::id()));
+        write(&path, &records).unwrap();
+        let db = LiteDatabase::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(db.get(TABLE_LICHESS, &lichess), Some(&lichess_buf[..]));
+        assert_eq!(db.get(TABLE_MASTERS, &masters), Some(&masters_buf[..]));
+        assert_eq!(db.get(TABLE_MASTERS, &lichess), None);
+    }
+
+    #[test]
+    fn test_open_invalid_entry() {
+        let masters = KeyBuilder::masters().with_zobrist(
+            Variant::Chess,
+            Chess::default().zobrist_hash(EnPassantMode::Legal),
+        );
+        let mut records = BTreeMap::new();
+        // Stats end early.
+        records.insert(index_key(TABLE_MASTERS, &masters), vec![3, 4, 5]);
+
+        let path =
+            std::env::temp_dir().join(format!("explorer-lite-invalid-{}", std::process::id()));
+        write(&path, &records).unwrap();
+        let res = LiteDatabase::open(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(res, Err(LiteError::InvalidEntry(0))));
+    }
+
+    #[test]
+    fn test_open_out_of_bounds() {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        buf.extend_from_slice(&2u32.to_le_bytes());
+        for (i, offset) in [0u32, 3].into_iter().enumerate() {
+            buf.push(TABLE_LICHESS);
+            buf.extend_from_slice(&[i as u8; KeyPrefix::SIZE]);
+            buf.extend_from_slice(&offset.to_le_bytes());
+            buf.extend_from_slice(&2u32.to_le_bytes());
+        }
+        // Lengths add up, but the second record ends past the data section.
+        buf.extend_from_slice(&[1, 2, 3, 4]);
+
+        let path =
+            std::env::temp_dir().join(format!("explorer-lite-corrupt-{}", std::process::id()));
+        fs::write(&path, &buf).unwrap();
+        let res = LiteDatabase::open(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(res, Err(LiteError::OutOfBounds(1))));
+    }
+}
@@ -1,18 +1,35 @@
 #![forbid(unsafe_code)]
 
 pub mod api;
+pub mod bulk_load;
+pub mod check;
+pub mod command;
 pub mod db;
+pub mod dump;
 pub mod importer;
 pub mod indexer;
+pub mod lite;
+pub mod merge;
+pub mod migration;
 pub mod model;
 pub mod opening;
+pub mod polyglot;
 pub mod util;
 
-use std::{mem, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    cmp::Reverse,
+    mem,
+    net::SocketAddr,
+    path::{Component, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use axum::{
-    extract::{FromRef, Path, Query, State},
+    extract::{FromRef, Path, Query, RawQuery, State},
     http::StatusCode,
+    middleware,
+    response::{IntoResponse, Response},
     routing::{get, post, put},
     Json, Router,
 };
@@ -29,18 +46,29 @@ use shakmaty::{
     Color, EnPassantMode,
 };
 use tikv_jemallocator::Jemalloc;
-use tokio::{sync::watch, task};
+use tokio::{
+    signal,
+    sync::{mpsc, watch},
+    task,
+};
 
 use crate::{
     api::{
-        Error, ExplorerGame, ExplorerGameWithUci, ExplorerHistoryResponse, ExplorerMove,
-        ExplorerResponse, LichessHistoryQuery, LichessQuery, Limits, MastersQuery, NdJson,
-        PlayPosition, PlayerQuery, PlayerQueryFilter,
+        rate_limit, require_admin_bearer, AdminBearer, Breakdowns, Error, ExplorerGame,
+        ExplorerGameWithUci, ExplorerHistoryResponse, ExplorerMove, ExplorerResponse,
+        LichessHistoryQuery, LichessQuery, LimitOpt, Limits, MastersEventQuery, MastersEventRow,
+        MastersEventsQuery, MastersEventsResponse, MastersPlayerQuery, MastersQuery,
+        MastersSearchQuery, MastersSearchResponse, MastersSearchRow, NdJson, PgnLimit, PgnStream,
+        PlayPosition, PlayerQuery, PlayerQueryFilter, Primary, RateLimiter, ReadLimiter,
     },
-    db::{Database, DbOpt, LichessDatabase},
-    importer::{LichessGameImport, LichessImporter, MastersImporter},
+    command::Command,
+    db::{AccessMode, Database, DbOpt, LichessDatabase, MastersDatabase},
+    importer::{LichessGameImport, LichessImporter, MastersImporter, MastersPolicyOpt},
     indexer::{IndexerOpt, IndexerStub},
-    model::{GameId, KeyBuilder, KeyPrefix, MastersGame, MastersGameWithId, PreparedMove, UserId},
+    model::{
+        GameId, KeyBuilder, KeyCheck, KeyPrefix, MastersEvent, MastersEventId, MastersGame,
+        MastersGameWithId, Month, PreparedMove, UserId,
+    },
     opening::{Opening, Openings},
     util::DedupStreamExt as _,
 };
@@ -50,33 +78,69 @@ static GLOBAL: Jemalloc = Jemalloc;
 
 #[derive(Parser)]
 struct Opt {
-    /// Binding address. Note that administrative endpoints must be protected
-    /// using a reverse proxy.
+    /// Binding address. Administrative endpoints are also served here,
+    /// unless --admin-bind is given.
     #[arg(long, default_value = "127.0.0.1:9002")]
     bind: SocketAddr,
+    /// Separate binding address for administrative endpoints.
+    #[arg(long)]
+    admin_bind: Option<SocketAddr>,
+    /// Bearer token required for administrative endpoints. Without a token,
+    /// administrative endpoints must be protected using a reverse proxy.
+    #[arg(long, env = "EXPLORER_ADMIN_BEARER")]
+    admin_bearer: Option<String>,
+    /// Base url of the primary explorer. When the database is opened
+    /// read-only or as a secondary, /player requests for players that have
+    /// not been indexed are forwarded to the primary instead of being
+    /// rejected.
+    #[arg(long)]
+    primary: Option<String>,
     /// Allow access from all origins.
     #[arg(long)]
     cors: bool,
     /// Number of cached responses for masters and Lichess database each.
     #[arg(long, default_value = "2000")]
     cached_responses: u64,
+    /// Directory for checkpoints and backups created with the /checkpoint
+    /// and /backup endpoints, which are disabled without it. Clients only
+    /// choose names relative to this directory.
+    #[arg(long)]
+    backup_root: Option<PathBuf>,
+    /// Also store the full move lists of imported and indexed Lichess games,
+    /// to serve /lichess/pgn/:id. Needs a lot of additional disk space.
+    #[arg(long)]
+    store_lichess_moves: bool,
     #[command(flatten)]
     db: DbOpt,
     #[command(flatten)]
     indexer: IndexerOpt,
+    #[command(flatten)]
+    masters_policy: MastersPolicyOpt,
+    #[command(flatten)]
+    limit: LimitOpt,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
 type ExplorerCache<T> = Cache<T, Result<Json<ExplorerResponse>, Error>>;
 
+/// All masters events, most recent first.
+type EventsCache = Cache<(), Arc<Vec<(MastersEventId, MastersEvent)>>>;
+
 #[derive(Clone)]
 struct AppState {
     openings: &'static Openings,
     db: Arc<Database>,
     lichess_cache: ExplorerCache<LichessQuery>,
     masters_cache: ExplorerCache<MastersQuery>,
+    events_cache: EventsCache,
     lichess_importer: LichessImporter,
     masters_importer: MastersImporter,
-    indexer: IndexerStub,
+    masters_policy: Arc<MastersPolicyOpt>,
+    indexer: Option<IndexerStub>,
+    primary: Option<Primary>,
+    reads: ReadLimiter,
+    backup_root: Option<BackupRoot>,
 }
 
 impl FromRef<AppState> for &'static Openings {
@@ -103,6 +167,12 @@ impl FromRef<AppState> for ExplorerCache<MastersQuery> {
     }
 }
 
+impl FromRef<AppState> for EventsCache {
+    fn from_ref(state: &AppState) -> EventsCache {
+        state.events_cache.clone()
+    }
+}
+
 impl FromRef<AppState> for LichessImporter {
     fn from_ref(state: &AppState) -> LichessImporter {
         state.lichess_importer.clone()
@@ -115,12 +185,36 @@ impl FromRef<AppState> for MastersImporter {
     }
 }
 
-impl FromRef<AppState> for IndexerStub {
-    fn from_ref(state: &AppState) -> IndexerStub {
+impl FromRef<AppState> for Arc<MastersPolicyOpt> {
+    fn from_ref(state: &AppState) -> Arc<MastersPolicyOpt> {
+        Arc::clone(&state.masters_policy)
+    }
+}
+
+impl FromRef<AppState> for Option<IndexerStub> {
+    fn from_ref(state: &AppState) -> Option<IndexerStub> {
         state.indexer.clone()
     }
 }
 
+impl FromRef<AppState> for Option<Primary> {
+    fn from_ref(state: &AppState) -> Option<Primary> {
+        state.primary.clone()
+    }
+}
+
+impl FromRef<AppState> for ReadLimiter {
+    fn from_ref(state: &AppState) -> ReadLimiter {
+        state.reads.clone()
+    }
+}
+
+impl FromRef<AppState> for Option<BackupRoot> {
+    fn from_ref(state: &AppState) -> Option<BackupRoot> {
+        state.backup_root.clone()
+    }
+}
+
 #[tokio::main]
 async fn main() {
     env_logger::Builder::from_env(
@@ -135,39 +229,152 @@ async fn main() {
 
     let opt = Opt::parse();
 
+    if let Some(command) = opt.command {
+        task::spawn_blocking(move || command.run(opt.db))
+            .await
+            .expect("blocking command");
+        return;
+    }
+
     let db = Arc::new(Database::open(opt.db).expect("db"));
-    let (indexer, join_handles) = IndexerStub::spawn(Arc::clone(&db), opt.indexer);
+    let (indexer, join_handles) = if db.is_read_only() {
+        (None, Vec::new())
+    } else {
+        let (indexer, join_handles) =
+            IndexerStub::spawn(Arc::clone(&db), opt.indexer, opt.store_lichess_moves);
+        (Some(indexer), join_handles)
+    };
 
-    let app = Router::new()
+    if !db.is_read_only() && migration::pending(&db).expect("format version") {
+        let db = Arc::clone(&db);
+        task::spawn_blocking(move || {
+            if let Err(err) = migration::run_pending(&db) {
+                log::error!("database migration failed: {}", err);
+            }
+        });
+    }
+
+    if let AccessMode::Secondary { catch_up_interval } = db.access() {
+        let db = Arc::clone(&db);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(catch_up_interval);
+            loop {
+                interval.tick().await;
+                let db = Arc::clone(&db);
+                if let Err(err) = task::spawn_blocking(move || db.try_catch_up_with_primary())
+                    .await
+                    .expect("blocking catch up")
+                {
+                    log::error!("failed to catch up with primary: {}", err);
+                }
+            }
+        });
+    }
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    tokio::spawn({
+        let indexer = indexer.clone();
+        async move {
+            shutdown_signal().await;
+            log::info!("shutting down ...");
+            if let Some(indexer) = indexer {
+                indexer.shutdown().await;
+            }
+            shutdown_tx.send_replace(());
+        }
+    });
+
+    let state = AppState {
+        openings: Box::leak(Box::new(Openings::build_table())),
+        lichess_cache: Cache::builder()
+            .max_capacity(opt.cached_responses)
+            .time_to_live(Duration::from_secs(5 * 60))
+            .build(),
+        masters_cache: Cache::builder()
+            .max_capacity(opt.cached_responses)
+            .time_to_live(Duration::from_secs(5 * 60))
+            .build(),
+        events_cache: Cache::builder()
+            .max_capacity(1)
+            .time_to_live(Duration::from_secs(5 * 60))
+            .build(),
+        lichess_importer: LichessImporter::new(Arc::clone(&db), opt.store_lichess_moves),
+        masters_importer: MastersImporter::new(Arc::clone(&db)),
+        masters_policy: Arc::new(opt.masters_policy),
+        indexer,
+        primary: opt.primary.map(Primary::new),
+        reads: ReadLimiter::new(&opt.limit),
+        backup_root: opt.backup_root.map(BackupRoot::new),
+        db: Arc::clone(&db),
+    };
+
+    let admin = Router::new()
         .route("/monitor/cf/:cf/:prop", get(cf_prop))
         .route("/monitor/db/:prop", get(db_prop))
         .route("/monitor/indexing", get(num_indexing))
-        .route("/compact", post(compact))
-        .route("/import/masters", put(masters_import))
-        .route("/import/lichess", put(lichess_import))
+        .route("/monitor/format-version", get(format_version))
+        .route("/monitor/masters-policy", get(masters_policy));
+
+    let admin = if state.db.is_read_only() {
+        admin
+    } else {
+        admin
+            .route("/compact", post(compact))
+            .route("/checkpoint", post(checkpoint))
+            .route("/backup", post(backup))
+            .route("/import/masters", put(masters_import))
+            .route("/import/lichess", put(lichess_import))
+    };
+
+    let admin = match opt.admin_bearer {
+        Some(bearer) => admin.route_layer(middleware::from_fn_with_state(
+            AdminBearer::from(bearer),
+            require_admin_bearer,
+        )),
+        None => admin,
+    };
+
+    let app = Router::new()
         .route("/masters/pgn/:id", get(masters_pgn))
+        .route("/masters/pgn", get(masters_position_pgn))
+        .route("/masters/games", get(masters_games))
+        .route("/masters/games/pgn", get(masters_games_pgn))
+        .route("/masters/player", get(masters_player))
+        .route("/masters/events", get(masters_events))
+        .route("/masters/event", get(masters_event))
         .route("/masters", get(masters))
         .route("/lichess", get(lichess))
         .route("/lichess/history", get(lichess_history))
+        .route("/lichess/pgn/:id", get(lichess_pgn))
         .route("/player", get(player))
         .route("/master/pgn/:id", get(masters_pgn)) // bc
         .route("/master", get(masters)) // bc
-        .route("/personal", get(player)) // bc
-        .with_state(AppState {
-            openings: Box::leak(Box::new(Openings::build_table())),
-            lichess_cache: Cache::builder()
-                .max_capacity(opt.cached_responses)
-                .time_to_live(Duration::from_secs(5 * 60))
-                .build(),
-            masters_cache: Cache::builder()
-                .max_capacity(opt.cached_responses)
-                .time_to_live(Duration::from_secs(5 * 60))
-                .build(),
-            lichess_importer: LichessImporter::new(Arc::clone(&db)),
-            masters_importer: MastersImporter::new(Arc::clone(&db)),
-            indexer,
-            db,
-        });
+        .route("/personal", get(player)); // bc
+
+    let app = match RateLimiter::new(&opt.limit) {
+        Some(limiter) => app.route_layer(middleware::from_fn_with_state(limiter, rate_limit)),
+        None => app,
+    };
+
+    let (app, admin_server) = match opt.admin_bind {
+        Some(admin_bind) => {
+            let admin = admin.with_state(state.clone());
+            let shutdown_rx = shutdown_rx.clone();
+            (
+                app,
+                Some(tokio::spawn(async move {
+                    axum::Server::bind(&admin_bind)
+                        .serve(admin.into_make_service())
+                        .with_graceful_shutdown(shutdown_requested(shutdown_rx))
+                        .await
+                        .expect("bind admin");
+                })),
+            )
+        }
+        None => (app.merge(admin), None),
+    };
+
+    let app = app.with_state(state);
 
     let app = if opt.cors {
         app.layer(tower_http::set_header::SetResponseHeaderLayer::overriding(
@@ -179,13 +386,52 @@ async fn main() {
     };
 
     axum::Server::bind(&opt.bind)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_requested(shutdown_rx))
         .await
         .expect("bind");
 
+    if let Some(admin_server) = admin_server {
+        admin_server.await.expect("admin server");
+    }
+
     for join_handle in join_handles {
         join_handle.await.expect("indexer");
     }
+
+    task::spawn_blocking(move || db.flush())
+        .await
+        .expect("blocking flush")
+        .expect("flush database");
+
+    log::info!("shut down cleanly");
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c().await.expect("install ctrl-c handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("install sigterm handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => (),
+        _ = terminate => (),
+    }
+}
+
+async fn shutdown_requested(mut shutdown_rx: watch::Receiver<()>) {
+    // Also resolves if the sender is gone.
+    let _ = shutdown_rx.changed().await;
 }
 
 #[derive(Deserialize)]
@@ -226,8 +472,23 @@ async fn db_prop(
     .expect("blocking db prop")
 }
 
-async fn num_indexing(State(indexer): State<IndexerStub>) -> String {
-    indexer.num_indexing().await.to_string()
+async fn format_version(State(db): State<Arc<Database>>) -> String {
+    task::spawn_blocking(move || db.format_version().expect("format version"))
+        .await
+        .expect("blocking format version")
+        .to_string()
+}
+
+async fn masters_policy(State(policy): State<Arc<MastersPolicyOpt>>) -> Json<MastersPolicyOpt> {
+    Json(policy.resolved())
+}
+
+async fn num_indexing(State(indexer): State<Option<IndexerStub>>) -> String {
+    match indexer {
+        Some(indexer) => indexer.num_indexing().await,
+        None => 0,
+    }
+    .to_string()
 }
 
 async fn compact(State(db): State<Arc<Database>>) {
@@ -236,6 +497,80 @@ async fn compact(State(db): State<Arc<Database>>) {
         .expect("blocking compact");
 }
 
+/// Directory given by `--backup-root`, confining the targets of /checkpoint
+/// and /backup.
+#[derive(Clone)]
+struct BackupRoot(Arc<std::path::Path>);
+
+impl BackupRoot {
+    fn new(path: PathBuf) -> BackupRoot {
+        BackupRoot(Arc::from(path))
+    }
+
+    /// Resolves a name chosen by the client. Only plain relative paths are
+    /// accepted, without `..`, `.` or a root.
+    fn resolve(
+        root: Option<BackupRoot>,
+        name: &std::path::Path,
+    ) -> Result<PathBuf, (StatusCode, String)> {
+        let root = root.ok_or_else(|| {
+            (
+                StatusCode::FORBIDDEN,
+                "disabled without --backup-root".to_owned(),
+            )
+        })?;
+        if name.as_os_str().is_empty()
+            || !name
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "expected a relative name under the backup root, got {}",
+                    name.display()
+                ),
+            ));
+        }
+        Ok(root.0.join(name))
+    }
+}
+
+#[derive(Deserialize)]
+struct CheckpointQuery {
+    path: PathBuf,
+}
+
+async fn checkpoint(
+    State(db): State<Arc<Database>>,
+    State(backup_root): State<Option<BackupRoot>>,
+    Query(query): Query<CheckpointQuery>,
+) -> Result<(), (StatusCode, String)> {
+    let path = BackupRoot::resolve(backup_root, &query.path)?;
+    task::spawn_blocking(move || db.checkpoint(&path))
+        .await
+        .expect("blocking checkpoint")
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+#[derive(Deserialize)]
+struct BackupQuery {
+    dir: PathBuf,
+    keep: Option<usize>,
+}
+
+async fn backup(
+    State(db): State<Arc<Database>>,
+    State(backup_root): State<Option<BackupRoot>>,
+    Query(query): Query<BackupQuery>,
+) -> Result<(), (StatusCode, String)> {
+    let dir = BackupRoot::resolve(backup_root, &query.dir)?;
+    task::spawn_blocking(move || db.backup(&dir, query.keep))
+        .await
+        .expect("blocking backup")
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
 fn finalize_lichess_moves(
     moves: Vec<PreparedMove>,
     pos: &VariantPosition,
@@ -256,6 +591,7 @@ fn finalize_lichess_moves(
             average_rating: p.average_rating,
             average_opponent_rating: p.average_opponent_rating,
             performance: p.performance,
+            breakdown: p.breakdown,
             game: p.game.and_then(|id| {
                 lichess_db
                     .game(id)
@@ -284,13 +620,66 @@ fn finalize_lichess_games(
         .collect()
 }
 
+fn finalize_masters_moves(
+    moves: Vec<PreparedMove>,
+    pos: &VariantPosition,
+    masters_db: &MastersDatabase,
+) -> Vec<ExplorerMove> {
+    moves
+        .into_iter()
+        .map(|p| ExplorerMove {
+            san: p.uci.to_move(pos).map_or(
+                SanPlus {
+                    san: San::Null,
+                    suffix: None,
+                },
+                |m| SanPlus::from_move(pos.clone(), &m),
+            ),
+            uci: p.uci,
+            average_rating: p.average_rating,
+            average_opponent_rating: p.average_opponent_rating,
+            performance: p.performance,
+            stats: p.stats,
+            breakdown: p.breakdown,
+            game: p.game.and_then(|id| {
+                masters_db
+                    .game(id)
+                    .expect("get masters game")
+                    .map(|info| ExplorerGame::from_masters(id, info))
+            }),
+        })
+        .collect()
+}
+
+fn finalize_masters_games(
+    games: Vec<(Uci, GameId)>,
+    masters_db: &MastersDatabase,
+) -> Vec<ExplorerGameWithUci> {
+    masters_db
+        .games(games.iter().map(|(_, id)| *id))
+        .expect("get masters games")
+        .into_iter()
+        .zip(games.into_iter())
+        .filter_map(|(info, (uci, id))| {
+            info.map(|info| ExplorerGameWithUci {
+                uci,
+                row: ExplorerGame::from_masters(id, info),
+            })
+        })
+        .collect()
+}
+
 struct PlayerStreamState {
     indexing: Option<watch::Receiver<()>>,
+    player: UserId,
     key: KeyPrefix,
+    check: KeyCheck,
     db: Arc<Database>,
+    reads: ReadLimiter,
     color: Color,
     filter: PlayerQueryFilter,
     limits: Limits,
+    breakdowns: Breakdowns,
     pos: VariantPosition,
     opening: Option<&'static Opening>,
     first: bool,
@@ -300,23 +689,59 @@ struct PlayerStreamState {
 async fn player(
     State(openings): State<&'static Openings>,
     State(db): State<Arc<Database>>,
-    State(indexer): State<IndexerStub>,
+    State(indexer): State<Option<IndexerStub>>,
+    State(primary): State<Option<Primary>>,
+    State(reads): State<ReadLimiter>,
+    RawQuery(raw_query): RawQuery,
     Query(query): Query<PlayerQuery>,
-) -> Result<NdJson<impl Stream<Item = ExplorerResponse>>, Error> {
+) -> Result<Response, Error> {
     let player = UserId::from(query.player);
-    let indexing = indexer.index_player(&player).await;
+    let indexing = match indexer {
+        Some(indexer) => indexer.index_player(&player).await,
+        None => {
+            // Read-only replica. Serve players that have been indexed by
+            // the primary, possibly slightly outdated.
+            let indexed = {
+                let db = Arc::clone(&db);
+                let player = player.clone();
+                reads
+                    .spawn_blocking(move || {
+                        db.lichess()
+                            .player_status(&player)
+                            .expect("get player status")
+                            .is_some()
+                    })
+                    .await
+                    .expect("blocking player status")
+            };
+            if !indexed {
+                return match primary {
+                    Some(primary) => Ok(primary.forward("/player", raw_query).await),
+                    None => Err(Error::PlayerNotIndexed {
+                        player: player.as_lowercase_str().to_owned(),
+                    }),
+                };
+            }
+            None
+        }
+    };
     let PlayPosition { pos, opening } = query.play.position(openings)?;
-    let key = KeyBuilder::player(&player, query.color)
-        .with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
+    let hash = KeyBuilder::player(&player, query.color);
+    let key = hash.with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
+    let check = hash.check(&pos);
 
     let state = PlayerStreamState {
         color: query.color,
         filter: query.filter,
         limits: query.limits,
+        breakdowns: query.breakdowns,
         db,
+        reads,
         indexing,
         opening,
+        player,
         key,
+        check,
         pos,
         first: true,
         done: false,
@@ -340,12 +765,22 @@ async fn player(
                 None => true,
             };
 
-            task::spawn_blocking(move || {
+            let reads = state.reads.clone();
+            reads.spawn_blocking(move || {
                 let lichess_db = state.db.lichess();
+                let checked = lichess_db
+                    .player_status(&state.player)
+                    .expect("get player status")
+                    .map_or(true, |status| !status.key_scheme.reads_legacy());
                 let filtered = lichess_db
-                    .read_player(&state.key, state.filter.since, state.filter.until)
+                    .read_player(
+                        &state.key,
+                        checked.then_some(&state.check),
+                        state.filter.since,
+                        state.filter.until,
+                    )
                     .expect("read player")
-                    .prepare(state.color, &state.filter, &state.limits);
+                    .prepare(state.color, &state.filter, &state.limits, &state.breakdowns);
 
                 Some((
                     ExplorerResponse {
@@ -354,107 +789,388 @@ async fn player(
                         recent_games: Some(finalize_lichess_games(filtered.recent_games, &lichess_db)),
                         top_games: None,
                         opening: state.opening,
+                        rating_range: None,
                     },
                     state,
                 ))
             }).await.expect("blocking player")
         },
-    ).dedup_by_key(|res| res.total.total())))
+    ).dedup_by_key(|res| res.total.total())).into_response())
 }
 
 async fn masters_import(
     State(importer): State<MastersImporter>,
+    State(policy): State<Arc<MastersPolicyOpt>>,
     Json(body): Json<MastersGameWithId>,
 ) -> Result<(), Error> {
-    task::spawn_blocking(move || importer.import(body))
+    policy.check(&body)?;
+    task::spawn_blocking(move || importer.insert(body))
         .await
         .expect("blocking masters import")
 }
 
 #[serde_as]
 #[derive(Deserialize)]
-struct MastersGameId(#[serde_as(as = "DisplayFromStr")] GameId);
+struct PathGameId(#[serde_as(as = "DisplayFromStr")] GameId);
 
 async fn masters_pgn(
-    Path(MastersGameId(id)): Path<MastersGameId>,
+    Path(PathGameId(id)): Path<PathGameId>,
     State(db): State<Arc<Database>>,
+    State(reads): State<ReadLimiter>,
 ) -> Result<MastersGame, StatusCode> {
-    task::spawn_blocking(
-        move || match db.masters().game(id).expect("get masters game") {
-            Some(game) => Ok(game),
-            None => Err(StatusCode::NOT_FOUND),
-        },
-    )
-    .await
-    .expect("blocking masters pgn")
+    reads
+        .spawn_blocking(
+            move || match db.masters().game(id).expect("get masters game") {
+                Some(game) => Ok(game),
+                None => Err(StatusCode::NOT_FOUND),
+            },
+        )
+        .await
+        .expect("blocking masters pgn")
+}
+
+async fn lichess_pgn(
+    Path(PathGameId(id)): Path<PathGameId>,
+    State(db): State<Arc<Database>>,
+    State(reads): State<ReadLimiter>,
+) -> Result<Response, StatusCode> {
+    reads
+        .spawn_blocking(move || {
+            let lichess_db = db.lichess();
+            let info = lichess_db.game(id).expect("get lichess game");
+            let moves = lichess_db.game_moves(id).expect("get lichess game moves");
+            let (info, moves) = match (info, moves) {
+                (Some(info), Some(Ok(moves))) => (info, moves),
+                (Some(_), Some(Err(err))) => {
+                    log::error!("corrupted moves of lichess game {}: {}", id, err);
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                }
+                _ => return Err(StatusCode::NOT_FOUND),
+            };
+            let mut buf = Vec::new();
+            if let Err(err) = moves.write_pgn(&mut buf, id, &info) {
+                log::error!("invalid moves of lichess game {}: {}", id, err);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            Ok((
+                [(axum::http::header::CONTENT_TYPE, "application/x-chess-pgn")],
+                buf,
+            )
+                .into_response())
+        })
+        .await
+        .expect("blocking lichess pgn")
+}
+
+async fn masters_position_pgn(
+    State(openings): State<&'static Openings>,
+    State(db): State<Arc<Database>>,
+    State(reads): State<ReadLimiter>,
+    Query(query): Query<MastersQuery>,
+    Query(limit): Query<PgnLimit>,
+) -> Result<PgnStream<impl Stream<Item = String>>, Error> {
+    let PlayPosition { pos, .. } = query.play.position(openings)?;
+    let key =
+        KeyBuilder::masters().with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
+
+    Ok(stream_masters_pgn(openings, db, reads, move |db| {
+        let masters_db = db.masters();
+        let mut ids: Vec<GameId> = masters_db
+            .read(key, query.since, query.until)
+            .expect("get masters")
+            .game_ids()
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+
+        let mut games: Vec<(GameId, MastersGame)> = ids
+            .iter()
+            .copied()
+            .zip(
+                masters_db
+                    .games(ids.iter().copied())
+                    .expect("get masters games"),
+            )
+            .filter_map(|(id, game)| game.map(|game| (id, game)))
+            .collect();
+        games.sort_by_key(|(_, game)| Reverse(game.date.to_sort_bytes()));
+        games.truncate(limit.max());
+        games.into_iter().map(|(id, _)| id).collect()
+    }))
+}
+
+async fn masters_games_pgn(
+    State(openings): State<&'static Openings>,
+    State(db): State<Arc<Database>>,
+    State(reads): State<ReadLimiter>,
+    Query(query): Query<MastersSearchQuery>,
+    Query(limit): Query<PgnLimit>,
+) -> Result<PgnStream<impl Stream<Item = String>>, Error> {
+    query.check_terms()?;
+
+    Ok(stream_masters_pgn(openings, db, reads, move |db| {
+        let max = limit.max();
+        let mut ids = Vec::new();
+        if max > 0 {
+            db.masters()
+                .search_each(&query, |id, _| {
+                    ids.push(id);
+                    ids.len() < max
+                })
+                .expect("search masters games");
+        }
+        ids
+    }))
+}
+
+/// Streams the games with the ids returned by `collect`. Games are read and
+/// formatted in batches, each on the blocking read pool, so that slow
+/// clients do not hold on to a read permit.
+fn stream_masters_pgn<F>(
+    openings: &'static Openings,
+    db: Arc<Database>,
+    reads: ReadLimiter,
+    collect: F,
+) -> PgnStream<impl Stream<Item = String>>
+where
+    F: FnOnce(&Database) -> Vec<GameId> + Send + 'static,
+{
+    const BATCH_SIZE: usize = 64;
+
+    let (tx, rx) = mpsc::channel(BATCH_SIZE);
+    tokio::spawn(async move {
+        let ids = {
+            let db = Arc::clone(&db);
+            reads
+                .spawn_blocking(move || collect(&db))
+                .await
+                .expect("blocking masters pgn search")
+        };
+
+        for batch in ids.chunks(BATCH_SIZE) {
+            let batch = batch.to_vec();
+            let db = Arc::clone(&db);
+            let pgns = reads
+                .spawn_blocking(move || {
+                    let games = db
+                        .masters()
+                        .games(batch.iter().copied())
+                        .expect("get masters games");
+                    batch
+                        .into_iter()
+                        .zip(games)
+                        .filter_map(|(id, game)| {
+                            let mut buf = Vec::new();
+                            match game?.write_classified_pgn(&mut buf, openings) {
+                                Ok(()) => Some(String::from_utf8(buf).expect("utf-8 pgn")),
+                                Err(err) => {
+                                    log::warn!(
+                                        "skipping masters game {} in pgn export: {}",
+                                        id,
+                                        err
+                                    );
+                                    None
+                                }
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .await
+                .expect("blocking masters pgn export");
+
+            for pgn in pgns {
+                if tx.send(pgn).await.is_err() {
+                    return; // Client is gone.
+                }
+            }
+        }
+    });
+
+    PgnStream(futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|pgn| (pgn, rx))
+    }))
+}
+
+async fn masters_games(
+    State(db): State<Arc<Database>>,
+    State(reads): State<ReadLimiter>,
+    Query(query): Query<MastersSearchQuery>,
+) -> Result<Json<MastersSearchResponse>, Error> {
+    query.check_page()?;
+    query.check_terms()?;
+
+    Ok(reads
+        .spawn_blocking(move || {
+            let (games, more, truncated) =
+                db.masters().search(&query).expect("search masters games");
+            Json(MastersSearchResponse {
+                page: query.page,
+                next_page: (more && query.page < query.last_page()).then_some(query.page + 1),
+                truncated,
+                games: games
+                    .into_iter()
+                    .map(|(id, game)| MastersSearchRow::new(id, game))
+                    .collect(),
+            })
+        })
+        .await
+        .expect("blocking masters games"))
 }
 
 async fn masters(
     State(openings): State<&'static Openings>,
     State(db): State<Arc<Database>>,
     State(masters_cache): State<ExplorerCache<MastersQuery>>,
+    State(reads): State<ReadLimiter>,
     Query(query): Query<MastersQuery>,
 ) -> Result<Json<ExplorerResponse>, Error> {
     masters_cache
         .get_with(query.clone(), async move {
-            task::spawn_blocking(move || {
-                let PlayPosition { pos, opening } = query.play.position(openings)?;
-                let key = KeyBuilder::masters()
-                    .with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
-                let masters_db = db.masters();
-                let entry = masters_db
-                    .read(key, query.since, query.until)
-                    .expect("get masters")
-                    .prepare(&query.limits);
-
-                Ok(Json(ExplorerResponse {
-                    total: entry.total,
-                    moves: entry
-                        .moves
-                        .into_iter()
-                        .map(|p| ExplorerMove {
-                            san: p.uci.to_move(&pos).map_or(
-                                SanPlus {
-                                    san: San::Null,
-                                    suffix: None,
-                                },
-                                |m| SanPlus::from_move(pos.clone(), &m),
-                            ),
-                            uci: p.uci,
-                            average_rating: p.average_rating,
-                            average_opponent_rating: p.average_opponent_rating,
-                            performance: p.performance,
-                            stats: p.stats,
-                            game: p.game.and_then(|id| {
-                                masters_db
-                                    .game(id)
-                                    .expect("get masters game")
-                                    .map(|info| ExplorerGame::from_masters(id, info))
-                            }),
-                        })
-                        .collect(),
-                    top_games: Some(
-                        masters_db
-                            .games(entry.top_games.iter().map(|(_, id)| *id))
-                            .expect("get masters games")
-                            .into_iter()
-                            .zip(entry.top_games.into_iter())
-                            .filter_map(|(info, (uci, id))| {
-                                info.map(|info| ExplorerGameWithUci {
-                                    uci: uci.clone(),
-                                    row: ExplorerGame::from_masters(id, info),
-                                })
-                            })
-                            .collect(),
-                    ),
-                    opening,
-                    recent_games: None,
-                }))
+            reads
+                .spawn_blocking(move || {
+                    let PlayPosition { pos, opening } = query.play.position(openings)?;
+                    let key = KeyBuilder::masters()
+                        .with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
+                    let masters_db = db.masters();
+                    let entry = masters_db
+                        .read(key, query.since, query.until)
+                        .expect("get masters")
+                        .prepare(&query.limits);
+
+                    Ok(Json(ExplorerResponse {
+                        total: entry.total,
+                        moves: finalize_masters_moves(entry.moves, &pos, &masters_db),
+                        top_games: Some(finalize_masters_games(entry.top_games, &masters_db)),
+                        opening,
+                        recent_games: None,
+                        rating_range: None,
+                    }))
+                })
+                .await
+                .expect("blocking masters")
+        })
+        .await
+}
+
+async fn masters_events(
+    State(db): State<Arc<Database>>,
+    State(events_cache): State<EventsCache>,
+    State(reads): State<ReadLimiter>,
+    Query(query): Query<MastersEventsQuery>,
+) -> Result<Json<MastersEventsResponse>, Error> {
+    query.check_page()?;
+
+    let events = events_cache
+        .get_with((), {
+            let reads = reads.clone();
+            async move {
+                reads
+                    .spawn_blocking(move || {
+                        Arc::new(db.masters().events().expect("list masters events"))
+                    })
+                    .await
+                    .expect("blocking list masters events")
+            }
+        })
+        .await;
+
+    Ok(reads
+        .spawn_blocking(move || {
+            let (events, more) = query.select(&events);
+            Json(MastersEventsResponse {
+                page: query.page,
+                next_page: (more && query.page < query.last_page()).then_some(query.page + 1),
+                events: events
+                    .into_iter()
+                    .map(|(id, event)| MastersEventRow::new(id, event))
+                    .collect(),
             })
-            .await
-            .expect("blocking masters")
         })
         .await
+        .expect("blocking masters events"))
+}
+
+async fn masters_event(
+    State(openings): State<&'static Openings>,
+    State(db): State<Arc<Database>>,
+    State(reads): State<ReadLimiter>,
+    Query(query): Query<MastersEventQuery>,
+) -> Result<Json<ExplorerResponse>, Error> {
+    if query.events.len() > MastersEventQuery::MAX_EVENTS {
+        return Err(Error::TooManyEvents {
+            max: MastersEventQuery::MAX_EVENTS,
+        });
+    }
+
+    reads
+        .spawn_blocking(move || {
+            let PlayPosition { pos, opening } = query.play.position(openings)?;
+            let zobrist = pos.zobrist_hash(EnPassantMode::Legal);
+            let keys: Vec<(KeyPrefix, KeyCheck)> = query
+                .events
+                .iter()
+                .map(|id| {
+                    let hash = KeyBuilder::masters_event(*id);
+                    (hash.with_zobrist(pos.variant(), zobrist), hash.check(&pos))
+                })
+                .collect();
+            let masters_db = db.masters();
+            let entry = masters_db
+                .read_by_event(&keys, query.since, query.until)
+                .expect("read masters by event")
+                .prepare(&query.limits);
+
+            Ok(Json(ExplorerResponse {
+                total: entry.total,
+                moves: finalize_masters_moves(entry.moves, &pos, &masters_db),
+                top_games: Some(finalize_masters_games(entry.top_games, &masters_db)),
+                opening,
+                recent_games: None,
+                rating_range: None,
+            }))
+        })
+        .await
+        .expect("blocking masters event")
+}
+
+async fn masters_player(
+    State(openings): State<&'static Openings>,
+    State(db): State<Arc<Database>>,
+    State(reads): State<ReadLimiter>,
+    Query(query): Query<MastersPlayerQuery>,
+) -> Result<Json<ExplorerResponse>, Error> {
+    reads
+        .spawn_blocking(move || {
+            let PlayPosition { pos, opening } = query.play.position(openings)?;
+            let hash = KeyBuilder::masters_player(&query.player, query.color);
+            let key = hash.with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
+            let masters_db = db.masters();
+            let entry = masters_db
+                .read_player(&key, &hash.check(&pos), query.since, query.until)
+                .expect("read masters player")
+                .prepare(
+                    query.color,
+                    &PlayerQueryFilter {
+                        modes: None,
+                        speeds: None,
+                        since: Month::min_value(),
+                        until: Month::max_value(),
+                    },
+                    &query.limits,
+                    &Breakdowns::default(),
+                );
+
+            Ok(Json(ExplorerResponse {
+                total: entry.total,
+                moves: finalize_masters_moves(entry.moves, &pos, &masters_db),
+                recent_games: Some(finalize_masters_games(entry.recent_games, &masters_db)),
+                top_games: None,
+                opening,
+                rating_range: None,
+            }))
+        })
+        .await
+        .expect("blocking masters player")
 }
 
 async fn lichess_import(
@@ -470,30 +1186,50 @@ async fn lichess(
     State(openings): State<&'static Openings>,
     State(db): State<Arc<Database>>,
     State(lichess_cache): State<ExplorerCache<LichessQuery>>,
+    State(reads): State<ReadLimiter>,
     Query(query): Query<LichessQuery>,
 ) -> Result<Json<ExplorerResponse>, Error> {
     lichess_cache
         .get_with(query.clone(), async move {
-            task::spawn_blocking(move || {
-                let PlayPosition { pos, opening } = query.play.position(openings)?;
-                let key = KeyBuilder::lichess()
-                    .with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
-                let lichess_db = db.lichess();
-                let filtered = lichess_db
-                    .read_lichess(&key, query.filter.since, query.filter.until)
-                    .expect("get lichess")
-                    .prepare(&query.filter, &query.limits);
-
-                Ok(Json(ExplorerResponse {
-                    total: filtered.total,
-                    moves: finalize_lichess_moves(filtered.moves, &pos, &lichess_db),
-                    recent_games: Some(finalize_lichess_games(filtered.recent_games, &lichess_db)),
-                    top_games: Some(finalize_lichess_games(filtered.top_games, &lichess_db)),
-                    opening,
-                }))
-            })
-            .await
-            .expect("blocking lichess")
+            reads
+                .spawn_blocking(move || {
+                    let PlayPosition { pos, opening } = query.play.position(openings)?;
+                    let key = KeyBuilder::lichess()
+                        .with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
+                    let rating_range = query.filter.rating_range()?;
+                    let lichess_db = db.lichess();
+                    let filtered = if query.matchup.any() {
+                        if query.filter.has_rating_filter() {
+                            return Err(Error::ConflictingRatingFilters);
+                        }
+                        if query.breakdowns.any() {
+                            return Err(Error::ConflictingBreakdown);
+                        }
+                        lichess_db
+                            .read_lichess_matchup(&key, query.filter.since, query.filter.until)
+                            .expect("get lichess matchup")
+                            .prepare(&query.filter, &query.matchup, &query.limits)
+                    } else {
+                        lichess_db
+                            .read_lichess(&key, query.filter.since, query.filter.until)
+                            .expect("get lichess")
+                            .prepare(&query.filter, &query.limits, &query.breakdowns)
+                    };
+
+                    Ok(Json(ExplorerResponse {
+                        total: filtered.total,
+                        moves: finalize_lichess_moves(filtered.moves, &pos, &lichess_db),
+                        recent_games: Some(finalize_lichess_games(
+                            filtered.recent_games,
+                            &lichess_db,
+                        )),
+                        top_games: Some(finalize_lichess_games(filtered.top_games, &lichess_db)),
+                        opening,
+                        rating_range,
+                    }))
+                })
+                .await
+                .expect("blocking lichess")
         })
         .await
 }
@@ -501,20 +1237,24 @@ async fn lichess(
 async fn lichess_history(
     State(openings): State<&'static Openings>,
     State(db): State<Arc<Database>>,
+    State(reads): State<ReadLimiter>,
     Query(query): Query<LichessHistoryQuery>,
 ) -> Result<Json<ExplorerHistoryResponse>, Error> {
-    task::spawn_blocking(move || {
-        let PlayPosition { pos, opening } = query.play.position(openings)?;
-        let key = KeyBuilder::lichess()
-            .with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
-        let lichess_db = db.lichess();
-        Ok(Json(ExplorerHistoryResponse {
-            history: lichess_db
-                .read_lichess_history(&key, &query.filter)
-                .expect("get lichess history"),
-            opening,
-        }))
-    })
-    .await
-    .expect("blocking lichess history")
+    reads
+        .spawn_blocking(move || {
+            let PlayPosition { pos, opening } = query.play.position(openings)?;
+            let key = KeyBuilder::lichess()
+                .with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
+            let rating_range = query.filter.rating_range()?;
+            let lichess_db = db.lichess();
+            Ok(Json(ExplorerHistoryResponse {
+                history: lichess_db
+                    .read_lichess_history(&key, &query.filter)
+                    .expect("get lichess history"),
+                opening,
+                rating_range,
+            }))
+        })
+        .await
+        .expect("blocking lichess history")
 }
@@ -0,0 +1,168 @@
+use std::sync::Arc;
+
+use rocksdb::{WriteBatch, DB};
+
+use crate::{
+    api::Error,
+    db::Database,
+    importer::MastersImporter,
+    model::{GameId, LichessGame, MastersGame, MastersGameWithId},
+};
+
+const BATCH_SIZE: usize = 10_000;
+
+#[derive(Default, Debug)]
+pub struct MergeReport {
+    pub lichess_entries: u64,
+    pub player_entries: u64,
+    pub lichess_games: u64,
+    /// Games indexed into the lichess explorer in both databases. Their
+    /// moves are now counted twice.
+    pub lichess_games_overlapping: u64,
+    /// Games indexed for the same player in both databases. Their moves are
+    /// now counted twice.
+    pub player_games_overlapping: u64,
+    pub player_statuses: u64,
+    pub masters_games: u64,
+    pub masters_games_identical: u64,
+    /// Masters games with the same id but different contents, or with
+    /// another id but the same players, date and moves, as already present
+    /// in the target.
+    pub masters_games_conflicting: u64,
+}
+
+/// Merges the `source` database into `target`.
+///
+/// Lichess and player entries are fed through the merge operators, and game
+/// infos are merged with their index status OR-ed. Player statuses are
+/// copied unless already present in the target.
+///
+/// Masters entries aggregate many games and can not be separated, so masters
+/// games not yet present in the target are imported one by one. Identical
+/// games are skipped, and conflicting games are reported.
+///
+/// Must not run concurrently with other writers.
+pub fn run(source: &Database, target: &Arc<Database>) -> Result<MergeReport, rocksdb::Error> {
+    let mut report = MergeReport::default();
+
+    log::info!("merging lichess_game ...");
+    for_each(source, "lichess_game", |key, value| {
+        report.lichess_games += 1;
+        if let Some(existing) = target
+            .inner
+            .get_pinned_cf(cf(&target.inner, "lichess_game"), key)?
+        {
+            let existing = LichessGame::read(&mut &existing[..]);
+            let new = LichessGame::read(&mut &value[..]);
+            if existing.indexed_lichess && new.indexed_lichess {
+                report.lichess_games_overlapping += 1;
+            }
+            if (existing.indexed_player.white && new.indexed_player.white)
+                || (existing.indexed_player.black && new.indexed_player.black)
+                || (existing.hardened_player.white && new.hardened_player.white)
+                || (existing.hardened_player.black && new.hardened_player.black)
+            {
+                report.player_games_overlapping += 1;
+            }
+        }
+        Ok(())
+    })?;
+    merge_column(source, target, "lichess_game")?;
+
+    log::info!("merging lichess_game_moves ...");
+    let cf_target = cf(&target.inner, "lichess_game_moves");
+    for_each(source, "lichess_game_moves", |key, value| {
+        if target.inner.get_pinned_cf(cf_target, key)?.is_none() {
+            target.inner.put_cf(cf_target, key, value)?;
+        }
+        Ok(())
+    })?;
+
+    log::info!("merging lichess ...");
+    report.lichess_entries = merge_column(source, target, "lichess")?;
+
+    log::info!("merging lichess_matchup ...");
+    merge_column(source, target, "lichess_matchup")?;
+
+    log::info!("merging player ...");
+    report.player_entries = merge_column(source, target, "player")?;
+
+    log::info!("merging player_status ...");
+    let cf_target = cf(&target.inner, "player_status");
+    for_each(source, "player_status", |key, value| {
+        if target.inner.get_pinned_cf(cf_target, key)?.is_none() {
+            target.inner.put_cf(cf_target, key, value)?;
+            report.player_statuses += 1;
+        }
+        Ok(())
+    })?;
+
+    log::info!("merging masters ...");
+    let importer = MastersImporter::new(Arc::clone(target));
+    let cf_target = cf(&target.inner, "masters_game");
+    for_each(source, "masters_game", |key, value| {
+        let id = GameId::read(&mut &key[..]);
+        if let Some(existing) = target.inner.get_pinned_cf(cf_target, key)? {
+            if existing.as_ref() == value {
+                report.masters_games_identical += 1;
+            } else {
+                log::warn!("conflicting masters game {}: same id, different game", id);
+                report.masters_games_conflicting += 1;
+            }
+            return Ok(());
+        }
+
+        let game: MastersGame = serde_json::from_slice(value).expect("deserialize masters game");
+        match importer.insert(MastersGameWithId { id, game }) {
+            Ok(()) => report.masters_games += 1,
+            Err(Error::DuplicateGame { existing, .. }) => {
+                log::warn!(
+                    "conflicting masters game {}: same game as existing {}",
+                    id,
+                    existing
+                );
+                report.masters_games_conflicting += 1;
+            }
+            Err(err) => {
+                log::warn!("conflicting masters game {}: {}", id, err);
+                report.masters_games_conflicting += 1;
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(report)
+}
+
+fn cf<'a>(db: &'a DB, name: &str) -> &'a rocksdb::ColumnFamily {
+    db.cf_handle(name).expect("cf")
+}
+
+fn for_each<F>(source: &Database, name: &str, mut f: F) -> Result<(), rocksdb::Error>
+where
+    F: FnMut(&[u8], &[u8]) -> Result<(), rocksdb::Error>,
+{
+    let mut iter = source.inner.raw_iterator_cf(cf(&source.inner, name));
+    iter.seek_to_first();
+    while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+        f(key, value)?;
+        iter.next();
+    }
+    iter.status()
+}
+
+fn merge_column(source: &Database, target: &Database, name: &str) -> Result<u64, rocksdb::Error> {
+    let cf_target = cf(&target.inner, name);
+    let mut batch = WriteBatch::default();
+    let mut records = 0;
+    for_each(source, name, |key, value| {
+        batch.merge_cf(cf_target, key, value);
+        records += 1;
+        if batch.len() >= BATCH_SIZE {
+            target.inner.write(std::mem::take(&mut batch))?;
+        }
+        Ok(())
+    })?;
+    target.inner.write(batch)?;
+    Ok(records)
+}
@@ -0,0 +1,138 @@
+use crate::{db::Database, importer::MastersImporter};
+
+/// Version of the on-disk format written by this build. Bump it whenever the
+/// layout of any record changes, and register a migration below.
+pub const FORMAT_VERSION: u32 = 5;
+
+/// Masters imports rely on the duplicate index, and must not race the
+/// backfill of the other masters indexes, all of which are complete from
+/// this version on.
+pub const MASTERS_INDEX_FORMAT: u32 = 5;
+
+/// Databases created before versioning was introduced have this format.
+pub const UNVERSIONED_FORMAT: u32 = 1;
+
+struct Migration {
+    /// Format version after running this migration.
+    to: u32,
+    description: &'static str,
+    /// Rewrites or backfills records. Runs in the background, while the
+    /// server is already serving requests, so readers must also accept
+    /// records in the previous format until the migration is complete.
+    run: fn(&Database) -> Result<(), rocksdb::Error>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        to: 2,
+        description: "masters duplicate index",
+        run: masters_dedup,
+    },
+    Migration {
+        to: 3,
+        description: "masters search index",
+        run: masters_search,
+    },
+    Migration {
+        to: 4,
+        description: "masters player index",
+        run: masters_player,
+    },
+    Migration {
+        to: MASTERS_INDEX_FORMAT,
+        description: "masters event index",
+        run: masters_event,
+    },
+];
+
+fn masters_dedup(db: &Database) -> Result<(), rocksdb::Error> {
+    let games = db.masters().backfill_dedup()?;
+    log::info!("indexed {} masters games for duplicate detection", games);
+    Ok(())
+}
+
+fn masters_search(db: &Database) -> Result<(), rocksdb::Error> {
+    let games = db.masters().backfill_search()?;
+    log::info!("indexed {} masters games for search", games);
+    Ok(())
+}
+
+fn masters_player(db: &Database) -> Result<(), rocksdb::Error> {
+    let games =
+        db.masters()
+            .backfill_players(|id, game| match MastersImporter::prepare(id, game) {
+                Ok(prepared) => prepared.player_entries,
+                Err(err) => {
+                    log::warn!("skipping masters game {}: {}", id, err);
+                    Vec::new()
+                }
+            })?;
+    log::info!("indexed {} masters games by player", games);
+    Ok(())
+}
+
+fn masters_event(db: &Database) -> Result<(), rocksdb::Error> {
+    let games =
+        db.masters()
+            .backfill_events(|id, game| match MastersImporter::prepare(id, game) {
+                Ok(prepared) => prepared.event_entries,
+                Err(err) => {
+                    log::warn!("skipping masters game {}: {}", id, err);
+                    Vec::new()
+                }
+            })?;
+    log::info!("indexed {} masters games by event", games);
+    Ok(())
+}
+
+/// Runs the migrations after format `after`, up to and including format
+/// `up_to`, without recording progress. For example, to build indexes for
+/// records loaded from an older dump into a database that already has a
+/// newer format.
+pub fn rerun(db: &Database, after: u32, up_to: u32) -> Result<(), rocksdb::Error> {
+    for migration in MIGRATIONS {
+        if after < migration.to && migration.to <= up_to {
+            log::info!(
+                "rerunning migration to format {}: {} ...",
+                migration.to,
+                migration.description
+            );
+            (migration.run)(db)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn pending(db: &Database) -> Result<bool, rocksdb::Error> {
+    Ok(db.format_version()? < FORMAT_VERSION)
+}
+
+/// Runs all pending migrations in order. Progress is recorded after each
+/// migration, so an interrupted run continues with the first incomplete
+/// migration.
+pub fn run_pending(db: &Database) -> Result<(), rocksdb::Error> {
+    let mut version = db.format_version()?;
+
+    for migration in MIGRATIONS {
+        if migration.to <= version {
+            continue;
+        }
+
+        log::info!(
+            "migrating database format {} -> {}: {} ...",
+            version,
+            migration.to,
+            migration.description
+        );
+        (migration.run)(db)?;
+        db.put_format_version(migration.to)?;
+        version = migration.to;
+        log::info!("migrated database to format {}", version);
+    }
+
+    if version < FORMAT_VERSION {
+        db.put_format_version(FORMAT_VERSION)?;
+    }
+
+    Ok(())
+}
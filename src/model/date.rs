@@ -1,7 +1,7 @@
 use std::{cmp::min, convert::TryFrom, fmt, str::FromStr};
 
 use thiserror::Error;
-use time::PrimitiveDateTime;
+use time::{OffsetDateTime, PrimitiveDateTime};
 
 #[derive(Error, Debug)]
 pub enum InvalidDate {
@@ -27,6 +27,21 @@ impl LaxDate {
         self.month
             .map(|m| Month(self.year.0 * 12 + u16::from(m) - 1))
     }
+
+    /// Big endian encoding that sorts chronologically, with unknown months
+    /// and days first.
+    pub fn to_sort_bytes(self) -> [u8; 4] {
+        let [hi, lo] = self.year.0.to_be_bytes();
+        [hi, lo, self.month.unwrap_or(0), self.day.unwrap_or(0)]
+    }
+
+    pub fn from_sort_bytes(buf: [u8; 4]) -> Result<LaxDate, InvalidDate> {
+        Ok(LaxDate {
+            year: Year::try_from(u16::from_be_bytes([buf[0], buf[1]]))?,
+            month: Some(buf[2]).filter(|m| 1 <= *m && *m <= 12),
+            day: Some(buf[3]).filter(|d| *d != 0),
+        })
+    }
 }
 
 impl FromStr for LaxDate {
@@ -80,8 +95,12 @@ impl Year {
         Year(MAX_YEAR)
     }
 
-    pub fn max_masters() -> Year {
-        Year(2022)
+    pub fn current() -> Year {
+        Year(
+            OffsetDateTime::now_utc()
+                .year()
+                .clamp(MIN_YEAR as i32, MAX_YEAR as i32) as u16,
+        )
     }
 
     #[must_use]
@@ -2,9 +2,14 @@ use std::array::TryFromSliceError;
 
 use bytes::{Buf, BufMut};
 use sha1::{Digest, Sha1};
-use shakmaty::{variant::Variant, zobrist::Zobrist128, Color};
+use shakmaty::{
+    fen::Epd,
+    variant::{Variant, VariantPosition},
+    zobrist::{Zobrist128, ZobristHash},
+    Color, EnPassantMode,
+};
 
-use crate::model::{InvalidDate, Month, UserId, Year};
+use crate::model::{tokenize, InvalidDate, MastersEventId, Month, UserId, Year};
 
 #[derive(Debug)]
 pub struct KeyBuilder {
@@ -22,6 +27,28 @@ impl KeyBuilder {
         }
     }
 
+    /// Masters player names are normalized to their sorted words, so that
+    /// `Carlsen, Magnus` and `Magnus Carlsen` are the same player.
+    pub fn masters_player(name: &str, color: Color) -> KeyBuilder {
+        let mut hash = Sha1::new();
+        hash.update([color.char() as u8]);
+        hash.update(tokenize(name).join(" "));
+        let buf = hash.finalize();
+        KeyBuilder {
+            base: (&mut buf.as_slice()).get_u128_le(),
+        }
+    }
+
+    pub fn masters_event(id: MastersEventId) -> KeyBuilder {
+        let mut hash = Sha1::new();
+        hash.update(b"event");
+        hash.update(id.to_bytes());
+        let buf = hash.finalize();
+        KeyBuilder {
+            base: (&mut buf.as_slice()).get_u128_le(),
+        }
+    }
+
     pub fn masters() -> KeyBuilder {
         KeyBuilder { base: 0 }
     }
@@ -33,10 +60,8 @@ impl KeyBuilder {
     pub fn with_zobrist(&self, variant: Variant, zobrist: Zobrist128) -> KeyPrefix {
         // Zobrist hashes are the opposite of cryptographically secure. An
         // attacker could efficiently construct a position such that a record
-        // will appear in the opening explorer of another player. This is not
-        // completely trivial, and theres very little incentive, so we will
-        // switch to a more expensive hash function only once required,
-        // and then also stop using SHA1 in with_user_pov().
+        // will appear in the opening explorer of another player. Player keys
+        // are therefore suffixed with a KeyCheck, see with_check().
         KeyPrefix {
             prefix: (self.base
                 ^ u128::from(zobrist)
@@ -53,6 +78,20 @@ impl KeyBuilder {
             .to_le_bytes(),
         }
     }
+
+    /// Secondary hash over the full position, to detect records of other
+    /// positions with colliding Zobrist hashes. Unlike the Zobrist hash, it
+    /// can not be efficiently steered by choosing moves.
+    pub fn check(&self, pos: &VariantPosition) -> KeyCheck {
+        let prefix = self.with_zobrist(pos.variant(), pos.zobrist_hash(EnPassantMode::Legal));
+        let mut hash = Sha1::new();
+        hash.update(prefix.prefix);
+        hash.update(Epd::from_position(pos.clone(), EnPassantMode::Legal).to_string());
+        let buf = hash.finalize();
+        let mut check = [0; KeyCheck::SIZE];
+        check.clone_from_slice(&buf[..KeyCheck::SIZE]);
+        KeyCheck(check)
+    }
 }
 
 #[derive(Debug)]
@@ -63,6 +102,12 @@ pub struct KeyPrefix {
 impl KeyPrefix {
     pub const SIZE: usize = 12;
 
+    pub fn to_bytes(&self) -> [u8; KeyPrefix::SIZE] {
+        let mut buf = [0; KeyPrefix::SIZE];
+        buf.clone_from_slice(&self.prefix[..KeyPrefix::SIZE]);
+        buf
+    }
+
     pub fn with_month(&self, month: Month) -> Key {
         let mut buf = [0; Key::SIZE];
         buf[..KeyPrefix::SIZE].clone_from_slice(&self.prefix[..KeyPrefix::SIZE]);
@@ -91,6 +136,46 @@ impl Key {
     pub fn month(&self) -> Result<Month, InvalidDate> {
         (&mut &self.0[KeyPrefix::SIZE..]).get_u16().try_into()
     }
+
+    /// Masters keys are suffixed with a year instead of a month.
+    pub fn year(&self) -> Result<Year, InvalidDate> {
+        (&mut &self.0[KeyPrefix::SIZE..]).get_u16().try_into()
+    }
+}
+
+impl Key {
+    pub fn with_check(&self, check: &KeyCheck) -> CheckedKey {
+        let mut buf = [0; CheckedKey::SIZE];
+        buf[..Key::SIZE].clone_from_slice(&self.0);
+        buf[Key::SIZE..].clone_from_slice(&check.0);
+        CheckedKey(buf)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct KeyCheck([u8; KeyCheck::SIZE]);
+
+impl KeyCheck {
+    pub const SIZE: usize = 8;
+
+    /// Checks the suffix of a player key. Legacy keys without suffix never
+    /// match.
+    pub fn matches(&self, key: &[u8]) -> bool {
+        key.len() == CheckedKey::SIZE && key[Key::SIZE..] == self.0
+    }
+}
+
+/// Player key suffixed with a `KeyCheck`. Sorts directly after the `Key`
+/// it extends, so that legacy and checked keys can be iterated together.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CheckedKey([u8; CheckedKey::SIZE]);
+
+impl CheckedKey {
+    pub const SIZE: usize = Key::SIZE + KeyCheck::SIZE;
+
+    pub fn into_bytes(self) -> [u8; Self::SIZE] {
+        self.0
+    }
 }
 
 impl TryFrom<&'_ [u8]> for Key {
@@ -117,5 +202,46 @@ mod tests {
 
             (a <= b) == (prefix.with_month(a).into_bytes() <= prefix.with_month(b).into_bytes())
         }
+
+        fn test_checked_key_order(a: Month, b: Month) -> bool {
+            let user_id = UserId::from("blindfoldpig".parse::<UserName>().unwrap());
+            let builder = KeyBuilder::player(&user_id, Color::White);
+            let pos = VariantPosition::new(Variant::Chess);
+            let prefix = builder.with_zobrist(Variant::Chess, pos.zobrist_hash(EnPassantMode::Legal));
+            let check = builder.check(&pos);
+
+            let legacy = prefix.with_month(a).into_bytes();
+            let checked = prefix.with_month(a).with_check(&check).into_bytes();
+            let next = prefix.with_month(b).into_bytes();
+            legacy[..] < checked[..] && (a < b) == (checked[..] < next[..])
+        }
+    }
+
+    #[test]
+    fn test_masters_player_normalized() {
+        let zobrist = VariantPosition::new(Variant::Chess).zobrist_hash(EnPassantMode::Legal);
+        let a = KeyBuilder::masters_player("Carlsen, Magnus", Color::White)
+            .with_zobrist(Variant::Chess, zobrist);
+        let b = KeyBuilder::masters_player(" magnus  CARLSEN", Color::White)
+            .with_zobrist(Variant::Chess, zobrist);
+        let c = KeyBuilder::masters_player("Carlsen, Magnus", Color::Black)
+            .with_zobrist(Variant::Chess, zobrist);
+        assert_eq!(a.to_bytes(), b.to_bytes());
+        assert_ne!(a.to_bytes(), c.to_bytes());
+    }
+
+    #[test]
+    fn test_check_distinguishes_players() {
+        let pos = VariantPosition::new(Variant::Chess);
+        let a = KeyBuilder::player(
+            &UserId::from("a".parse::<UserName>().unwrap()),
+            Color::White,
+        );
+        let b = KeyBuilder::player(
+            &UserId::from("b".parse::<UserName>().unwrap()),
+            Color::White,
+        );
+        assert_eq!(a.check(&pos), a.check(&pos));
+        assert_ne!(a.check(&pos), b.check(&pos));
     }
 }
@@ -1,24 +1,28 @@
 use std::{
     array,
     cmp::{max, min, Reverse},
+    collections::BTreeMap,
+    mem,
     str::FromStr,
 };
 
 use bytes::{Buf, BufMut};
 use nohash_hasher::IntMap;
+use serde::{Deserialize, Serialize};
 use shakmaty::{uci::Uci, Outcome};
 use thin_vec::{thin_vec, ThinVec};
 
 use crate::{
-    api::{LichessQueryFilter, Limits},
-    model::{read_uint, write_uint, BySpeed, GameId, RawUci, Speed, Stats},
+    api::{Breakdown, Breakdowns, LichessQueryFilter, Limits},
+    model::{read_uint, write_uint, BySpeed, GameId, RawUci, Speed, Stats, Termination},
     util::{midpoint, sort_by_key_and_truncate},
 };
 
 const MAX_LICHESS_GAMES: usize = 8;
 const MAX_TOP_GAMES: usize = 4; // <= MAX_LICHESS_GAMES
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub enum RatingGroup {
     GroupLow,
     Group1000,
@@ -75,6 +79,29 @@ impl RatingGroup {
     fn select(mover_rating: u16, opponent_rating: u16) -> RatingGroup {
         RatingGroup::select_avg(midpoint(mover_rating, opponent_rating))
     }
+
+    /// Group of the rating of a single player, rather than of the average.
+    pub fn select_single(rating: u16) -> RatingGroup {
+        RatingGroup::select_avg(rating)
+    }
+
+    /// Lowest average rating in the group, as accepted by the `ratings`
+    /// filter.
+    pub fn min_rating(self) -> u16 {
+        match self {
+            RatingGroup::GroupLow => 0,
+            RatingGroup::Group1000 => 1000,
+            RatingGroup::Group1200 => 1200,
+            RatingGroup::Group1400 => 1400,
+            RatingGroup::Group1600 => 1600,
+            RatingGroup::Group1800 => 1800,
+            RatingGroup::Group2000 => 2000,
+            RatingGroup::Group2200 => 2200,
+            RatingGroup::Group2500 => 2500,
+            RatingGroup::Group2800 => 2800,
+            RatingGroup::Group3200 => 3200,
+        }
+    }
 }
 
 impl FromStr for RatingGroup {
@@ -172,18 +199,98 @@ impl<T> IntoIterator for ByRatingGroup<T> {
     }
 }
 
+/// Coarse category of a `Termination`, for the breakdown of outcomes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TerminationGroup {
+    /// Mate, resignation, or another decisive end on the board.
+    Decisive,
+    /// Out of time, or left the game.
+    Time,
+    /// Draw by agreement, repetition, or another draw rule.
+    Draw,
+    Stalemate,
+}
+
+impl TerminationGroup {
+    pub fn select(termination: Termination) -> TerminationGroup {
+        match termination {
+            Termination::Mate
+            | Termination::Resign
+            | Termination::Cheat
+            | Termination::VariantEnd => TerminationGroup::Decisive,
+            Termination::OutOfTime | Termination::Timeout => TerminationGroup::Time,
+            Termination::Draw => TerminationGroup::Draw,
+            Termination::Stalemate => TerminationGroup::Stalemate,
+        }
+    }
+
+    /// Header prefix, using the otherwise invalid speed 7. See
+    /// `LichessHeader` and `PlayerEntry`.
+    pub fn read_prefix(n: u8) -> Option<TerminationGroup> {
+        (n & 7 == 7).then(|| match n >> 3 {
+            0 => TerminationGroup::Decisive,
+            1 => TerminationGroup::Time,
+            2 => TerminationGroup::Draw,
+            3 => TerminationGroup::Stalemate,
+            _ => panic!("invalid termination group"),
+        })
+    }
+
+    pub fn to_prefix(self) -> u8 {
+        7 | (match self {
+            TerminationGroup::Decisive => 0,
+            TerminationGroup::Time => 1,
+            TerminationGroup::Draw => 2,
+            TerminationGroup::Stalemate => 3,
+        } << 3)
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct ByTerminationGroup<T> {
+    pub decisive: T,
+    pub time: T,
+    pub draw: T,
+    pub stalemate: T,
+}
+
+impl<T> ByTerminationGroup<T> {
+    pub fn by_termination_group_mut(&mut self, termination_group: TerminationGroup) -> &mut T {
+        match termination_group {
+            TerminationGroup::Decisive => &mut self.decisive,
+            TerminationGroup::Time => &mut self.time,
+            TerminationGroup::Draw => &mut self.draw,
+            TerminationGroup::Stalemate => &mut self.stalemate,
+        }
+    }
+
+    pub fn zip_termination_group(&self) -> [(TerminationGroup, &T); 4] {
+        [
+            (TerminationGroup::Decisive, &self.decisive),
+            (TerminationGroup::Time, &self.time),
+            (TerminationGroup::Draw, &self.draw),
+            (TerminationGroup::Stalemate, &self.stalemate),
+        ]
+    }
+}
+
 enum LichessHeader {
     Group {
         rating_group: RatingGroup,
         speed: Speed,
         num_games: usize,
     },
+    /// The following group has games of only this termination group.
+    Termination(TerminationGroup),
     End,
 }
 
 impl LichessHeader {
     fn read<B: Buf>(buf: &mut B) -> LichessHeader {
         let n = buf.get_u8();
+        if let Some(termination_group) = TerminationGroup::read_prefix(n) {
+            return LichessHeader::Termination(termination_group);
+        }
         let speed = match n & 7 {
             0 => return LichessHeader::End,
             1 => Speed::UltraBullet,
@@ -192,7 +299,7 @@ impl LichessHeader {
             4 => Speed::Rapid,
             5 => Speed::Classical,
             6 => Speed::Correspondence,
-            _ => panic!("invalid speed"),
+            _ => unreachable!(),
         };
         let rating_group = match (n >> 3) & 15 {
             0 => RatingGroup::GroupLow,
@@ -223,6 +330,9 @@ impl LichessHeader {
     fn write<B: BufMut>(&self, buf: &mut B) {
         match *self {
             LichessHeader::End => buf.put_u8(0),
+            LichessHeader::Termination(termination_group) => {
+                buf.put_u8(termination_group.to_prefix())
+            }
             LichessHeader::Group {
                 speed,
                 rating_group,
@@ -264,6 +374,139 @@ impl LichessHeader {
 pub struct LichessGroup {
     pub stats: Stats,
     pub games: ThinVec<(u64, GameId)>,
+    /// Parts of `stats` with known termination. Games indexed by older
+    /// builds, and masters games, have none.
+    pub terminations: Option<Box<ByTerminationGroup<Stats>>>,
+}
+
+impl LichessGroup {
+    pub fn new_single(
+        outcome: Outcome,
+        rating: u16,
+        game_id: GameId,
+        termination: Option<Termination>,
+    ) -> LichessGroup {
+        let stats = Stats::new_single(outcome, rating);
+        let mut group = LichessGroup {
+            stats: Stats::default(),
+            games: thin_vec![(0, game_id)],
+            terminations: None,
+        };
+        if let Some(termination) = termination {
+            group.add_termination(TerminationGroup::select(termination), &stats);
+        }
+        group.stats = stats;
+        group
+    }
+
+    pub fn add_termination(&mut self, termination_group: TerminationGroup, stats: &Stats) {
+        *self
+            .terminations
+            .get_or_insert_with(Default::default)
+            .by_termination_group_mut(termination_group) += stats;
+    }
+
+    pub fn merge(&mut self, other: LichessGroup) {
+        if let Some(terminations) = other.terminations {
+            for (termination_group, stats) in terminations.zip_termination_group() {
+                self.add_termination(termination_group, stats);
+            }
+        }
+        self.stats += &other.stats;
+        self.games.extend(other.games);
+    }
+
+    /// Splits `stats` into parts to be written as separate groups: Games
+    /// with unknown termination first, then by termination group. Empty
+    /// parts are omitted.
+    pub fn parts(&self) -> Vec<(Option<TerminationGroup>, Stats)> {
+        match self.terminations {
+            None => vec![(None, self.stats.clone())],
+            Some(ref terminations) => {
+                let mut unknown = self.stats.clone();
+                let mut parts = Vec::with_capacity(4);
+                for (termination_group, stats) in terminations.zip_termination_group() {
+                    if !stats.is_empty() {
+                        unknown -= stats;
+                        parts.push((Some(termination_group), stats.clone()));
+                    }
+                }
+                if !unknown.is_empty() {
+                    parts.insert(0, (None, unknown));
+                }
+                parts
+            }
+        }
+    }
+}
+
+/// Outcomes after a move by how the games ended.
+#[derive(Default, Debug, Clone, Serialize)]
+pub struct TerminationBreakdown {
+    #[serde(flatten)]
+    pub known: ByTerminationGroup<Stats>,
+    /// Games without known termination.
+    pub unknown: Stats,
+}
+
+impl TerminationBreakdown {
+    pub fn add_group(&mut self, group: &LichessGroup) {
+        let mut unknown = group.stats.clone();
+        if let Some(ref terminations) = group.terminations {
+            for (termination_group, stats) in terminations.zip_termination_group() {
+                *self.known.by_termination_group_mut(termination_group) += stats;
+                unknown -= stats;
+            }
+        }
+        self.unknown += &unknown;
+    }
+}
+
+/// Optional sub-statistics of a move. See `Breakdowns`.
+#[derive(Default, Debug, Clone, Serialize)]
+pub struct MoveBreakdown {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub termination: Option<TerminationBreakdown>,
+    /// Keyed by speed, for each speed selected by the filter.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed: Option<BTreeMap<Speed, Stats>>,
+    /// Keyed by the lowest rating of each group selected by the filter.
+    /// Groups above 2500 are merged, like in the `ratings` filter.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rating: Option<BTreeMap<u16, Stats>>,
+}
+
+impl MoveBreakdown {
+    /// Rating groups are only available for entries that are grouped by
+    /// rating.
+    pub fn new(breakdowns: &Breakdowns, rating_groups: bool) -> Option<MoveBreakdown> {
+        breakdowns.any().then(|| MoveBreakdown {
+            termination: breakdowns
+                .contains(Breakdown::Termination)
+                .then(TerminationBreakdown::default),
+            speed: breakdowns.contains(Breakdown::Speed).then(BTreeMap::new),
+            rating: (rating_groups && breakdowns.contains(Breakdown::Rating)).then(BTreeMap::new),
+        })
+    }
+
+    pub fn add_group(
+        &mut self,
+        group: &LichessGroup,
+        speed: Speed,
+        rating_group: Option<RatingGroup>,
+    ) {
+        if let Some(ref mut termination) = self.termination {
+            termination.add_group(group);
+        }
+        if let Some(ref mut by_speed) = self.speed {
+            *by_speed.entry(speed).or_default() += &group.stats;
+        }
+        if let (Some(by_rating), Some(rating_group)) = (&mut self.rating, rating_group) {
+            *by_rating
+                .entry(min(rating_group, RatingGroup::Group2500).min_rating())
+                .or_default() += &group.stats;
+        }
+    }
 }
 
 #[derive(Default, Debug)]
@@ -276,11 +519,16 @@ pub struct LichessEntry {
 impl LichessEntry {
     pub const SIZE_HINT: usize = 13;
 
+    /// Approximate memory used by each move of a decoded entry, not
+    /// counting game references.
+    pub const MOVE_SIZE: usize = mem::size_of::<(RawUci, BySpeed<ByRatingGroup<LichessGroup>>)>();
+
     pub fn new_single(
         uci: Uci,
         speed: Speed,
         game_id: GameId,
         outcome: Outcome,
+        termination: Option<Termination>,
         mover_rating: u16,
         opponent_rating: u16,
     ) -> LichessEntry {
@@ -288,10 +536,7 @@ impl LichessEntry {
         *sub_entry
             .by_speed_mut(speed)
             .by_rating_group_mut(RatingGroup::select(mover_rating, opponent_rating)) =
-            LichessGroup {
-                stats: Stats::new_single(outcome, mover_rating),
-                games: thin_vec![(0, game_id)],
-            };
+            LichessGroup::new_single(outcome, mover_rating, game_id, termination);
         LichessEntry {
             sub_entries: [(RawUci::from(uci), sub_entry)].into_iter().collect(),
             min_game_idx: Some(0),
@@ -305,10 +550,12 @@ impl LichessEntry {
         while buf.has_remaining() {
             let uci = RawUci::read(buf);
             let sub_entry = self.sub_entries.entry(uci).or_default();
+            let mut termination_group = None;
 
             while buf.has_remaining() {
                 match LichessHeader::read(buf) {
                     LichessHeader::End => break,
+                    LichessHeader::Termination(group) => termination_group = Some(group),
                     LichessHeader::Group {
                         speed,
                         rating_group,
@@ -317,7 +564,11 @@ impl LichessEntry {
                         let group = sub_entry
                             .by_speed_mut(speed)
                             .by_rating_group_mut(rating_group);
-                        group.stats += &Stats::read(buf);
+                        let stats = Stats::read(buf);
+                        if let Some(termination_group) = termination_group.take() {
+                            group.add_termination(termination_group, &stats);
+                        }
+                        group.stats += &stats;
                         group.games.extend((0..num_games).map(|_| {
                             let game_idx = base_game_idx + read_uint(buf);
                             self.min_game_idx =
@@ -343,19 +594,31 @@ impl LichessEntry {
             for (speed, by_rating_group) in sub_entry.as_ref().zip_speed() {
                 for (rating_group, group) in by_rating_group.as_ref().zip_rating_group() {
                     if !group.stats.is_empty() {
-                        let num_games = min(group.games.len(), MAX_LICHESS_GAMES);
-                        LichessHeader::Group {
-                            speed,
-                            rating_group,
-                            num_games,
-                        }
-                        .write(buf);
+                        // Games are attached to the last part.
+                        let parts = group.parts();
+                        let last = parts.len() - 1;
+                        for (i, (termination_group, stats)) in parts.into_iter().enumerate() {
+                            let num_games = if i == last {
+                                min(group.games.len(), MAX_LICHESS_GAMES)
+                            } else {
+                                0
+                            };
+                            if let Some(termination_group) = termination_group {
+                                LichessHeader::Termination(termination_group).write(buf);
+                            }
+                            LichessHeader::Group {
+                                speed,
+                                rating_group,
+                                num_games,
+                            }
+                            .write(buf);
 
-                        group.stats.write(buf);
+                            stats.write(buf);
 
-                        for (game_idx, game) in &group.games[group.games.len() - num_games..] {
-                            write_uint(buf, *game_idx - self.min_game_idx.unwrap_or(0));
-                            game.write(buf);
+                            for (game_idx, game) in &group.games[group.games.len() - num_games..] {
+                                write_uint(buf, *game_idx - self.min_game_idx.unwrap_or(0));
+                                game.write(buf);
+                            }
                         }
                     }
                 }
@@ -363,6 +626,81 @@ impl LichessEntry {
         }
     }
 
+    /// Non-empty groups with their move, speed and rating group, for
+    /// `dump`.
+    pub fn groups(&self) -> impl Iterator<Item = (RawUci, Speed, RatingGroup, &LichessGroup)> + '_ {
+        self.sub_entries.iter().flat_map(|(uci, sub_entry)| {
+            sub_entry
+                .as_ref()
+                .zip_speed()
+                .into_iter()
+                .flat_map(move |(speed, by_rating_group)| {
+                    by_rating_group
+                        .as_ref()
+                        .zip_rating_group()
+                        .into_iter()
+                        .filter(|(_, group)| !group.stats.is_empty())
+                        .map(move |(rating_group, group)| (*uci, speed, rating_group, group))
+                })
+        })
+    }
+
+    /// Inverse of `groups()`. Game indexes order games across all groups of
+    /// the entry.
+    pub fn add_group(
+        &mut self,
+        uci: RawUci,
+        speed: Speed,
+        rating_group: RatingGroup,
+        group: LichessGroup,
+    ) {
+        for (game_idx, _) in &group.games {
+            self.min_game_idx = Some(min(self.min_game_idx.unwrap_or(u64::MAX), *game_idx));
+            self.max_game_idx = Some(max(self.max_game_idx.unwrap_or(0), *game_idx));
+        }
+        self.sub_entries
+            .entry(uci)
+            .or_default()
+            .by_speed_mut(speed)
+            .by_rating_group_mut(rating_group)
+            .merge(group);
+    }
+
+    pub fn num_moves(&self) -> usize {
+        self.sub_entries.len()
+    }
+
+    /// Drops all game references, keeping only statistics.
+    pub fn clear_games(&mut self) {
+        for sub_entry in self.sub_entries.values_mut() {
+            for group in [
+                &mut sub_entry.ultra_bullet,
+                &mut sub_entry.bullet,
+                &mut sub_entry.blitz,
+                &mut sub_entry.rapid,
+                &mut sub_entry.classical,
+                &mut sub_entry.correspondence,
+            ] {
+                for rating_group in RatingGroup::ALL {
+                    group.by_rating_group_mut(rating_group).games.clear();
+                }
+            }
+        }
+        self.min_game_idx = None;
+        self.max_game_idx = None;
+    }
+
+    pub fn game_ids(&self) -> impl Iterator<Item = GameId> + '_ {
+        self.sub_entries.values().flat_map(|sub_entry| {
+            sub_entry.as_ref().into_iter().flat_map(|group| {
+                group
+                    .as_ref()
+                    .into_iter()
+                    .flat_map(|group| group.games.iter().map(|(_, id)| *id))
+            })
+        })
+    }
+
     pub fn total(&self, filter: &LichessQueryFilter) -> Stats {
         let mut stats = Stats::default();
 
@@ -381,7 +719,12 @@ impl LichessEntry {
         stats
     }
 
-    pub fn prepare(self, filter: &LichessQueryFilter, limits: &Limits) -> PreparedResponse {
+    pub fn prepare(
+        self,
+        filter: &LichessQueryFilter,
+        limits: &Limits,
+        breakdowns: &Breakdowns,
+    ) -> PreparedResponse {
         let mut total = Stats::default();
         let mut moves = Vec::with_capacity(self.sub_entries.len());
         let mut recent_games: Vec<(RatingGroup, Speed, u64, Uci, GameId)> = Vec::new();
@@ -391,12 +734,16 @@ impl LichessEntry {
 
             let mut latest_game: Option<(u64, GameId)> = None;
             let mut stats = Stats::default();
+            let mut breakdown = MoveBreakdown::new(breakdowns, true);
 
             for (speed, group) in sub_entry.as_ref().zip_speed() {
                 if filter.contains_speed(speed) {
                     for (rating_group, group) in group.as_ref().zip_rating_group() {
                         if filter.contains_rating_group(rating_group) {
                             stats += &group.stats;
+                            if let Some(ref mut breakdown) = breakdown {
+                                breakdown.add_group(group, speed, Some(rating_group));
+                            }
 
                             for (idx, game) in group.games.iter().copied() {
                                 if latest_game.map_or(true, |(latest_idx, _game)| latest_idx < idx)
@@ -425,6 +772,7 @@ impl LichessEntry {
                     performance: None,
                     game: latest_game.filter(|_| stats.is_single()).map(|(_, id)| id),
                     stats,
+                    breakdown,
                 });
             }
         }
@@ -502,6 +850,7 @@ pub struct PreparedMove {
     pub average_rating: Option<u16>,
     pub average_opponent_rating: Option<u16>,
     pub performance: Option<i32>,
+    pub breakdown: Option<MoveBreakdown>,
 }
 
 #[cfg(test)]
@@ -524,6 +873,7 @@ mod tests {
             Speed::Blitz,
             "aaaaaaaa".parse().unwrap(),
             Outcome::Draw,
+            None,
             2000,
             2200,
         );
@@ -556,6 +906,7 @@ mod tests {
             Outcome::Decisive {
                 winner: Color::White,
             },
+            Some(Termination::Mate),
             2000,
             2200,
         );
@@ -581,6 +932,8 @@ mod tests {
             &LichessQueryFilter {
                 speeds: None,
                 ratings: Some(vec![RatingGroup::Group2000]),
+                min_rating: None,
+                max_rating: None,
                 since: None,
                 until: None,
             },
@@ -589,13 +942,39 @@ mod tests {
                 top_games: usize::MAX,
                 moves: None,
             },
+            &Breakdowns {
+                breakdown: Some(vec![
+                    Breakdown::Termination,
+                    Breakdown::Speed,
+                    Breakdown::Rating,
+                ]),
+            },
         );
         assert_eq!(
             res.recent_games,
             &[
-                (uci_b, "bbbbbbbb".parse().unwrap()),
-                (uci_a, "aaaaaaaa".parse().unwrap()),
+                (uci_b.clone(), "bbbbbbbb".parse().unwrap()),
+                (uci_a.clone(), "aaaaaaaa".parse().unwrap()),
             ]
         );
+
+        // Breakdown by termination, unknown for games without.
+        for m in res.moves {
+            let breakdown = m.breakdown.unwrap();
+            assert_eq!(breakdown.speed.unwrap()[&Speed::Blitz], m.stats);
+            assert_eq!(
+                breakdown.rating.unwrap().into_iter().collect::<Vec<_>>(),
+                &[(2000, m.stats)]
+            );
+
+            let termination = breakdown.termination.unwrap();
+            if m.uci == uci_b {
+                assert_eq!(termination.known.decisive.white(), 1);
+                assert!(termination.unknown.is_empty());
+            } else {
+                assert!(termination.known.decisive.is_empty());
+                assert_eq!(termination.unknown.draws(), 1);
+            }
+        }
     }
 }
@@ -6,6 +6,13 @@ use shakmaty::{ByColor, Color, Outcome};
 
 use crate::model::{read_uint, write_uint, Mode, Month, Speed};
 
+/// Game info of Lichess games.
+///
+/// Records written by newer builds end with the termination (u8, 0 if
+/// unknown), the number of plies plus one (varint), and the initial clock in
+/// seconds plus one (varint, 0 without clock) followed by the increment
+/// (varint, only with clock). Records of older builds end before, and have
+/// none of this metadata. Both are read without a format bump.
 #[derive(Debug)]
 pub struct LichessGame {
     pub outcome: Outcome,
@@ -15,10 +22,15 @@ pub struct LichessGame {
     pub month: Month,
     pub indexed_player: ByColor<bool>,
     pub indexed_lichess: bool,
+    /// Indexed for the player under checked keys. See `KeyCheck`.
+    pub hardened_player: ByColor<bool>,
+    pub clock: Option<Clock>,
+    pub termination: Option<Termination>,
+    pub plies: Option<u32>,
 }
 
 impl LichessGame {
-    pub const SIZE_HINT: usize = 1 + 2 * (1 + 20 + 2) + 2;
+    pub const SIZE_HINT: usize = 1 + 2 * (1 + 20 + 2) + 2 + 1 + 1 + 2 + 3 + 1;
 
     pub fn write<B: BufMut>(&self, buf: &mut B) {
         buf.put_u8(
@@ -45,7 +57,30 @@ impl LichessGame {
         self.players.white.write(buf);
         self.players.black.write(buf);
         buf.put_u16_le(u16::from(self.month));
-        buf.put_u8(u8::from(self.indexed_lichess));
+        buf.put_u8(
+            u8::from(self.indexed_lichess)
+                | (u8::from(self.hardened_player.white) << 1)
+                | (u8::from(self.hardened_player.black) << 2),
+        );
+        buf.put_u8(match self.termination {
+            None => 0,
+            Some(Termination::Mate) => 1,
+            Some(Termination::Resign) => 2,
+            Some(Termination::Stalemate) => 3,
+            Some(Termination::Timeout) => 4,
+            Some(Termination::Draw) => 5,
+            Some(Termination::OutOfTime) => 6,
+            Some(Termination::Cheat) => 7,
+            Some(Termination::VariantEnd) => 8,
+        });
+        write_uint(buf, self.plies.map_or(0, |plies| u64::from(plies) + 1));
+        match self.clock {
+            Some(clock) => {
+                write_uint(buf, u64::from(clock.initial) + 1);
+                write_uint(buf, u64::from(clock.increment));
+            }
+            None => write_uint(buf, 0),
+        }
     }
 
     pub fn read<B: Buf>(buf: &mut B) -> LichessGame {
@@ -79,7 +114,40 @@ impl LichessGame {
             black: GamePlayer::read(buf),
         };
         let month = buf.get_u16_le().try_into().expect("month");
-        let indexed_lichess = buf.get_u8() != 0;
+        let byte = buf.get_u8();
+        let indexed_lichess = byte & 1 == 1;
+        let hardened_player = ByColor {
+            white: (byte >> 1) & 1 == 1,
+            black: (byte >> 2) & 1 == 1,
+        };
+        let (termination, plies, clock) = if buf.has_remaining() {
+            let termination = match buf.get_u8() {
+                0 => None,
+                1 => Some(Termination::Mate),
+                2 => Some(Termination::Resign),
+                3 => Some(Termination::Stalemate),
+                4 => Some(Termination::Timeout),
+                5 => Some(Termination::Draw),
+                6 => Some(Termination::OutOfTime),
+                7 => Some(Termination::Cheat),
+                8 => Some(Termination::VariantEnd),
+                _ => panic!("invalid termination"),
+            };
+            let plies = match read_uint(buf) {
+                0 => None,
+                plies => Some(u32::try_from(plies - 1).expect("plies")),
+            };
+            let clock = match read_uint(buf) {
+                0 => None,
+                initial => Some(Clock {
+                    initial: u32::try_from(initial - 1).expect("clock initial"),
+                    increment: u32::try_from(read_uint(buf)).expect("clock increment"),
+                }),
+            };
+            (termination, plies, clock)
+        } else {
+            (None, None, None)
+        };
         LichessGame {
             outcome,
             speed,
@@ -88,10 +156,38 @@ impl LichessGame {
             month,
             indexed_player,
             indexed_lichess,
+            hardened_player,
+            clock,
+            termination,
+            plies,
         }
     }
 }
 
+/// Time control of a real-time game.
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Clock {
+    /// Initial time in seconds.
+    pub initial: u32,
+    /// Increment in seconds.
+    pub increment: u32,
+}
+
+/// How a finished game ended, named like the game status of Lichess.
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Termination {
+    Mate,
+    Resign,
+    Stalemate,
+    Timeout,
+    Draw,
+    #[serde(rename = "outoftime")]
+    OutOfTime,
+    Cheat,
+    VariantEnd,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct GamePlayer {
     pub name: String,
@@ -115,3 +211,67 @@ impl GamePlayer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game() -> LichessGame {
+        LichessGame {
+            outcome: Outcome::Decisive {
+                winner: Color::White,
+            },
+            speed: Speed::Blitz,
+            mode: Mode::Rated,
+            players: ByColor {
+                white: GamePlayer {
+                    name: "white".to_owned(),
+                    rating: 2000,
+                },
+                black: GamePlayer {
+                    name: "black".to_owned(),
+                    rating: 1900,
+                },
+            },
+            month: "2023-01".parse().unwrap(),
+            indexed_player: ByColor::new_with(|color| color.is_white()),
+            indexed_lichess: false,
+            hardened_player: ByColor::new_with(|color| color.is_white()),
+            clock: Some(Clock {
+                initial: 180,
+                increment: 2,
+            }),
+            termination: Some(Termination::OutOfTime),
+            plies: Some(0),
+        }
+    }
+
+    #[test]
+    fn test_lichess_game_roundtrip() {
+        let mut buf = Vec::new();
+        game().write(&mut buf);
+        let info = LichessGame::read(&mut &buf[..]);
+        assert_eq!(
+            info.clock,
+            Some(Clock {
+                initial: 180,
+                increment: 2
+            })
+        );
+        assert_eq!(info.termination, Some(Termination::OutOfTime));
+        assert_eq!(info.plies, Some(0));
+        assert!(info.hardened_player.white && !info.hardened_player.black);
+    }
+
+    #[test]
+    fn test_lichess_game_without_metadata() {
+        let mut buf = Vec::new();
+        game().write(&mut buf);
+        buf.truncate(buf.len() - 5); // Written by older builds
+        let info = LichessGame::read(&mut &buf[..]);
+        assert_eq!(info.clock, None);
+        assert_eq!(info.termination, None);
+        assert_eq!(info.plies, None);
+        assert_eq!(info.players.black.rating, 1900);
+    }
+}
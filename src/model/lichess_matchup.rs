@@ -0,0 +1,302 @@
+use std::{
+    cmp::Reverse,
+    collections::{btree_map::Entry, BTreeMap},
+    mem,
+};
+
+use bytes::{Buf, BufMut};
+use nohash_hasher::IntMap;
+use shakmaty::{uci::Uci, Outcome};
+
+use crate::{
+    api::{LichessMatchupFilter, LichessQueryFilter, Limits},
+    model::{
+        read_uint, write_uint, PreparedMove, PreparedResponse, RatingGroup, RawUci, Speed, Stats,
+    },
+    util::sort_by_key_and_truncate,
+};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+struct Matchup {
+    speed: Speed,
+    mover: RatingGroup,
+    opponent: RatingGroup,
+}
+
+impl Matchup {
+    fn read<B: Buf>(buf: &mut B) -> Matchup {
+        let speed = Speed::ALL[usize::from(buf.get_u8())];
+        let groups = buf.get_u8();
+        Matchup {
+            speed,
+            mover: RatingGroup::ALL[usize::from(groups >> 4)],
+            opponent: RatingGroup::ALL[usize::from(groups & 15)],
+        }
+    }
+
+    fn write<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u8(index_of(&Speed::ALL, self.speed));
+        buf.put_u8(
+            (index_of(&RatingGroup::ALL, self.mover) << 4)
+                | index_of(&RatingGroup::ALL, self.opponent),
+        );
+    }
+}
+
+fn index_of<T: PartialEq>(all: &[T], item: T) -> u8 {
+    all.iter().position(|i| *i == item).expect("listed") as u8
+}
+
+#[derive(Default, Debug)]
+struct MatchupGroup {
+    /// Ratings of the player to move, like in `LichessEntry`.
+    stats: Stats,
+    opponent_rating_sum: u64,
+}
+
+/// Outcomes of Lichess games by speed, and by the separate rating groups of
+/// the player to move and of their opponent. `LichessEntry` only groups by
+/// the average rating. Kept without game references.
+///
+/// Encoded as `RawUci`, number of groups (varint), and for each group the
+/// index of the speed (u8), the indexes of the mover and opponent rating
+/// groups (u8, 4 bits each), `Stats` and the sum of opponent ratings
+/// (varint). Repeated until the end of the record.
+#[derive(Default, Debug)]
+pub struct LichessMatchupEntry {
+    sub_entries: IntMap<RawUci, BTreeMap<Matchup, MatchupGroup>>,
+}
+
+impl LichessMatchupEntry {
+    pub const SIZE_HINT: usize = 10;
+
+    /// Approximate memory used by each group of a decoded entry.
+    pub const GROUP_SIZE: usize = mem::size_of::<(Matchup, MatchupGroup)>();
+
+    pub fn num_groups(&self) -> usize {
+        self.sub_entries.values().map(BTreeMap::len).sum()
+    }
+
+    pub fn new_single(
+        uci: Uci,
+        speed: Speed,
+        outcome: Outcome,
+        mover_rating: u16,
+        opponent_rating: u16,
+    ) -> LichessMatchupEntry {
+        let matchup = Matchup {
+            speed,
+            mover: RatingGroup::select_single(mover_rating),
+            opponent: RatingGroup::select_single(opponent_rating),
+        };
+        let group = MatchupGroup {
+            stats: Stats::new_single(outcome, mover_rating),
+            opponent_rating_sum: u64::from(opponent_rating),
+        };
+        LichessMatchupEntry {
+            sub_entries: [(RawUci::from(uci), [(matchup, group)].into_iter().collect())]
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    pub fn extend_from_reader<B: Buf>(&mut self, buf: &mut B) {
+        while buf.has_remaining() {
+            let sub_entry = self.sub_entries.entry(RawUci::read(buf)).or_default();
+            for _ in 0..read_uint(buf) {
+                let matchup = Matchup::read(buf);
+                let stats = Stats::read(buf);
+                let opponent_rating_sum = read_uint(buf);
+                match sub_entry.entry(matchup) {
+                    Entry::Occupied(mut occupied) => {
+                        let group = occupied.get_mut();
+                        group.stats += &stats;
+                        group.opponent_rating_sum += opponent_rating_sum;
+                    }
+                    Entry::Vacant(vacant) => {
+                        vacant.insert(MatchupGroup {
+                            stats,
+                            opponent_rating_sum,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Groups by move, speed, and rating groups of the mover and the
+    /// opponent, with the sum of opponent ratings, for `dump`.
+    pub fn groups(
+        &self,
+    ) -> impl Iterator<Item = (RawUci, Speed, RatingGroup, RatingGroup, &Stats, u64)> + '_ {
+        self.sub_entries.iter().flat_map(|(uci, sub_entry)| {
+            sub_entry.iter().map(move |(matchup, group)| {
+                (
+                    *uci,
+                    matchup.speed,
+                    matchup.mover,
+                    matchup.opponent,
+                    &group.stats,
+                    group.opponent_rating_sum,
+                )
+            })
+        })
+    }
+
+    /// Inverse of `groups()`.
+    pub fn add_group(
+        &mut self,
+        uci: RawUci,
+        speed: Speed,
+        mover: RatingGroup,
+        opponent: RatingGroup,
+        stats: &Stats,
+        opponent_rating_sum: u64,
+    ) {
+        let group = self
+            .sub_entries
+            .entry(uci)
+            .or_default()
+            .entry(Matchup {
+                speed,
+                mover,
+                opponent,
+            })
+            .or_default();
+        group.stats += stats;
+        group.opponent_rating_sum += opponent_rating_sum;
+    }
+
+    pub fn write<B: BufMut>(&self, buf: &mut B) {
+        for (uci, sub_entry) in &self.sub_entries {
+            uci.write(buf);
+            write_uint(buf, sub_entry.len() as u64);
+            for (matchup, group) in sub_entry {
+                matchup.write(buf);
+                group.stats.write(buf);
+                write_uint(buf, group.opponent_rating_sum);
+            }
+        }
+    }
+
+    /// Only moves and totals. Ratings in `filter` are ignored in favor of
+    /// `matchup`.
+    pub fn prepare(
+        self,
+        filter: &LichessQueryFilter,
+        matchup: &LichessMatchupFilter,
+        limits: &Limits,
+    ) -> PreparedResponse {
+        let mut total = Stats::default();
+        let mut moves = Vec::with_capacity(self.sub_entries.len());
+
+        for (uci, sub_entry) in self.sub_entries {
+            let mut stats = Stats::default();
+            let mut opponent_rating_sum = 0;
+
+            for (key, group) in sub_entry {
+                if filter.contains_speed(key.speed) && matchup.contains(key.mover, key.opponent) {
+                    stats += &group.stats;
+                    opponent_rating_sum += group.opponent_rating_sum;
+                }
+            }
+
+            if !stats.is_empty() {
+                total += &stats;
+
+                moves.push(PreparedMove {
+                    uci: Uci::from(uci),
+                    average_rating: stats.average_rating(),
+                    average_opponent_rating: Some(
+                        (opponent_rating_sum as f64 / stats.total() as f64).round() as u16,
+                    ),
+                    performance: None,
+                    game: None,
+                    stats,
+                    breakdown: None,
+                });
+            }
+        }
+
+        sort_by_key_and_truncate(&mut moves, limits.moves.unwrap_or(12), |row| {
+            Reverse(row.stats.total())
+        });
+
+        PreparedResponse {
+            total,
+            moves,
+            recent_games: Vec::new(),
+            top_games: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::{Color, Square};
+
+    use super::*;
+
+    #[test]
+    fn test_lichess_matchup_entry() {
+        let uci = Uci::Normal {
+            from: Square::E2,
+            to: Square::E4,
+            promotion: None,
+        };
+
+        let mut entry = LichessMatchupEntry::default();
+        for (outcome, mover_rating, opponent_rating) in [
+            (Outcome::Draw, 1500, 2000),
+            (
+                Outcome::Decisive {
+                    winner: Color::White,
+                },
+                1550,
+                2100,
+            ),
+            (Outcome::Draw, 2000, 1500),
+        ] {
+            let mut buf = Vec::new();
+            LichessMatchupEntry::new_single(
+                uci.clone(),
+                Speed::Blitz,
+                outcome,
+                mover_rating,
+                opponent_rating,
+            )
+            .write(&mut buf);
+            entry.extend_from_reader(&mut &buf[..]);
+        }
+
+        // Roundtrip.
+        let mut buf = Vec::new();
+        entry.write(&mut buf);
+        let mut deserialized = LichessMatchupEntry::default();
+        deserialized.extend_from_reader(&mut &buf[..]);
+
+        // Same average rating group, but only two games with a 1400 mover.
+        let res = deserialized.prepare(
+            &LichessQueryFilter {
+                speeds: None,
+                ratings: None,
+                min_rating: None,
+                max_rating: None,
+                since: None,
+                until: None,
+            },
+            &LichessMatchupFilter {
+                mover_ratings: Some(vec![RatingGroup::Group1400]),
+                opponent_ratings: None,
+            },
+            &Limits {
+                recent_games: 0,
+                top_games: 0,
+                moves: None,
+            },
+        );
+        assert_eq!(res.total.total(), 2);
+        assert_eq!(res.moves[0].average_rating, Some(1525));
+        assert_eq!(res.moves[0].average_opponent_rating, Some(2050));
+    }
+}
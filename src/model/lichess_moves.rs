@@ -0,0 +1,245 @@
+use std::io::{self, Write};
+
+use bytes::{Buf, BufMut};
+use shakmaty::{
+    fen::Fen,
+    san::SanPlus,
+    uci::Uci,
+    variant::{Variant, VariantPosition},
+    CastlingMode, Position,
+};
+
+use crate::model::{try_read_uint, write_uint, GameId, LichessGame, RawUci};
+
+/// Full move list of a Lichess game, stored only with `--store-lichess-moves`.
+///
+/// Encoded as variant (u8), initial FEN (varint length and ASCII, empty for
+/// the default starting position), and the moves (`RawUci` each, as in
+/// `Uci::from_chess960()`) until the end of the record.
+#[derive(Debug, Clone)]
+pub struct LichessGameMoves {
+    pub variant: Variant,
+    pub fen: Option<Fen>,
+    pub moves: Vec<RawUci>,
+}
+
+impl LichessGameMoves {
+    pub fn write<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u8(match self.variant {
+            Variant::Chess => 0,
+            Variant::Antichess => 1,
+            Variant::Atomic => 2,
+            Variant::Crazyhouse => 3,
+            Variant::Horde => 4,
+            Variant::KingOfTheHill => 5,
+            Variant::RacingKings => 6,
+            Variant::ThreeCheck => 7,
+        });
+        let fen = self.fen.as_ref().map_or(String::new(), ToString::to_string);
+        write_uint(buf, fen.len() as u64);
+        buf.put_slice(fen.as_bytes());
+        for uci in &self.moves {
+            uci.write(buf);
+        }
+    }
+
+    /// Fails on corrupted records, rather than panicking, because they are
+    /// served as they are.
+    pub fn read<B: Buf>(buf: &mut B) -> io::Result<LichessGameMoves> {
+        if !buf.has_remaining() {
+            return Err(invalid_data("missing variant"));
+        }
+        let variant = match buf.get_u8() {
+            0 => Variant::Chess,
+            1 => Variant::Antichess,
+            2 => Variant::Atomic,
+            3 => Variant::Crazyhouse,
+            4 => Variant::Horde,
+            5 => Variant::KingOfTheHill,
+            6 => Variant::RacingKings,
+            7 => Variant::ThreeCheck,
+            byte => return Err(invalid_data(format!("invalid variant {byte}"))),
+        };
+        let fen_len = match try_read_uint(buf) {
+            Some(fen_len) if fen_len <= buf.remaining() as u64 => fen_len as usize,
+            _ => return Err(invalid_data("truncated fen")),
+        };
+        let mut fen = vec![0; fen_len];
+        buf.copy_to_slice(&mut fen);
+        let fen = if fen.is_empty() {
+            None
+        } else {
+            Some(Fen::from_ascii(&fen).map_err(invalid_data)?)
+        };
+        if buf.remaining() % 2 != 0 {
+            return Err(invalid_data("truncated moves"));
+        }
+        let mut moves = Vec::with_capacity(buf.remaining() / 2);
+        while buf.has_remaining() {
+            moves.push(RawUci::read(buf));
+        }
+        Ok(LichessGameMoves {
+            variant,
+            fen,
+            moves,
+        })
+    }
+
+    pub fn write_pgn<W: Write>(
+        &self,
+        writer: &mut W,
+        id: GameId,
+        info: &LichessGame,
+    ) -> io::Result<()> {
+        let mut pos = match self.fen {
+            Some(ref fen) => VariantPosition::from_setup(
+                self.variant,
+                fen.clone().into_setup(),
+                CastlingMode::Chess960,
+            )
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?,
+            None => VariantPosition::new(self.variant),
+        };
+
+        writeln!(
+            writer,
+            "[Event \"{} {:?} game\"]",
+            if info.mode.is_rated() {
+                "Rated"
+            } else {
+                "Casual"
+            },
+            info.speed
+        )?;
+        writeln!(writer, "[Site \"https://lichess.org/{}\"]", id)?;
+        writeln!(
+            writer,
+            "[Date \"{}.??\"]",
+            info.month.to_string().replace('-', ".")
+        )?;
+        writeln!(writer, "[White \"{}\"]", info.players.white.name)?;
+        writeln!(writer, "[Black \"{}\"]", info.players.black.name)?;
+        writeln!(writer, "[Result \"{}\"]", info.outcome)?;
+        writeln!(writer, "[WhiteElo \"{}\"]", info.players.white.rating)?;
+        writeln!(writer, "[BlackElo \"{}\"]", info.players.black.rating)?;
+        if let Some(name) = match self.variant {
+            Variant::Chess => None,
+            Variant::Antichess => Some("Antichess"),
+            Variant::Atomic => Some("Atomic"),
+            Variant::Crazyhouse => Some("Crazyhouse"),
+            Variant::Horde => Some("Horde"),
+            Variant::KingOfTheHill => Some("King of the Hill"),
+            Variant::RacingKings => Some("Racing Kings"),
+            Variant::ThreeCheck => Some("Three-check"),
+        } {
+            writeln!(writer, "[Variant \"{}\"]", name)?;
+        }
+        if let Some(ref fen) = self.fen {
+            writeln!(writer, "[FEN \"{}\"]", fen)?;
+            writeln!(writer, "[SetUp \"1\"]")?;
+        }
+        writeln!(writer)?;
+
+        let offset = usize::from(pos.turn().is_black());
+        let first_move_number = pos.fullmoves().get() as usize;
+        for (i, raw) in self.moves.iter().enumerate() {
+            let m = Uci::from(*raw)
+                .to_move(&pos)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let ply = i + offset;
+            if i == 0 || ply % 2 == 0 {
+                if i > 0 {
+                    write!(writer, " ")?;
+                }
+                write!(writer, "{}.", first_move_number + ply / 2)?;
+                if ply % 2 == 1 {
+                    write!(writer, "..")?;
+                }
+            }
+            let san = SanPlus::from_move_and_play_unchecked(&mut pos, &m);
+            write!(writer, " {}", san)?;
+        }
+
+        if !self.moves.is_empty() {
+            write!(writer, " ")?;
+        }
+        writeln!(writer, "{}", info.outcome)
+    }
+}
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::{ByColor, Outcome};
+
+    use super::*;
+    use crate::model::{GamePlayer, Mode, Speed, Termination};
+
+    #[test]
+    fn test_lichess_game_moves_pgn() {
+        let moves = LichessGameMoves {
+            variant: Variant::Chess,
+            fen: Some(
+                "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1"
+                    .parse()
+                    .unwrap(),
+            ),
+            moves: ["e7e5", "g1f3"]
+                .into_iter()
+                .map(|uci| RawUci::from(uci.parse::<Uci>().unwrap()))
+                .collect(),
+        };
+
+        let mut buf = Vec::new();
+        moves.write(&mut buf);
+        let moves = LichessGameMoves::read(&mut &buf[..]).unwrap();
+
+        let info = LichessGame {
+            outcome: Outcome::Draw,
+            speed: Speed::Blitz,
+            mode: Mode::Rated,
+            players: ByColor {
+                white: GamePlayer {
+                    name: "white".to_owned(),
+                    rating: 1500,
+                },
+                black: GamePlayer {
+                    name: "black".to_owned(),
+                    rating: 1600,
+                },
+            },
+            month: "2022-11".parse().unwrap(),
+            indexed_player: Default::default(),
+            indexed_lichess: true,
+            hardened_player: Default::default(),
+            clock: None,
+            termination: Some(Termination::Draw),
+            plies: Some(2),
+        };
+
+        let mut pgn = Vec::new();
+        moves
+            .write_pgn(&mut pgn, "aaaaaaaa".parse().unwrap(), &info)
+            .unwrap();
+        let pgn = String::from_utf8(pgn).unwrap();
+        assert!(pgn.contains("[Date \"2022.11.??\"]\n"));
+        assert!(pgn.contains("[SetUp \"1\"]\n\n1... e5 2. Nf3 1/2-1/2\n"));
+    }
+
+    #[test]
+    fn test_lichess_game_moves_corrupted() {
+        assert!(LichessGameMoves::read(&mut &[][..]).is_err());
+        assert!(LichessGameMoves::read(&mut &[8, 0][..]).is_err());
+        assert!(LichessGameMoves::read(&mut &[0, 5, b'8'][..]).is_err());
+        assert!(LichessGameMoves::read(&mut &[0, 128][..]).is_err());
+        assert!(LichessGameMoves::read(&mut &[0, 1, b'x'][..]).is_err());
+        assert!(LichessGameMoves::read(&mut &[0, 0, 1][..]).is_err());
+        assert!(LichessGameMoves::read(&mut &[0, 0, 1, 2][..]).is_ok());
+    }
+}
@@ -12,12 +12,19 @@ use bytes::{Buf, BufMut};
 use nohash_hasher::IntMap;
 use serde::{Deserialize, Serialize};
 use serde_with::{formats::SpaceSeparator, serde_as, DisplayFromStr, StringWithSeparator};
-use shakmaty::{san::SanPlus, uci::Uci, ByColor, Chess, Color, Outcome};
+use sha1::{Digest, Sha1};
+use shakmaty::{
+    san::SanPlus,
+    uci::Uci,
+    variant::{Variant, VariantPosition},
+    ByColor, Chess, Color, Outcome,
+};
 use thin_vec::{thin_vec, ThinVec};
 
 use crate::{
     api::Limits,
     model::{GameId, GamePlayer, LaxDate, PreparedMove, PreparedResponse, RawUci, Stats},
+    opening::Openings,
     util::{sort_by_key_and_truncate, ByColorDef},
 };
 
@@ -49,11 +56,54 @@ pub struct MastersGame {
 }
 
 impl MastersGame {
+    pub const FINGERPRINT_SIZE: usize = 20;
+
     fn outcome(&self) -> Outcome {
         Outcome::from_winner(self.winner)
     }
 
-    fn write_pgn<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+    /// Hash over players, date and moves, to recognize the same game
+    /// imported again under another id.
+    pub fn fingerprint(&self) -> [u8; MastersGame::FINGERPRINT_SIZE] {
+        let mut hash = Sha1::new();
+        for player in [&self.players.white, &self.players.black] {
+            hash.update(player.name.trim().to_lowercase());
+            hash.update([0]);
+        }
+        hash.update(self.date.to_string());
+        hash.update([0]);
+        for uci in &self.moves {
+            hash.update(uci.to_string());
+            hash.update([b' ']);
+        }
+        let buf = hash.finalize();
+        let mut fingerprint = [0; MastersGame::FINGERPRINT_SIZE];
+        fingerprint.clone_from_slice(&buf);
+        fingerprint
+    }
+
+    /// Writes the game with additional ECO, Opening and PlyCount headers,
+    /// for bulk exports.
+    pub fn write_classified_pgn<W: Write>(
+        &self,
+        writer: &mut W,
+        openings: &Openings,
+    ) -> io::Result<()> {
+        let mut root = VariantPosition::new(Variant::Chess);
+        let opening = openings
+            .classify_and_play(&mut root, self.moves.clone())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let mut headers = Vec::with_capacity(3);
+        if let Some(opening) = opening {
+            headers.push(("ECO", opening.eco().to_owned()));
+            headers.push(("Opening", opening.name().to_owned()));
+        }
+        headers.push(("PlyCount", self.moves.len().to_string()));
+        self.write_pgn(writer, &headers)
+    }
+
+    fn write_pgn<W: Write>(&self, writer: &mut W, headers: &[(&str, String)]) -> io::Result<()> {
         writeln!(writer, "[Event \"{}\"]", self.event)?;
         writeln!(writer, "[Site \"{}\"]", self.site)?;
         writeln!(writer, "[Date \"{}\"]", self.date)?;
@@ -63,6 +113,9 @@ impl MastersGame {
         writeln!(writer, "[Result \"{}\"]", self.outcome())?;
         writeln!(writer, "[WhiteElo \"{}\"]", self.players.white.rating)?;
         writeln!(writer, "[BlackElo \"{}\"]", self.players.black.rating)?;
+        for (name, value) in headers {
+            writeln!(writer, "[{} \"{}\"]", name, value)?;
+        }
         writeln!(writer)?;
 
         let mut pos = Chess::default();
@@ -91,7 +144,7 @@ impl MastersGame {
 impl IntoResponse for MastersGame {
     fn into_response(self) -> Response {
         let mut buf = Cursor::new(Vec::new());
-        self.write_pgn(&mut buf).expect("write pgn");
+        self.write_pgn(&mut buf, &[]).expect("write pgn");
 
         Response::builder()
             .header(axum::http::header::CONTENT_TYPE, "application/x-chess-pgn")
@@ -185,6 +238,27 @@ impl MastersEntry {
         }
     }
 
+    /// Groups by move, with games and their sort keys (the sum of both
+    /// ratings), for `dump`.
+    pub fn groups(&self) -> impl Iterator<Item = (RawUci, &Stats, &[(u16, GameId)])> + '_ {
+        self.groups
+            .iter()
+            .map(|(uci, group)| (*uci, &group.stats, &group.games[..]))
+    }
+
+    /// Inverse of `groups()`.
+    pub fn add_group(&mut self, uci: RawUci, stats: &Stats, games: &[(u16, GameId)]) {
+        let group = self.groups.entry(uci).or_default();
+        group.stats += stats;
+        group.games.extend(games.iter().copied());
+    }
+
+    pub fn game_ids(&self) -> impl Iterator<Item = GameId> + '_ {
+        self.groups
+            .values()
+            .flat_map(|group| group.games.iter().map(|(_, id)| *id))
+    }
+
     pub fn prepare(self, limits: &Limits) -> PreparedResponse {
         let mut total = Stats::default();
         let mut moves = Vec::with_capacity(self.groups.len());
@@ -207,6 +281,7 @@ impl MastersEntry {
                 performance: None,
                 game: single_game,
                 stats: group.stats,
+                breakdown: None,
             });
 
             top_games.extend(
@@ -272,4 +347,61 @@ mod tests {
         assert_eq!(group.stats.draws(), 1);
         assert_eq!(group.games[0], (1600 + 1700, game));
     }
+
+    #[test]
+    fn test_fingerprint() {
+        let game = |white: &str, moves: &str| MastersGame {
+            event: "Event".to_owned(),
+            site: "Site".to_owned(),
+            date: "2022.01.01".parse().unwrap(),
+            round: "1".to_owned(),
+            players: ByColor {
+                white: GamePlayer {
+                    name: white.to_owned(),
+                    rating: 2700,
+                },
+                black: GamePlayer {
+                    name: "Black".to_owned(),
+                    rating: 2700,
+                },
+            },
+            winner: None,
+            moves: moves.split(' ').map(|uci| uci.parse().unwrap()).collect(),
+        };
+
+        let a = game("White", "g1f3 g8f6 f3g1 f6g8 g1f3 g8f6 f3g1 f6g8");
+        let b = game(" white", "g1f3 g8f6 f3g1 f6g8 g1f3 g8f6 f3g1 f6g8");
+        let c = game("White", "b1c3 b8c6 c3b1 c6b8 b1c3 b8c6 c3b1 c6b8");
+        assert_eq!(a.fingerprint(), b.fingerprint());
+        assert_ne!(a.fingerprint(), c.fingerprint());
+    }
+
+    #[test]
+    fn test_classified_pgn() {
+        let game = MastersGame {
+            event: "Event".to_owned(),
+            site: "Site".to_owned(),
+            date: "2022.01.01".parse().unwrap(),
+            round: "1".to_owned(),
+            players: ByColor {
+                white: GamePlayer {
+                    name: "White".to_owned(),
+                    rating: 2700,
+                },
+                black: GamePlayer {
+                    name: "Black".to_owned(),
+                    rating: 2700,
+                },
+            },
+            winner: Some(Color::White),
+            moves: vec!["e2e4".parse().unwrap(), "e7e5".parse().unwrap()],
+        };
+
+        let mut buf = Vec::new();
+        game.write_classified_pgn(&mut buf, &Openings::build_table())
+            .unwrap();
+        let pgn = String::from_utf8(buf).unwrap();
+        assert!(pgn.contains("[ECO \"C20\"]\n"));
+        assert!(pgn.contains("[PlyCount \"2\"]\n\n1. e4 e5 1-0\n"));
+    }
 }
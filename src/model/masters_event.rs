@@ -0,0 +1,188 @@
+//! Masters events (tournaments), identified by their event and site.
+//!
+//! The event index maps each `MastersEventId` to a `MastersEvent` summary:
+//! number of games (varint), first and last date (see
+//! `LaxDate::to_sort_bytes()`), event and site (each as varint length and
+//! UTF-8). Summaries are combined by the merge operator.
+
+use std::{
+    fmt::{self, Write as _},
+    str::FromStr,
+};
+
+use bytes::{Buf, BufMut};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+use crate::model::{read_uint, write_uint, InvalidDate, LaxDate, MastersGame};
+
+#[derive(Error, Debug)]
+#[error("invalid event id")]
+pub struct InvalidEventId;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash)]
+pub struct MastersEventId([u8; MastersEventId::SIZE]);
+
+impl MastersEventId {
+    pub const SIZE: usize = 8;
+
+    pub fn of(game: &MastersGame) -> MastersEventId {
+        let mut hash = Sha1::new();
+        hash.update(game.event.trim());
+        hash.update([0]);
+        hash.update(game.site.trim());
+        let buf = hash.finalize();
+        let mut id = [0; MastersEventId::SIZE];
+        id.clone_from_slice(&buf[..MastersEventId::SIZE]);
+        MastersEventId(id)
+    }
+
+    pub fn to_bytes(self) -> [u8; MastersEventId::SIZE] {
+        self.0
+    }
+
+    pub fn read<B: Buf>(buf: &mut B) -> MastersEventId {
+        let mut id = [0; MastersEventId::SIZE];
+        buf.copy_to_slice(&mut id);
+        MastersEventId(id)
+    }
+}
+
+impl FromStr for MastersEventId {
+    type Err = InvalidEventId;
+
+    fn from_str(s: &str) -> Result<MastersEventId, InvalidEventId> {
+        if s.len() != 2 * MastersEventId::SIZE {
+            return Err(InvalidEventId);
+        }
+        let mut id = [0; MastersEventId::SIZE];
+        for (i, byte) in id.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(s.get(2 * i..2 * i + 2).ok_or(InvalidEventId)?, 16)
+                .map_err(|_| InvalidEventId)?;
+        }
+        Ok(MastersEventId(id))
+    }
+}
+
+impl fmt::Display for MastersEventId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for MastersEventId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MastersEventId(")?;
+        fmt::Display::fmt(self, f)?;
+        f.write_char(')')
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MastersEvent {
+    pub event: String,
+    pub site: String,
+    pub games: u64,
+    pub first: LaxDate,
+    pub last: LaxDate,
+}
+
+impl MastersEvent {
+    pub const SIZE_HINT: usize = 1 + 4 + 4 + 64;
+
+    pub fn new_single(game: &MastersGame) -> MastersEvent {
+        MastersEvent {
+            event: game.event.trim().to_owned(),
+            site: game.site.trim().to_owned(),
+            games: 1,
+            first: game.date,
+            last: game.date,
+        }
+    }
+
+    pub fn merge(&mut self, other: MastersEvent) {
+        self.games += other.games;
+        if other.first.to_sort_bytes() < self.first.to_sort_bytes() {
+            self.first = other.first;
+        }
+        if other.last.to_sort_bytes() > self.last.to_sort_bytes() {
+            self.last = other.last;
+        }
+    }
+
+    pub fn read<B: Buf>(buf: &mut B) -> Result<MastersEvent, InvalidDate> {
+        let games = read_uint(buf);
+        let mut first = [0; 4];
+        buf.copy_to_slice(&mut first);
+        let mut last = [0; 4];
+        buf.copy_to_slice(&mut last);
+        Ok(MastersEvent {
+            games,
+            first: LaxDate::from_sort_bytes(first)?,
+            last: LaxDate::from_sort_bytes(last)?,
+            event: read_string(buf),
+            site: read_string(buf),
+        })
+    }
+
+    pub fn write<B: BufMut>(&self, buf: &mut B) {
+        write_uint(buf, self.games);
+        buf.put_slice(&self.first.to_sort_bytes());
+        buf.put_slice(&self.last.to_sort_bytes());
+        write_string(buf, &self.event);
+        write_string(buf, &self.site);
+    }
+}
+
+fn read_string<B: Buf>(buf: &mut B) -> String {
+    let len = read_uint(buf) as usize;
+    let mut bytes = vec![0; len];
+    buf.copy_to_slice(&mut bytes);
+    String::from_utf8(bytes).expect("utf-8 event")
+}
+
+fn write_string<B: BufMut>(buf: &mut B, s: &str) {
+    write_uint(buf, s.len() as u64);
+    buf.put_slice(s.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_id_roundtrip() {
+        let id: MastersEventId = "0123456789abcdef".parse().unwrap();
+        assert_eq!(id.to_string(), "0123456789abcdef");
+        assert!("0123456789abcde".parse::<MastersEventId>().is_err());
+        assert!("0123456789abcdeg".parse::<MastersEventId>().is_err());
+    }
+
+    #[test]
+    fn test_merge_events() {
+        let mut event = MastersEvent {
+            event: "Candidates 2022".to_owned(),
+            site: "Madrid ESP".to_owned(),
+            games: 1,
+            first: "2022.06.20".parse().unwrap(),
+            last: "2022.06.20".parse().unwrap(),
+        };
+        let mut buf = Vec::new();
+        MastersEvent {
+            games: 2,
+            first: "2022.06.17".parse().unwrap(),
+            last: "2022.06.18".parse().unwrap(),
+            ..event.clone()
+        }
+        .write(&mut buf);
+        event.merge(MastersEvent::read(&mut &buf[..]).unwrap());
+
+        assert_eq!(event.games, 3);
+        assert_eq!(event.first.to_string(), "2022.06.17");
+        assert_eq!(event.last.to_string(), "2022.06.20");
+        assert_eq!(event.site, "Madrid ESP");
+    }
+}
@@ -0,0 +1,129 @@
+//! Keys of the masters search index.
+//!
+//! Each game is indexed under its date, and under every token of its player
+//! names, event and site: tag (u8), token, 0, date (see
+//! `LaxDate::to_sort_bytes()`), game id. Tokens are lowercase alphanumeric
+//! words, so that scanning for a token prefix finds partial matches.
+
+use crate::model::{GameId, MastersGame, Year};
+
+const DATE_TAG: u8 = 0;
+
+const SUFFIX_SIZE: usize = 4 + GameId::SIZE;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SearchField {
+    Player,
+    Event,
+    Site,
+}
+
+impl SearchField {
+    fn tag(self) -> u8 {
+        match self {
+            SearchField::Player => 1,
+            SearchField::Event => 2,
+            SearchField::Site => 3,
+        }
+    }
+}
+
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    tokens.sort_unstable();
+    tokens.dedup();
+    tokens
+}
+
+/// Each of the `query` tokens is a prefix of one of the `text` tokens.
+pub fn matches_tokens(query: &[String], text: &str) -> bool {
+    let text = tokenize(text);
+    query
+        .iter()
+        .all(|q| text.iter().any(|token| token.starts_with(q.as_str())))
+}
+
+pub struct MastersSearchKey;
+
+impl MastersSearchKey {
+    pub fn all(id: GameId, game: &MastersGame) -> Vec<Vec<u8>> {
+        let mut suffix = Vec::with_capacity(SUFFIX_SIZE);
+        suffix.extend_from_slice(&game.date.to_sort_bytes());
+        suffix.extend_from_slice(&id.to_bytes());
+
+        let mut keys = vec![[&[DATE_TAG][..], &suffix[..]].concat()];
+        for (field, text) in [
+            (SearchField::Player, game.players.white.name.as_str()),
+            (SearchField::Player, game.players.black.name.as_str()),
+            (SearchField::Event, game.event.as_str()),
+            (SearchField::Site, game.site.as_str()),
+        ] {
+            for token in tokenize(text) {
+                keys.push([&[field.tag()][..], token.as_bytes(), &[0][..], &suffix[..]].concat());
+            }
+        }
+        keys.sort_unstable();
+        keys.dedup();
+        keys
+    }
+
+    /// Prefix of all keys with a token starting with `token`.
+    pub fn token_prefix(field: SearchField, token: &str) -> Vec<u8> {
+        [&[field.tag()][..], token.as_bytes()].concat()
+    }
+
+    /// Upper bound (inclusive) for iterating backwards over the date index,
+    /// starting with the most recent games of `until`.
+    pub fn date_upper_bound(until: Year) -> Vec<u8> {
+        let [hi, lo] = u16::from(until).to_be_bytes();
+        [&[DATE_TAG, hi, lo][..], &[0xff; 2 + GameId::SIZE][..]].concat()
+    }
+
+    pub fn is_date_key(key: &[u8]) -> bool {
+        key.len() == 1 + SUFFIX_SIZE && key[0] == DATE_TAG
+    }
+
+    /// Extracts the date and game id from the end of a search key.
+    pub fn parse_suffix(key: &[u8]) -> Option<SearchSuffix> {
+        let suffix = key.get(key.len().checked_sub(SUFFIX_SIZE)?..)?;
+        let mut date = [0; 4];
+        date.clone_from_slice(&suffix[..4]);
+        Some(SearchSuffix {
+            date,
+            id: GameId::read(&mut &suffix[4..]),
+        })
+    }
+}
+
+pub struct SearchSuffix {
+    /// See `LaxDate::to_sort_bytes()`.
+    pub date: [u8; 4],
+    pub id: GameId,
+}
+
+impl SearchSuffix {
+    pub fn year(&self) -> u16 {
+        u16::from_be_bytes([self.date[0], self.date[1]])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_tokens() {
+        assert_eq!(tokenize("Carlsen, Magnus"), vec!["carlsen", "magnus"]);
+        assert!(matches_tokens(&tokenize("carls m"), "Carlsen, Magnus"));
+        assert!(matches_tokens(&tokenize("MAGNUS"), "Carlsen, Magnus"));
+        assert!(!matches_tokens(&tokenize("sen"), "Carlsen, Magnus"));
+        assert!(!matches_tokens(
+            &tokenize("carlsen hikaru"),
+            "Carlsen, Magnus"
+        ));
+    }
+}
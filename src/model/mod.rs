@@ -3,7 +3,11 @@ mod game_id;
 mod key;
 mod lichess;
 mod lichess_game;
+mod lichess_matchup;
+mod lichess_moves;
 mod masters;
+mod masters_event;
+mod masters_search;
 mod mode;
 mod player;
 mod speed;
@@ -14,14 +18,21 @@ mod user;
 
 pub use date::{InvalidDate, LaxDate, Month, Year};
 pub use game_id::{GameId, InvalidGameId};
-pub use key::{Key, KeyBuilder, KeyPrefix};
-pub use lichess::{LichessEntry, LichessGroup, PreparedMove, PreparedResponse, RatingGroup};
-pub use lichess_game::{GamePlayer, LichessGame};
+pub use key::{CheckedKey, Key, KeyBuilder, KeyCheck, KeyPrefix};
+pub use lichess::{
+    ByTerminationGroup, LichessEntry, LichessGroup, MoveBreakdown, PreparedMove, PreparedResponse,
+    RatingGroup, TerminationBreakdown, TerminationGroup,
+};
+pub use lichess_game::{Clock, GamePlayer, LichessGame, Termination};
+pub use lichess_matchup::LichessMatchupEntry;
+pub use lichess_moves::LichessGameMoves;
 pub use masters::{MastersEntry, MastersGame, MastersGameWithId};
+pub use masters_event::{InvalidEventId, MastersEvent, MastersEventId};
+pub use masters_search::{matches_tokens, tokenize, MastersSearchKey, SearchField, SearchSuffix};
 pub use mode::{ByMode, Mode};
-pub use player::{IndexRun, PlayerEntry, PlayerStatus};
+pub use player::{IndexRun, KeyScheme, PlayerEntry, PlayerStatus};
 pub use speed::{BySpeed, Speed};
 pub use stats::Stats;
 pub use uci::RawUci;
-pub use uint::{read_uint, write_uint};
+pub use uint::{read_uint, try_read_uint, write_uint};
 pub use user::{UserId, UserName};
@@ -6,14 +6,14 @@ use std::{
 
 use bytes::{Buf, BufMut};
 use nohash_hasher::IntMap;
+use serde::{Deserialize, Serialize};
 use shakmaty::{uci::Uci, Color, Outcome};
-use thin_vec::thin_vec;
 
 use crate::{
-    api::{Limits, PlayerQueryFilter},
+    api::{Breakdowns, Limits, PlayerQueryFilter},
     model::{
-        read_uint, write_uint, ByMode, BySpeed, GameId, LichessGroup, Mode, PreparedMove,
-        PreparedResponse, RawUci, Speed, Stats,
+        read_uint, write_uint, ByMode, BySpeed, GameId, LichessGroup, Mode, MoveBreakdown,
+        PreparedMove, PreparedResponse, RawUci, Speed, Stats, Termination, TerminationGroup,
     },
     util::sort_by_key_and_truncate,
 };
@@ -27,12 +27,17 @@ enum Header {
         speed: Speed,
         num_games: usize,
     },
+    /// The following group has games of only this termination group.
+    Termination(TerminationGroup),
     End,
 }
 
 impl Header {
     fn read<B: Buf>(buf: &mut B) -> Header {
         let n = buf.get_u8();
+        if let Some(termination_group) = TerminationGroup::read_prefix(n) {
+            return Header::Termination(termination_group);
+        }
         Header::Group {
             speed: match n & 7 {
                 0 => return Header::End,
@@ -42,7 +47,7 @@ impl Header {
                 4 => Speed::Rapid,
                 5 => Speed::Classical,
                 6 => Speed::Correspondence,
-                _ => panic!("invalid player header"),
+                _ => unreachable!(),
             },
             mode: Mode::from_rated((n >> 3) & 1 == 1),
             num_games: usize::from(n >> 4),
@@ -52,6 +57,7 @@ impl Header {
     fn write<B: BufMut>(&self, buf: &mut B) {
         buf.put_u8(match *self {
             Header::End => 0,
+            Header::Termination(termination_group) => termination_group.to_prefix(),
             Header::Group {
                 mode,
                 speed,
@@ -87,13 +93,12 @@ impl PlayerEntry {
         mode: Mode,
         game_id: GameId,
         outcome: Outcome,
+        termination: Option<Termination>,
         opponent_rating: u16,
     ) -> PlayerEntry {
         let mut sub_entry: BySpeed<ByMode<LichessGroup>> = Default::default();
-        *sub_entry.by_speed_mut(speed).by_mode_mut(mode) = LichessGroup {
-            stats: Stats::new_single(outcome, opponent_rating),
-            games: thin_vec![(0, game_id)],
-        };
+        *sub_entry.by_speed_mut(speed).by_mode_mut(mode) =
+            LichessGroup::new_single(outcome, opponent_rating, game_id, termination);
         PlayerEntry {
             sub_entries: [(RawUci::from(uci), sub_entry)].into_iter().collect(),
             min_game_idx: Some(0),
@@ -107,17 +112,23 @@ impl PlayerEntry {
         while buf.has_remaining() {
             let uci = RawUci::read(buf);
             let sub_entry = self.sub_entries.entry(uci).or_default();
+            let mut termination_group = None;
 
             while buf.has_remaining() {
                 match Header::read(buf) {
                     Header::End => break,
+                    Header::Termination(group) => termination_group = Some(group),
                     Header::Group {
                         speed,
                         mode,
                         num_games,
                     } => {
                         let group = sub_entry.by_speed_mut(speed).by_mode_mut(mode);
-                        group.stats += &Stats::read(buf);
+                        let stats = Stats::read(buf);
+                        if let Some(termination_group) = termination_group.take() {
+                            group.add_termination(termination_group, &stats);
+                        }
+                        group.stats += &stats;
                         group.games.extend((0..num_games).map(|_| {
                             let game_idx = base_game_idx + read_uint(buf);
                             self.min_game_idx =
@@ -143,20 +154,31 @@ impl PlayerEntry {
             for (speed, by_mode) in sub_entry.as_ref().zip_speed() {
                 for (mode, group) in by_mode.as_ref().zip_mode() {
                     if !group.stats.is_empty() {
-                        Header::Group {
-                            speed,
-                            mode,
-                            num_games: min(group.games.len(), MAX_PLAYER_GAMES),
-                        }
-                        .write(buf);
+                        // Games are attached to the last part.
+                        let parts = group.parts();
+                        let last = parts.len() - 1;
+                        for (i, (termination_group, stats)) in parts.into_iter().enumerate() {
+                            let num_games = if i == last {
+                                min(group.games.len(), MAX_PLAYER_GAMES)
+                            } else {
+                                0
+                            };
+                            if let Some(termination_group) = termination_group {
+                                Header::Termination(termination_group).write(buf);
+                            }
+                            Header::Group {
+                                speed,
+                                mode,
+                                num_games,
+                            }
+                            .write(buf);
 
-                        group.stats.write(buf);
+                            stats.write(buf);
 
-                        for (game_idx, game) in
-                            &group.games[group.games.len().saturating_sub(MAX_PLAYER_GAMES)..]
-                        {
-                            write_uint(buf, *game_idx - self.min_game_idx.unwrap_or(0));
-                            game.write(buf);
+                            for (game_idx, game) in &group.games[group.games.len() - num_games..] {
+                                write_uint(buf, *game_idx - self.min_game_idx.unwrap_or(0));
+                                game.write(buf);
+                            }
                         }
                     }
                 }
@@ -164,11 +186,55 @@ impl PlayerEntry {
         }
     }
 
+    /// Non-empty groups with their move, speed and mode, for `dump`.
+    pub fn groups(&self) -> impl Iterator<Item = (RawUci, Speed, Mode, &LichessGroup)> + '_ {
+        self.sub_entries.iter().flat_map(|(uci, sub_entry)| {
+            sub_entry
+                .as_ref()
+                .zip_speed()
+                .into_iter()
+                .flat_map(move |(speed, by_mode)| {
+                    by_mode
+                        .as_ref()
+                        .zip_mode()
+                        .into_iter()
+                        .filter(|(_, group)| !group.stats.is_empty())
+                        .map(move |(mode, group)| (*uci, speed, mode, group))
+                })
+        })
+    }
+
+    /// Inverse of `groups()`, like `LichessEntry::add_group()`.
+    pub fn add_group(&mut self, uci: RawUci, speed: Speed, mode: Mode, group: LichessGroup) {
+        for (game_idx, _) in &group.games {
+            self.min_game_idx = Some(min(self.min_game_idx.unwrap_or(u64::MAX), *game_idx));
+            self.max_game_idx = Some(max(self.max_game_idx.unwrap_or(0), *game_idx));
+        }
+        self.sub_entries
+            .entry(uci)
+            .or_default()
+            .by_speed_mut(speed)
+            .by_mode_mut(mode)
+            .merge(group);
+    }
+
+    pub fn game_ids(&self) -> impl Iterator<Item = GameId> + '_ {
+        self.sub_entries.values().flat_map(|sub_entry| {
+            sub_entry.as_ref().into_iter().flat_map(|group| {
+                group
+                    .as_ref()
+                    .into_iter()
+                    .flat_map(|group| group.games.iter().map(|(_, id)| *id))
+            })
+        })
+    }
+
     pub fn prepare(
         self,
         color: Color,
         filter: &PlayerQueryFilter,
         limits: &Limits,
+        breakdowns: &Breakdowns,
     ) -> PreparedResponse {
         let mut total = Stats::default();
         let mut moves = Vec::with_capacity(self.sub_entries.len());
@@ -177,6 +243,7 @@ impl PlayerEntry {
         for (uci, sub_entry) in self.sub_entries {
             let mut latest_game: Option<(u64, GameId)> = None;
             let mut stats = Stats::default();
+            let mut breakdown = MoveBreakdown::new(breakdowns, false);
 
             for (speed, group) in sub_entry.as_ref().zip_speed() {
                 if filter
@@ -191,6 +258,9 @@ impl PlayerEntry {
                             .map_or(true, |modes| modes.contains(&mode))
                         {
                             stats += &group.stats;
+                            if let Some(ref mut breakdown) = breakdown {
+                                breakdown.add_group(group, speed, None);
+                            }
 
                             for (idx, game) in group.games.iter().copied() {
                                 if latest_game.map_or(true, |(latest_idx, _game)| latest_idx < idx)
@@ -221,6 +291,7 @@ impl PlayerEntry {
                     performance: stats.performance(color),
                     game: latest_game.filter(|_| stats.is_single()).map(|(_, id)| id),
                     stats,
+                    breakdown,
                 });
             }
         }
@@ -252,6 +323,33 @@ pub struct PlayerStatus {
     pub revisit_ongoing_created_at: Option<u64>,
     pub indexed_at: SystemTime,
     pub revisited_at: SystemTime,
+    pub key_scheme: KeyScheme,
+}
+
+/// Progress of moving the entries of a player to checked keys (see
+/// `KeyCheck`).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum KeyScheme {
+    /// Indexed under legacy keys only.
+    Legacy,
+    /// Reindexing all games under checked keys. Reads still use the legacy
+    /// keys, which are kept complete in the meantime.
+    Rehashing,
+    /// All games indexed under checked keys, which are used for reads.
+    /// Revisiting all games to delete their legacy keys, which are spread
+    /// over all positions rather than sharing a common prefix. Crafted
+    /// collisions may also delete legacy keys of another player, who will
+    /// be served from checked keys after their own rehash.
+    Cleaning,
+    /// All games indexed under checked keys. Legacy keys are deleted.
+    Checked,
+}
+
+impl KeyScheme {
+    pub fn reads_legacy(self) -> bool {
+        matches!(self, KeyScheme::Legacy | KeyScheme::Rehashing)
+    }
 }
 
 impl Default for PlayerStatus {
@@ -261,6 +359,7 @@ impl Default for PlayerStatus {
             revisit_ongoing_created_at: None,
             indexed_at: SystemTime::UNIX_EPOCH,
             revisited_at: SystemTime::UNIX_EPOCH,
+            key_scheme: KeyScheme::Checked,
         }
     }
 }
@@ -268,11 +367,28 @@ impl Default for PlayerStatus {
 impl PlayerStatus {
     pub const SIZE_HINT: usize = 3 * 8;
 
+    /// Starts reindexing players that were indexed before checked keys were
+    /// introduced, from their very first game. Once finished, the legacy keys
+    /// are deleted in another pass over all games.
+    pub fn maybe_rehash(&mut self) -> Option<IndexRun> {
+        match self.key_scheme {
+            KeyScheme::Legacy => {
+                self.key_scheme = KeyScheme::Rehashing;
+                self.latest_created_at = 0;
+                Some(IndexRun::Index { after: 0 })
+            }
+            KeyScheme::Rehashing | KeyScheme::Cleaning => self.maybe_index(),
+            KeyScheme::Checked => None,
+        }
+    }
+
     pub fn maybe_revisit_ongoing(&mut self) -> Option<IndexRun> {
-        if SystemTime::now()
-            .duration_since(self.revisited_at)
-            .unwrap_or_default()
-            > Duration::from_secs(24 * 60 * 60)
+        // Revisits could skip ahead of an ongoing rehash.
+        if self.key_scheme == KeyScheme::Checked
+            && SystemTime::now()
+                .duration_since(self.revisited_at)
+                .unwrap_or_default()
+                > Duration::from_secs(24 * 60 * 60)
         {
             self.revisit_ongoing_created_at
                 .map(|since| IndexRun::Revisit { since })
@@ -292,8 +408,16 @@ impl PlayerStatus {
 
     pub fn finish_run(&mut self, run: IndexRun) {
         self.indexed_at = SystemTime::now();
-        if matches!(run, IndexRun::Revisit { .. }) {
-            self.revisited_at = self.indexed_at;
+        match run {
+            IndexRun::Index { .. } => match self.key_scheme {
+                KeyScheme::Rehashing => {
+                    self.key_scheme = KeyScheme::Cleaning;
+                    self.latest_created_at = 0;
+                }
+                KeyScheme::Cleaning => self.key_scheme = KeyScheme::Checked,
+                KeyScheme::Legacy | KeyScheme::Checked => (),
+            },
+            IndexRun::Revisit { .. } => self.revisited_at = self.indexed_at,
         }
     }
 
@@ -303,6 +427,17 @@ impl PlayerStatus {
             revisit_ongoing_created_at: Some(read_uint(buf)).filter(|t| *t != 0),
             indexed_at: SystemTime::UNIX_EPOCH + Duration::from_secs(read_uint(buf)),
             revisited_at: SystemTime::UNIX_EPOCH + Duration::from_secs(read_uint(buf)),
+            key_scheme: if buf.has_remaining() {
+                match read_uint(buf) {
+                    0 => KeyScheme::Legacy,
+                    1 => KeyScheme::Rehashing,
+                    2 => KeyScheme::Cleaning,
+                    3 => KeyScheme::Checked,
+                    _ => panic!("invalid key scheme"),
+                }
+            } else {
+                KeyScheme::Legacy
+            },
         }
     }
 
@@ -323,6 +458,15 @@ impl PlayerStatus {
                 .expect("duration since unix epoch")
                 .as_secs(),
         );
+        write_uint(
+            buf,
+            match self.key_scheme {
+                KeyScheme::Legacy => 0,
+                KeyScheme::Rehashing => 1,
+                KeyScheme::Cleaning => 2,
+                KeyScheme::Checked => 3,
+            },
+        );
     }
 }
 
@@ -369,6 +513,7 @@ mod tests {
                 speed: Speed::Correspondence,
                 num_games: 15,
             },
+            Header::Termination(TerminationGroup::Stalemate),
             Header::End,
         ];
 
@@ -401,6 +546,7 @@ mod tests {
             Outcome::Decisive {
                 winner: Color::White,
             },
+            None,
             1600,
         );
 
@@ -412,6 +558,7 @@ mod tests {
             Outcome::Decisive {
                 winner: Color::Black,
             },
+            Some(Termination::Resign),
             1800,
         );
 
@@ -427,6 +574,7 @@ mod tests {
             Mode::Rated,
             "cccccccc".parse().unwrap(),
             Outcome::Draw,
+            Some(Termination::Stalemate),
             1700,
         );
 
@@ -462,6 +610,29 @@ mod tests {
         assert_eq!(group.stats.black(), 1);
         assert_eq!(group.stats.average_rating(), Some(1700));
         assert_eq!(group.games.len(), 2);
+        assert_eq!(
+            group.parts(),
+            &[
+                (
+                    None,
+                    Stats::new_single(
+                        Outcome::Decisive {
+                            winner: Color::White
+                        },
+                        1600
+                    )
+                ),
+                (
+                    Some(TerminationGroup::Decisive),
+                    Stats::new_single(
+                        Outcome::Decisive {
+                            winner: Color::Black
+                        },
+                        1800
+                    )
+                ),
+            ]
+        );
 
         // Roundtrip the combined entry.
         let mut buf = Vec::new();
@@ -471,4 +642,37 @@ mod tests {
         assert_eq!(deserialized.sub_entries.len(), 2);
         assert_eq!(deserialized.max_game_idx, Some(2));
     }
+
+    #[test]
+    fn test_status_key_scheme() {
+        let status = PlayerStatus {
+            latest_created_at: 123,
+            ..PlayerStatus::default()
+        };
+        let mut buf = Vec::new();
+        status.write(&mut buf);
+        assert_eq!(
+            PlayerStatus::read(&mut &buf[..]).key_scheme,
+            KeyScheme::Checked
+        );
+
+        // Written by older builds, without key scheme.
+        buf.pop();
+        let mut legacy = PlayerStatus::read(&mut &buf[..]);
+        assert_eq!(legacy.key_scheme, KeyScheme::Legacy);
+        assert!(matches!(
+            legacy.maybe_rehash(),
+            Some(IndexRun::Index { after: 0 })
+        ));
+        assert_eq!(legacy.key_scheme, KeyScheme::Rehashing);
+        assert_eq!(legacy.latest_created_at, 0);
+
+        legacy.latest_created_at = 456;
+        legacy.finish_run(IndexRun::Index { after: 0 });
+        assert_eq!(legacy.key_scheme, KeyScheme::Cleaning);
+        assert_eq!(legacy.latest_created_at, 0);
+
+        legacy.finish_run(IndexRun::Index { after: 0 });
+        assert_eq!(legacy.key_scheme, KeyScheme::Checked);
+    }
 }
@@ -1,4 +1,4 @@
-use std::ops::AddAssign;
+use std::ops::{AddAssign, SubAssign};
 
 use bytes::{Buf, BufMut};
 use serde::Serialize;
@@ -35,7 +35,25 @@ impl AddAssign<&Stats> for Stats {
     }
 }
 
+impl SubAssign<&Stats> for Stats {
+    fn sub_assign(&mut self, rhs: &Stats) {
+        self.rating_sum = self.rating_sum.saturating_sub(rhs.rating_sum);
+        self.white = self.white.saturating_sub(rhs.white);
+        self.draws = self.draws.saturating_sub(rhs.draws);
+        self.black = self.black.saturating_sub(rhs.black);
+    }
+}
+
 impl Stats {
+    pub fn new(white: u64, draws: u64, black: u64, rating_sum: u64) -> Stats {
+        Stats {
+            rating_sum,
+            white,
+            draws,
+            black,
+        }
+    }
+
     pub fn total(&self) -> u64 {
         self.white + self.draws + self.black
     }
@@ -60,6 +78,10 @@ impl Stats {
         self.draws
     }
 
+    pub fn rating_sum(&self) -> u64 {
+        self.rating_sum
+    }
+
     fn average_rating_f64(&self) -> Option<f64> {
         if self.total() > 0 {
             Some(self.rating_sum as f64 / self.total() as f64)
@@ -15,6 +15,21 @@ pub fn read_uint<B: Buf>(buf: &mut B) -> u64 {
     }
 }
 
+/// Like `read_uint()`, but returns `None` for truncated or overlong input.
+pub fn try_read_uint<B: Buf>(buf: &mut B) -> Option<u64> {
+    let mut n = 0;
+    let mut shift = 0;
+    while shift < 64 && buf.has_remaining() {
+        let byte = buf.get_u8();
+        n |= u64::from(byte & 127) << shift;
+        if byte & 128 == 0 {
+            return Some(n);
+        }
+        shift += 7;
+    }
+    None
+}
+
 pub fn write_uint<B: BufMut>(buf: &mut B, mut n: u64) {
     while n > 127 {
         buf.put_u8((n as u8 & 127) | 128);
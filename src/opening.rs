@@ -18,6 +18,16 @@ pub struct Opening {
     name: String,
 }
 
+impl Opening {
+    pub fn eco(&self) -> &str {
+        &self.eco
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
 #[derive(Deserialize)]
 struct OpeningRecord {
     eco: String,
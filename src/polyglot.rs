@@ -0,0 +1,215 @@
+use std::{
+    cmp::Reverse,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use clap::ValueEnum;
+use shakmaty::{
+    uci::Uci,
+    variant::{Variant, VariantPosition},
+    zobrist::{Zobrist64, ZobristHash},
+    Color, EnPassantMode, Position, Role,
+};
+use thiserror::Error;
+
+use crate::{
+    api::{Breakdowns, LichessQueryFilter, Limits},
+    db::Database,
+    model::{KeyBuilder, PreparedMove, Stats, Year},
+    util::walk_positions,
+};
+
+#[derive(Error, Debug)]
+pub enum BookError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    RocksDb(#[from] rocksdb::Error),
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub enum BookSource {
+    Lichess,
+    Masters,
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub enum BookWeight {
+    /// Number of games.
+    Popularity,
+    /// Score of the side to move.
+    Score,
+    /// Number of games weighted by score of the side to move.
+    Blend,
+}
+
+impl BookWeight {
+    fn weight(self, stats: &Stats, turn: Color) -> f64 {
+        let total = stats.total() as f64;
+        let wins = match turn {
+            Color::White => stats.white(),
+            Color::Black => stats.black(),
+        } as f64;
+        let score = (wins + stats.draws() as f64 / 2.0) / total;
+        match self {
+            BookWeight::Popularity => total,
+            BookWeight::Score => score,
+            BookWeight::Blend => total * score,
+        }
+    }
+}
+
+pub struct BookOpt {
+    pub source: BookSource,
+    pub weight: BookWeight,
+    /// Minimum number of games for a move to be included.
+    pub min_games: u64,
+    /// Speeds, rating groups and months to consider (lichess only).
+    pub filter: LichessQueryFilter,
+    /// Maximum depth of positions, in plies from the starting position.
+    pub max_plies: usize,
+}
+
+struct BookEntry {
+    key: u64,
+    mv: u16,
+    weight: u16,
+}
+
+/// Traverses the explorer tree from the standard starting position, and
+/// writes moves played at least `min_games` times to a Polyglot book.
+pub fn export(db: &Database, path: &Path, opt: &BookOpt) -> Result<(), BookError> {
+    let limits = Limits {
+        top_games: 0,
+        recent_games: 0,
+        moves: Some(usize::MAX),
+    };
+
+    let mut book = Vec::new();
+
+    walk_positions(Variant::Chess, opt.max_plies, |pos| {
+        let zobrist = pos.zobrist_hash(EnPassantMode::Legal);
+        let moves: Vec<PreparedMove> = match opt.source {
+            BookSource::Lichess => {
+                db.lichess()
+                    .read_lichess(
+                        &KeyBuilder::lichess().with_zobrist(Variant::Chess, zobrist),
+                        opt.filter.since,
+                        opt.filter.until,
+                    )?
+                    .prepare(&opt.filter, &limits, &Breakdowns::default())
+                    .moves
+            }
+            BookSource::Masters => {
+                db.masters()
+                    .read(
+                        KeyBuilder::masters().with_zobrist(Variant::Chess, zobrist),
+                        Year::min_value(),
+                        Year::max_value(),
+                    )?
+                    .prepare(&limits)
+                    .moves
+            }
+        };
+
+        let moves: Vec<PreparedMove> = moves
+            .into_iter()
+            .filter(|m| m.stats.total() >= opt.min_games.max(1))
+            .collect();
+        add_position(&mut book, pos, &moves, opt.weight);
+        Ok::<_, BookError>(moves.into_iter().map(|m| m.uci).collect())
+    })?;
+
+    // Polyglot books are sorted by key, so that they can be binary searched.
+    book.sort_by_key(|entry| (entry.key, Reverse(entry.weight)));
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    for entry in &book {
+        writer.write_all(&entry.key.to_be_bytes())?;
+        writer.write_all(&entry.mv.to_be_bytes())?;
+        writer.write_all(&entry.weight.to_be_bytes())?;
+        writer.write_all(&0u32.to_be_bytes())?; // learn
+    }
+    writer.flush()?;
+
+    log::info!("wrote polyglot book with {} entries", book.len());
+    Ok(())
+}
+
+fn add_position(
+    book: &mut Vec<BookEntry>,
+    pos: &VariantPosition,
+    moves: &[PreparedMove],
+    weight: BookWeight,
+) {
+    // Polyglot hashes the en passant square whenever a pawn could capture,
+    // regardless of legality.
+    let Zobrist64(key) = pos.zobrist_hash(EnPassantMode::PseudoLegal);
+
+    let weights: Vec<f64> = moves
+        .iter()
+        .map(|m| weight.weight(&m.stats, pos.turn()))
+        .collect();
+    let max_weight = weights.iter().copied().fold(0.0, f64::max);
+    if max_weight <= 0.0 {
+        return;
+    }
+
+    for (m, w) in moves.iter().zip(weights) {
+        if let Some(mv) = encode_move(&m.uci) {
+            book.push(BookEntry {
+                key,
+                mv,
+                weight: (w / max_weight * f64::from(u16::MAX)).round() as u16,
+            });
+        }
+    }
+}
+
+/// Polyglot move encoding. Castling moves are already encoded as king to
+/// rook, like in the explorer.
+fn encode_move(uci: &Uci) -> Option<u16> {
+    match *uci {
+        Uci::Normal {
+            from,
+            to,
+            promotion,
+        } => Some(
+            to.file() as u16
+                | (to.rank() as u16) << 3
+                | (from.file() as u16) << 6
+                | (from.rank() as u16) << 9
+                | (match promotion {
+                    Some(Role::Knight) => 1,
+                    Some(Role::Bishop) => 2,
+                    Some(Role::Rook) => 3,
+                    Some(Role::Queen) => 4,
+                    _ => 0,
+                } << 12),
+        ),
+        Uci::Put { .. } | Uci::Null => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::Chess;
+
+    use super::*;
+
+    #[test]
+    fn test_polyglot_key() {
+        // Test vector from the Polyglot specification.
+        let pos = VariantPosition::Chess(Chess::default());
+        let Zobrist64(key) = pos.zobrist_hash(EnPassantMode::PseudoLegal);
+        assert_eq!(key, 0x463b96181691fc9c);
+    }
+
+    #[test]
+    fn test_encode_move() {
+        assert_eq!(encode_move(&"e2e4".parse().unwrap()), Some(0x031c));
+        assert_eq!(encode_move(&"e1h1".parse().unwrap()), Some(0x0107));
+    }
+}
@@ -1,5 +1,7 @@
 use std::{
+    any::Any,
     cmp::min,
+    collections::{HashSet, VecDeque},
     pin::Pin,
     task::{Context, Poll},
 };
@@ -8,7 +10,12 @@ use futures_util::{ready, stream::Stream};
 use partial_sort::partial_sort;
 use pin_project_lite::pin_project;
 use serde::{Deserialize, Serialize};
-use shakmaty::ByColor;
+use shakmaty::{
+    uci::Uci,
+    variant::{Variant, VariantPosition},
+    zobrist::{Zobrist128, ZobristHash},
+    ByColor, EnPassantMode, Position,
+};
 
 #[derive(Serialize, Deserialize)]
 #[serde(remote = "ByColor")]
@@ -17,6 +24,16 @@ pub struct ByColorDef<T> {
     white: T,
 }
 
+pub fn panic_message(err: &(dyn Any + Send)) -> String {
+    if let Some(msg) = err.downcast_ref::<&str>() {
+        (*msg).to_owned()
+    } else if let Some(msg) = err.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown error".to_owned()
+    }
+}
+
 pub fn sort_by_key_and_truncate<T, K, F>(vec: &mut Vec<T>, num: usize, mut f: F)
 where
     F: FnMut(&T) -> K,
@@ -80,3 +97,35 @@ where
 pub fn midpoint(a: u16, b: u16) -> u16 {
     ((u32::from(a) + u32::from(b)) / 2) as u16
 }
+
+/// Breadth first search from the starting position of `variant`, following
+/// the moves returned by `visit`, up to a depth of `max_plies`. Each position
+/// is visited once.
+pub fn walk_positions<F, E>(variant: Variant, max_plies: usize, mut visit: F) -> Result<(), E>
+where
+    F: FnMut(&VariantPosition) -> Result<Vec<Uci>, E>,
+{
+    let mut seen: HashSet<Zobrist128> = HashSet::new();
+    let mut queue = VecDeque::new();
+    queue.push_back((VariantPosition::new(variant), 0));
+
+    while let Some((pos, plies)) = queue.pop_front() {
+        if !seen.insert(pos.zobrist_hash(EnPassantMode::Legal)) {
+            continue;
+        }
+
+        let moves = visit(&pos)?;
+        if plies >= max_plies {
+            continue;
+        }
+        for uci in moves {
+            if let Ok(m) = uci.to_move(&pos) {
+                let mut child = pos.clone();
+                child.play_unchecked(&m);
+                queue.push_back((child, plies + 1));
+            }
+        }
+    }
+
+    Ok(())
+}